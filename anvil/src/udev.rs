@@ -64,7 +64,7 @@ use smithay::{
         renderer::{ImportDma, ImportEgl},
         udev::primary_gpu,
     },
-    wayland::dmabuf::init_dmabuf_global,
+    wayland::dmabuf::{init_dmabuf_global, ImportError},
 };
 
 use crate::state::{AnvilState, Backend};
@@ -211,10 +211,10 @@ pub fn run_udev(log: Logger) {
                 let anvil_state = ddata.get::<AnvilState<UdevData>>().unwrap();
                 for backend_data in anvil_state.backend_data.backends.values() {
                     if backend_data.renderer.borrow_mut().import_dmabuf(buffer).is_ok() {
-                        return true;
+                        return Ok(());
                     }
                 }
-                false
+                Err(ImportError::Failed)
             },
             log.clone(),
         );
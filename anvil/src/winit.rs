@@ -5,7 +5,7 @@ use smithay::backend::renderer::gles2::Gles2Texture;
 #[cfg(feature = "egl")]
 use smithay::{
     backend::renderer::{ImportDma, ImportEgl},
-    wayland::dmabuf::init_dmabuf_global,
+    wayland::dmabuf::{init_dmabuf_global, ImportError},
 };
 use smithay::{
     backend::{input::InputBackend, renderer::Frame, winit, SwapBuffersError},
@@ -70,7 +70,14 @@ pub fn run_winit(log: Logger) {
         init_dmabuf_global(
             &mut *display.borrow_mut(),
             dmabuf_formats,
-            move |buffer, _| renderer.borrow_mut().renderer().import_dmabuf(buffer).is_ok(),
+            move |buffer, _| {
+                renderer
+                    .borrow_mut()
+                    .renderer()
+                    .import_dmabuf(buffer)
+                    .map(|_| ())
+                    .map_err(|_| ImportError::Failed)
+            },
             log.clone(),
         );
     };
@@ -53,6 +53,21 @@ fn gl_generate() {
     }
 }
 
+#[cfg(feature = "wayland_frontend")]
+fn protocol_generate() {
+    use std::{env, path::PathBuf};
+    use wayland_scanner::{generate_code, Side};
+
+    let dest = PathBuf::from(&env::var("OUT_DIR").unwrap());
+
+    // wayland-protocols only ships version 3 of linux-dmabuf, which lacks dmabuf feedback
+    let protocol = "./protocols/linux-dmabuf-unstable-v1.xml";
+    println!("cargo:rerun-if-changed={}", protocol);
+    generate_code(protocol, dest.join("linux-dmabuf-v1_server_api.rs"), Side::Server);
+    // used by the tests of the dmabuf global
+    generate_code(protocol, dest.join("linux-dmabuf-v1_client_api.rs"), Side::Client);
+}
+
 #[cfg(feature = "backend_session_logind")]
 fn find_logind() {
     // We should allow only dynamic linkage due to libsystemd and libelogind LICENSE.
@@ -89,6 +104,9 @@ fn main() {
     #[cfg(any(feature = "backend_egl", feature = "renderer_gl"))]
     gl_generate();
 
+    #[cfg(feature = "wayland_frontend")]
+    protocol_generate();
+
     #[cfg(feature = "backend_session_logind")]
     find_logind();
 }
@@ -10,11 +10,15 @@
 //! This can be especially useful in resources where other parts of the stack should decide upon
 //! the lifetime of the buffer. E.g. when you are only caching associated resources for a dmabuf.
 
-use super::{Buffer, Format, Fourcc, Modifier};
+use super::{format::FormatInfo, Buffer, Format, Fourcc, Modifier};
 use crate::utils::{Buffer as BufferCoords, Size};
+use nix::fcntl::{fcntl, FcntlArg};
 use std::hash::{Hash, Hasher};
-use std::os::unix::io::{IntoRawFd, RawFd};
-use std::sync::{Arc, Weak};
+use std::os::unix::io::{AsFd, BorrowedFd, IntoRawFd, OwnedFd, RawFd};
+use std::sync::{
+    atomic::{AtomicU64, Ordering},
+    Arc, Weak,
+};
 
 /// Maximum amount of planes this implementation supports
 pub const MAX_PLANES: usize = 4;
@@ -31,19 +35,95 @@ pub(crate) struct DmabufInternal {
     ///
     /// This is a bitflag, to be compared with the `Flags` enum re-exported by this module.
     pub flags: DmabufFlags,
+    /// Identity of the memory of the first plane
+    pub id: DmabufId,
 }
 
+/// Identity of the memory backing a [`Dmabuf`]
+///
+/// Unlike the [`Dmabuf`] handle itself, which only compares equal to its clones, the id is derived
+/// from the inode and offset of the first plane. Dmabufs created out of different file descriptors
+/// referring to the same memory therefore share the same id, which makes it suitable as a key for
+/// caching imported buffers. The id is determined once when the dmabuf is built.
+///
+/// If the inode cannot be queried, the dmabuf gets a unique id, that is only shared by its clones.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct DmabufId {
+    dev: u64,
+    ino: u64,
+    offset: u32,
+}
+
+impl DmabufId {
+    fn unique() -> DmabufId {
+        static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+        DmabufId {
+            // no real device uses this number
+            dev: u64::MAX,
+            ino: NEXT_ID.fetch_add(1, Ordering::Relaxed),
+            offset: 0,
+        }
+    }
+
+    fn for_plane(plane: &Plane) -> Option<DmabufId> {
+        let stat = nix::sys::stat::fstat(plane.fd?).ok()?;
+        Some(DmabufId {
+            dev: stat.st_dev as u64,
+            ino: stat.st_ino as u64,
+            offset: plane.offset,
+        })
+    }
+}
+
+/// A single plane of a dmabuf
+///
+/// Owns the file descriptor of the plane, which is closed once the plane is dropped.
 #[derive(Debug)]
-pub(crate) struct Plane {
-    pub fd: Option<RawFd>,
+pub struct Plane {
+    pub(crate) fd: Option<RawFd>,
     /// The plane index
-    pub plane_idx: u32,
+    pub(crate) plane_idx: u32,
     /// Offset from the start of the Fd
-    pub offset: u32,
+    pub(crate) offset: u32,
     /// Stride for this plane
-    pub stride: u32,
+    pub(crate) stride: u32,
     /// Modifier for this plane
-    pub modifier: Modifier,
+    pub(crate) modifier: Modifier,
+}
+
+impl Plane {
+    /// Raw file descriptor of this plane
+    pub fn fd(&self) -> RawFd {
+        *self.fd.as_ref().unwrap()
+    }
+
+    /// Index of this plane
+    pub fn plane_idx(&self) -> u32 {
+        self.plane_idx
+    }
+
+    /// Offset of this plane from the start of its file descriptor
+    pub fn offset(&self) -> u32 {
+        self.offset
+    }
+
+    /// Stride of this plane
+    pub fn stride(&self) -> u32 {
+        self.stride
+    }
+
+    /// Modifier of this plane
+    pub fn modifier(&self) -> Modifier {
+        self.modifier
+    }
+}
+
+impl AsFd for Plane {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        // Safety: the fd is owned by the plane and only closed once it is dropped,
+        // which cannot happen during the lifetime of the borrow
+        unsafe { BorrowedFd::borrow_raw(self.fd()) }
+    }
 }
 
 impl IntoRawFd for Plane {
@@ -72,6 +152,49 @@ bitflags::bitflags! {
     }
 }
 
+impl DmabufFlags {
+    /// Set or clear [`DmabufFlags::Y_INVERT`]
+    ///
+    /// Allows constructing flags from individual booleans, e.g.
+    /// `DmabufFlags::empty().y_invert(true).interlaced(false)`.
+    pub const fn y_invert(self, y_invert: bool) -> DmabufFlags {
+        self.set_const(DmabufFlags::Y_INVERT, y_invert)
+    }
+
+    /// Set or clear [`DmabufFlags::INTERLACED`]
+    pub const fn interlaced(self, interlaced: bool) -> DmabufFlags {
+        self.set_const(DmabufFlags::INTERLACED, interlaced)
+    }
+
+    /// Set or clear [`DmabufFlags::BOTTOM_FIRST`]
+    pub const fn bottom_first(self, bottom_first: bool) -> DmabufFlags {
+        self.set_const(DmabufFlags::BOTTOM_FIRST, bottom_first)
+    }
+
+    /// Returns if the buffer content is y-inverted
+    pub const fn is_y_inverted(&self) -> bool {
+        self.contains(DmabufFlags::Y_INVERT)
+    }
+
+    /// Returns if the buffer content is interlaced
+    pub const fn is_interlaced(&self) -> bool {
+        self.contains(DmabufFlags::INTERLACED)
+    }
+
+    /// Returns if the buffer content, if interlaced, is bottom-field first
+    pub const fn is_bottom_first(&self) -> bool {
+        self.contains(DmabufFlags::BOTTOM_FIRST)
+    }
+
+    const fn set_const(self, flag: DmabufFlags, value: bool) -> DmabufFlags {
+        if value {
+            self.union(flag)
+        } else {
+            self.difference(flag)
+        }
+    }
+}
+
 #[derive(Debug, Clone)]
 /// Strong reference to a dmabuf handle
 pub struct Dmabuf(pub(crate) Arc<DmabufInternal>);
@@ -113,11 +236,33 @@ impl Buffer for Dmabuf {
     fn format(&self) -> Format {
         Format {
             code: self.0.format,
-            modifier: self.0.planes[0].modifier,
+            modifier: self.modifier(),
         }
     }
 }
 
+/// Description of a plane passed to [`Dmabuf::from_planes`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PlaneInfo {
+    /// File descriptor of the plane
+    pub fd: RawFd,
+    /// Offset of the plane from the start of the file descriptor
+    pub offset: u32,
+    /// Stride of the plane
+    pub stride: u32,
+    /// Modifier of the plane
+    pub modifier: Modifier,
+}
+
+/// Offset and stride of a plane, see [`Dmabuf::plane_layout`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PlaneLayout {
+    /// Offset of the plane from the start of the file descriptor
+    pub offset: u32,
+    /// Stride of the plane
+    pub stride: u32,
+}
+
 /// Builder for Dmabufs
 #[derive(Debug)]
 pub struct DmabufBuilder {
@@ -153,6 +298,9 @@ impl DmabufBuilder {
         }
 
         self.internal.planes.sort_by_key(|plane| plane.plane_idx);
+        if let Some(id) = DmabufId::for_plane(&self.internal.planes[0]) {
+            self.internal.id = id;
+        }
         Some(Dmabuf(Arc::new(self.internal)))
     }
 }
@@ -170,6 +318,7 @@ impl Dmabuf {
                 size: src.size(),
                 format: src.format().code,
                 flags,
+                id: DmabufId::unique(),
             },
         }
     }
@@ -186,20 +335,100 @@ impl Dmabuf {
                 size: size.into(),
                 format,
                 flags,
+                id: DmabufId::unique(),
             },
         }
     }
 
+    /// Create a new Dmabuf out of the given planes
+    ///
+    /// The planes are indexed in the order they are given. The returned Dmabuf takes ownership
+    /// of their file descriptors.
+    ///
+    /// Returns `None` and leaves the file descriptors untouched, if no or more than
+    /// [`MAX_PLANES`] planes are given.
+    pub fn from_planes(
+        size: impl Into<Size<i32, BufferCoords>>,
+        format: Fourcc,
+        flags: DmabufFlags,
+        planes: &[PlaneInfo],
+    ) -> Option<Dmabuf> {
+        if planes.is_empty() || planes.len() > MAX_PLANES {
+            return None;
+        }
+
+        let mut builder = Dmabuf::builder(size, format, flags);
+        for (idx, plane) in planes.iter().enumerate() {
+            builder.add_plane(plane.fd, idx as u32, plane.offset, plane.stride, plane.modifier);
+        }
+        builder.build()
+    }
+
+    /// Create a new Dmabuf out of a single drm prime fd shared by all planes
+    ///
+    /// Useful for buffers of other apis handing out a prime fd together with the layout of the
+    /// buffer, e.g. v4l2 video decoders. The buffer consists of one plane per entry of `strides`
+    /// and `offsets`, all of them using the modifier of `format`.
+    ///
+    /// The returned Dmabuf takes ownership of `fd` for its first plane, all further planes
+    /// hold duplicates of it. Fails with [`std::io::ErrorKind::InvalidInput`], if `strides` and
+    /// `offsets` differ in length, or describe no or more than [`MAX_PLANES`] planes.
+    pub fn from_prime_fd(
+        fd: OwnedFd,
+        size: impl Into<Size<i32, BufferCoords>>,
+        format: Format,
+        flags: DmabufFlags,
+        strides: &[u32],
+        offsets: &[u32],
+    ) -> std::io::Result<Dmabuf> {
+        if strides.len() != offsets.len() || strides.is_empty() || strides.len() > MAX_PLANES {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!(
+                    "Invalid layout of {} strides and {} offsets",
+                    strides.len(),
+                    offsets.len()
+                ),
+            ));
+        }
+
+        let mut fds = Vec::with_capacity(strides.len());
+        for _ in 1..strides.len() {
+            fds.push(fd.try_clone()?);
+        }
+        fds.insert(0, fd);
+
+        let mut builder = Dmabuf::builder(size, format.code, flags);
+        for (idx, ((fd, stride), offset)) in fds.into_iter().zip(strides).zip(offsets).enumerate() {
+            builder.add_plane(fd.into_raw_fd(), idx as u32, *offset, *stride, format.modifier);
+        }
+        Ok(builder.build().unwrap())
+    }
+
     /// The amount of planes this Dmabuf has
     pub fn num_planes(&self) -> usize {
         self.0.planes.len()
     }
 
+    /// Returns the planes of this buffer, ordered by their index
+    pub fn planes(&self) -> impl Iterator<Item = &Plane> + '_ {
+        self.0.planes.iter()
+    }
+
     /// Returns raw handles of the planes of this buffer
     pub fn handles(&self) -> impl Iterator<Item = RawFd> + '_ {
         self.0.planes.iter().map(|p| *p.fd.as_ref().unwrap())
     }
 
+    /// Returns the file descriptors of the planes of this buffer, borrowed from the buffer
+    ///
+    /// Unlike the raw [`handles`](Dmabuf::handles), the borrowed fds cannot outlive the buffer
+    /// keeping them open. Use these e.g. to poll the implicit fences of the planes before
+    /// importing the buffer.
+    pub fn fds(&self) -> impl Iterator<Item = BorrowedFd<'_>> + '_ {
+        self.0.planes.iter().map(|p| p.as_fd())
+    }
+
     /// Returns offsets for the planes of this buffer
     pub fn offsets(&self) -> impl Iterator<Item = u32> + '_ {
         self.0.planes.iter().map(|p| p.offset)
@@ -210,20 +439,229 @@ impl Dmabuf {
         self.0.planes.iter().map(|p| p.stride)
     }
 
+    /// Returns the offset and stride of every plane, indexed like [`planes`](Dmabuf::planes)
+    ///
+    /// Entries past the last plane are `None`. Matches the fixed size arrays of the
+    /// libgbm and drm apis, without converting the values to their signed types.
+    pub fn plane_layout(&self) -> [Option<PlaneLayout>; MAX_PLANES] {
+        let mut layout = [None; MAX_PLANES];
+        for (slot, plane) in layout.iter_mut().zip(self.planes()) {
+            *slot = Some(PlaneLayout {
+                offset: plane.offset,
+                stride: plane.stride,
+            });
+        }
+        layout
+    }
+
     /// Returns if this buffer format has any vendor-specific modifiers set or is implicit/linear
     pub fn has_modifier(&self) -> bool {
-        self.0.planes[0].modifier != Modifier::Invalid && self.0.planes[0].modifier != Modifier::Linear
+        let modifier = self.modifier();
+        modifier != Modifier::Invalid && modifier != Modifier::Linear
+    }
+
+    /// Modifier of the first plane, [`Modifier::Invalid`] for a malformed buffer without planes
+    fn modifier(&self) -> Modifier {
+        self.0
+            .planes
+            .first()
+            .map(|plane| plane.modifier)
+            .unwrap_or(Modifier::Invalid)
+    }
+
+    /// Returns if the planes of this buffer are stored in different memory objects
+    ///
+    /// File descriptors referring to the same dma-buf, e.g. because they were duplicated,
+    /// are not considered disjoint.
+    pub fn is_disjoint(&self) -> bool {
+        let mut handles = self.handles();
+        let first = match handles.next() {
+            Some(fd) => fd,
+            None => return false,
+        };
+        let first_inode = nix::sys::stat::fstat(first)
+            .ok()
+            .map(|stat| (stat.st_dev, stat.st_ino));
+        handles.any(|fd| {
+            fd != first
+                && (first_inode.is_none()
+                    || nix::sys::stat::fstat(fd)
+                        .ok()
+                        .map(|stat| (stat.st_dev, stat.st_ino))
+                        != first_inode)
+        })
+    }
+
+    /// Identity of the memory backing this buffer, see [`DmabufId`]
+    pub fn id(&self) -> DmabufId {
+        self.0.id
     }
 
     /// Returns if the buffer is stored inverted on the y-axis
     pub fn y_inverted(&self) -> bool {
-        self.0.flags.contains(DmabufFlags::Y_INVERT)
+        self.0.flags.is_y_inverted()
+    }
+
+    /// Flags of the buffer, describing how it has to be sampled
+    ///
+    /// Renderers have to flip buffers with [`DmabufFlags::Y_INVERT`] set on the y-axis.
+    pub fn flags(&self) -> DmabufFlags {
+        self.0.flags
+    }
+
+    /// Create an independent handle to the same buffer, owning duplicates of the file descriptors
+    ///
+    /// Unlike [`Clone::clone`], which shares the file descriptors between all clones and closes them
+    /// once the last clone is dropped, the returned dmabuf holds file descriptors of its own, which are
+    /// duplicated with `F_DUPFD_CLOEXEC`. Both dmabufs refer to the same memory and share their [`id`](Dmabuf::id),
+    /// but do not compare equal.
+    pub fn try_clone(&self) -> std::io::Result<Dmabuf> {
+        self.try_clone_with_flags(self.0.flags)
+    }
+
+    /// Create an independent handle to the same buffer carrying `flags`, see [`Dmabuf::try_clone`]
+    pub fn try_clone_with_flags(&self, flags: DmabufFlags) -> std::io::Result<Dmabuf> {
+        let mut planes = Vec::with_capacity(self.0.planes.len());
+        for plane in self.planes() {
+            let fd = fcntl(plane.fd(), FcntlArg::F_DUPFD_CLOEXEC(0)).map_err(std::io::Error::from)?;
+            // pushed right away, so already duplicated fds are closed if a later one fails
+            planes.push(Plane {
+                fd: Some(fd),
+                plane_idx: plane.plane_idx,
+                offset: plane.offset,
+                stride: plane.stride,
+                modifier: plane.modifier,
+            });
+        }
+        Ok(Dmabuf(Arc::new(DmabufInternal {
+            planes,
+            size: self.0.size,
+            format: self.0.format,
+            flags,
+            id: self.0.id,
+        })))
+    }
+
+    /// Replace the flags of this dmabuf, unless it is shared with other handles
+    fn with_unique_flags(mut self, flags: DmabufFlags) -> Dmabuf {
+        if let Some(internal) = Arc::get_mut(&mut self.0) {
+            internal.flags = flags;
+        }
+        self
     }
 
     /// Create a weak reference to this dmabuf
     pub fn weak(&self) -> WeakDmabuf {
         WeakDmabuf(Arc::downgrade(&self.0))
     }
+
+    /// Check the invariants of the layout of this buffer
+    ///
+    /// Verifies that the number of planes matches the format, allowing additional auxiliary planes
+    /// for explicit modifiers, that all planes use the same modifier and a non-zero stride, and that
+    /// every plane fits into its file descriptor, as far as its size is known. Formats with an unknown
+    /// memory layout only have their modifiers and strides checked.
+    ///
+    /// These invariants are checked by the dmabuf protocol implementation for buffers submitted by
+    /// clients, but not enforced when building a dmabuf, so this is mostly useful in tests.
+    pub fn validate(&self) -> Result<(), DmabufValidationError> {
+        let planes = &self.0.planes;
+        let modifier = self.format().modifier;
+        if planes.iter().any(|plane| plane.modifier != modifier) {
+            return Err(DmabufValidationError::MixedModifiers);
+        }
+
+        let info = FormatInfo::for_fourcc(self.0.format);
+        if let Some(expected) = info.as_ref().map(FormatInfo::plane_count) {
+            let explicit_modifier = modifier != Modifier::Invalid && modifier != Modifier::Linear;
+            if planes.len() < expected || (planes.len() > expected && !explicit_modifier) {
+                return Err(DmabufValidationError::PlaneCount {
+                    expected,
+                    got: planes.len(),
+                });
+            }
+        }
+
+        for plane in planes {
+            if plane.stride == 0 {
+                return Err(DmabufValidationError::ZeroStride(plane.plane_idx));
+            }
+            let rows = match info
+                .as_ref()
+                .and_then(|info| info.plane_height(plane.plane_idx as usize, self.height()))
+            {
+                Some(rows) => rows,
+                // auxiliary planes of unknown size
+                None => continue,
+            };
+            let end = (plane.stride as u64)
+                .checked_mul(rows as u64)
+                .and_then(|size| size.checked_add(plane.offset as u64))
+                .filter(|end| *end <= u32::MAX as u64)
+                .ok_or(DmabufValidationError::OutOfBounds(plane.plane_idx))?;
+            // the size of file descriptors, which are not backed by memory, e.g. in tests, is zero
+            let fd_size = nix::sys::stat::fstat(plane.fd())
+                .map(|stat| stat.st_size as u64)
+                .unwrap_or(0);
+            if fd_size != 0 && end > fd_size {
+                return Err(DmabufValidationError::OutOfBounds(plane.plane_idx));
+            }
+        }
+        Ok(())
+    }
+
+    /// Describe the layout of this buffer, without its file descriptors
+    pub fn describe(&self) -> DmabufDescriptor {
+        DmabufDescriptor {
+            width: self.0.size.w,
+            height: self.0.size.h,
+            format: self.0.format,
+            modifier: self.modifier(),
+            y_inverted: self.y_inverted(),
+            planes: self
+                .planes()
+                .map(|plane| PlaneDescriptor {
+                    plane_idx: plane.plane_idx,
+                    offset: plane.offset,
+                    stride: plane.stride,
+                })
+                .collect(),
+        }
+    }
+}
+
+/// Plain data description of a [`Dmabuf`], see [`Dmabuf::describe`]
+///
+/// Contains everything describing the layout of the buffer except for the file descriptors
+/// of its planes, so it can be freely stored, compared or logged. With the `serialize`
+/// feature enabled it implements `serde::Serialize`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serialize", derive(serde::Serialize))]
+pub struct DmabufDescriptor {
+    /// Width of the buffer
+    pub width: i32,
+    /// Height of the buffer
+    pub height: i32,
+    /// Format of the buffer
+    pub format: Fourcc,
+    /// Modifier shared by all planes
+    pub modifier: Modifier,
+    /// Whether the buffer is stored inverted on the y-axis
+    pub y_inverted: bool,
+    /// Layout of the planes
+    pub planes: Vec<PlaneDescriptor>,
+}
+
+/// Layout of a single plane of a [`DmabufDescriptor`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serialize", derive(serde::Serialize))]
+pub struct PlaneDescriptor {
+    /// The plane index
+    pub plane_idx: u32,
+    /// Offset from the start of the file descriptor
+    pub offset: u32,
+    /// Stride of the plane
+    pub stride: u32,
 }
 
 impl WeakDmabuf {
@@ -235,6 +673,28 @@ impl WeakDmabuf {
     }
 }
 
+/// Violated invariant of a [`Dmabuf`], see [`Dmabuf::validate`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, thiserror::Error)]
+pub enum DmabufValidationError {
+    /// The number of planes does not match the format
+    #[error("The format requires {expected} planes, the buffer has {got}")]
+    PlaneCount {
+        /// Number of planes of the format
+        expected: usize,
+        /// Number of planes of the buffer
+        got: usize,
+    },
+    /// The planes use different modifiers
+    #[error("The planes of the buffer use different modifiers")]
+    MixedModifiers,
+    /// The stride of the plane is zero
+    #[error("The stride of plane {0} is zero")]
+    ZeroStride(u32),
+    /// The plane exceeds its file descriptor or the range of its offset
+    #[error("Plane {0} is out of bounds")]
+    OutOfBounds(u32),
+}
+
 /// Buffer that can be exported as Dmabufs
 pub trait AsDmabuf {
     /// Error type returned, if exporting fails
@@ -242,12 +702,367 @@ pub trait AsDmabuf {
 
     /// Export this buffer as a new Dmabuf
     fn export(&self) -> Result<Dmabuf, Self::Error>;
+
+    /// Export this buffer as a new Dmabuf carrying the given `flags`
+    ///
+    /// Most buffers do not track any of these flags themselves. Use this e.g. to mark buffers
+    /// rendered with a bottom-left origin as [`DmabufFlags::Y_INVERT`], instead of flipping their contents.
+    ///
+    /// The default implementation sets `flags` on the dmabuf returned by [`export`](AsDmabuf::export).
+    /// If that dmabuf is shared with other handles, it keeps its flags instead, implementations returning
+    /// shared dmabufs should override this method.
+    fn export_with(&self, flags: DmabufFlags) -> Result<Dmabuf, Self::Error> {
+        self.export().map(|dmabuf| dmabuf.with_unique_flags(flags))
+    }
 }
 
+/// Exports share the dmabuf, so unlike other implementations they keep its flags.
+/// Use [`Dmabuf::try_clone_with_flags`] to get a handle carrying different flags.
 impl AsDmabuf for Dmabuf {
     type Error = std::convert::Infallible;
 
     fn export(&self) -> Result<Dmabuf, std::convert::Infallible> {
         Ok(self.clone())
     }
+
+    fn export_with(&self, _flags: DmabufFlags) -> Result<Dmabuf, std::convert::Infallible> {
+        Ok(self.clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::{fs::MetadataExt, io::AsRawFd};
+
+    fn plane_info(fd: RawFd, offset: u32) -> PlaneInfo {
+        PlaneInfo {
+            fd,
+            offset,
+            stride: 256,
+            modifier: Modifier::Linear,
+        }
+    }
+
+    #[test]
+    fn from_planes() {
+        let plane = |offset| plane_info(std::fs::File::open("/dev/null").unwrap().into_raw_fd(), offset);
+
+        assert!(Dmabuf::from_planes((64, 64), Fourcc::Nv12, DmabufFlags::empty(), &[]).is_none());
+        let dmabuf = Dmabuf::from_planes(
+            (64, 64),
+            Fourcc::Nv12,
+            DmabufFlags::empty(),
+            &[plane(0), plane(64 * 256)],
+        )
+        .unwrap();
+        assert_eq!(dmabuf.num_planes(), 2);
+        assert_eq!(dmabuf.offsets().collect::<Vec<_>>(), vec![0, 64 * 256]);
+        assert_eq!(
+            dmabuf
+                .planes()
+                .map(|plane| (plane.plane_idx(), plane.offset(), plane.stride()))
+                .collect::<Vec<_>>(),
+            vec![(0, 0, 256), (1, 64 * 256, 256)]
+        );
+        assert_eq!(dmabuf.format().modifier, Modifier::Linear);
+    }
+
+    #[test]
+    fn borrowed_fds() {
+        let plane = |offset| plane_info(std::fs::File::open("/dev/null").unwrap().into_raw_fd(), offset);
+        let dmabuf = Dmabuf::from_planes(
+            (64, 64),
+            Fourcc::Nv12,
+            DmabufFlags::empty(),
+            &[plane(0), plane(64 * 256)],
+        )
+        .unwrap();
+
+        let fds = dmabuf.fds().map(|fd| fd.as_raw_fd()).collect::<Vec<_>>();
+        assert_eq!(fds, dmabuf.handles().collect::<Vec<_>>());
+        // /dev/null is always ready
+        let mut poll_fds = dmabuf
+            .fds()
+            .map(|fd| nix::poll::PollFd::new(fd.as_raw_fd(), nix::poll::PollFlags::POLLIN))
+            .collect::<Vec<_>>();
+        assert_eq!(nix::poll::poll(&mut poll_fds, 0).unwrap(), 2);
+    }
+
+    #[test]
+    fn try_clone_owns_its_fds() {
+        let file = std::fs::File::open("/dev/null").unwrap();
+        let rdev = file.metadata().unwrap().rdev();
+        let dmabuf = Dmabuf::from_planes(
+            (64, 64),
+            Fourcc::Argb8888,
+            DmabufFlags::empty(),
+            &[plane_info(file.into_raw_fd(), 0)],
+        )
+        .unwrap();
+
+        let clone = dmabuf.try_clone().unwrap();
+        assert_ne!(clone, dmabuf);
+        assert_eq!(clone.id(), dmabuf.id());
+        assert_eq!(clone.describe(), dmabuf.describe());
+        assert_ne!(clone.handles().next(), dmabuf.handles().next());
+
+        // the clone stays usable after the original closed its fds
+        drop(dmabuf);
+        let stat = nix::sys::stat::fstat(clone.handles().next().unwrap()).unwrap();
+        assert_eq!(stat.st_rdev, rdev);
+    }
+
+    #[test]
+    fn export_with_flags() {
+        let file = std::fs::File::open("/dev/null").unwrap();
+        let dmabuf = Dmabuf::from_planes(
+            (64, 64),
+            Fourcc::Argb8888,
+            DmabufFlags::empty(),
+            &[plane_info(file.into_raw_fd(), 0)],
+        )
+        .unwrap();
+
+        // exporting a dmabuf shares it, including its flags
+        assert_eq!(dmabuf.export().unwrap(), dmabuf);
+        assert_eq!(dmabuf.export_with(DmabufFlags::Y_INVERT).unwrap(), dmabuf);
+        assert!(!dmabuf.y_inverted());
+
+        let inverted = dmabuf.try_clone_with_flags(DmabufFlags::Y_INVERT).unwrap();
+        assert!(inverted.y_inverted());
+        assert!(!dmabuf.y_inverted());
+        assert_eq!(inverted.id(), dmabuf.id());
+
+        // the default implementation stamps the flags onto fresh exports
+        struct Export(DmabufFlags);
+        impl AsDmabuf for Export {
+            type Error = std::io::Error;
+
+            fn export(&self) -> std::io::Result<Dmabuf> {
+                let file = std::fs::File::open("/dev/null")?;
+                Ok(Dmabuf::from_planes(
+                    (64, 64),
+                    Fourcc::Argb8888,
+                    self.0,
+                    &[plane_info(file.into_raw_fd(), 0)],
+                )
+                .unwrap())
+            }
+        }
+        assert!(!Export(DmabufFlags::empty()).export().unwrap().y_inverted());
+        assert!(Export(DmabufFlags::empty())
+            .export_with(DmabufFlags::Y_INVERT)
+            .unwrap()
+            .y_inverted());
+        assert_eq!(
+            Export(DmabufFlags::Y_INVERT)
+                .export_with(DmabufFlags::empty())
+                .unwrap()
+                .flags(),
+            DmabufFlags::empty()
+        );
+    }
+
+    #[test]
+    fn validate() {
+        use nix::sys::memfd::{memfd_create, MemFdCreateFlag};
+        let memfd = |len| {
+            let name = std::ffi::CStr::from_bytes_with_nul(b"validate\0").unwrap();
+            let fd = memfd_create(name, MemFdCreateFlag::MFD_CLOEXEC).unwrap();
+            nix::unistd::ftruncate(fd, len).unwrap();
+            fd
+        };
+        let dmabuf = |format, planes: Vec<PlaneInfo>| {
+            Dmabuf::from_planes((64, 64), format, DmabufFlags::empty(), &planes).unwrap()
+        };
+
+        let argb = dmabuf(Fourcc::Argb8888, vec![plane_info(memfd(64 * 256), 0)]);
+        assert_eq!(argb.validate(), Ok(()));
+
+        let nv12 = dmabuf(Fourcc::Nv12, vec![plane_info(memfd(64 * 256), 0)]);
+        assert_eq!(
+            nv12.validate(),
+            Err(DmabufValidationError::PlaneCount { expected: 2, got: 1 })
+        );
+
+        let mixed = dmabuf(
+            Fourcc::Nv12,
+            vec![
+                plane_info(memfd(64 * 256), 0),
+                PlaneInfo {
+                    modifier: Modifier::I915_x_tiled,
+                    ..plane_info(memfd(32 * 256), 0)
+                },
+            ],
+        );
+        assert_eq!(mixed.validate(), Err(DmabufValidationError::MixedModifiers));
+
+        let zero_stride = dmabuf(
+            Fourcc::Argb8888,
+            vec![PlaneInfo {
+                stride: 0,
+                ..plane_info(memfd(64 * 256), 0)
+            }],
+        );
+        assert_eq!(zero_stride.validate(), Err(DmabufValidationError::ZeroStride(0)));
+
+        // the second plane starts within the file, but does not fit into it
+        let out_of_bounds = dmabuf(
+            Fourcc::Nv12,
+            vec![
+                plane_info(memfd(64 * 256), 0),
+                plane_info(memfd(64 * 256), 40 * 256),
+            ],
+        );
+        assert_eq!(
+            out_of_bounds.validate(),
+            Err(DmabufValidationError::OutOfBounds(1))
+        );
+
+        let overflow = dmabuf(
+            Fourcc::Argb8888,
+            vec![PlaneInfo {
+                stride: u32::MAX / 32,
+                ..plane_info(memfd(64 * 256), 0)
+            }],
+        );
+        assert_eq!(overflow.validate(), Err(DmabufValidationError::OutOfBounds(0)));
+    }
+
+    #[test]
+    fn from_prime_fd() {
+        use std::os::unix::io::FromRawFd;
+
+        // a memfd stands in for the prime fd of another api
+        let name = std::ffi::CStr::from_bytes_with_nul(b"prime\0").unwrap();
+        let fd = nix::sys::memfd::memfd_create(name, nix::sys::memfd::MemFdCreateFlag::MFD_CLOEXEC).unwrap();
+        nix::unistd::ftruncate(fd, 64 * 96).unwrap();
+        let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+        let format = Format {
+            code: Fourcc::Nv12,
+            modifier: Modifier::Linear,
+        };
+        let dmabuf = Dmabuf::from_prime_fd(
+            fd,
+            (64, 64),
+            format,
+            DmabufFlags::empty(),
+            &[64, 64],
+            &[0, 64 * 64],
+        )
+        .unwrap();
+        assert_eq!(dmabuf.format(), format);
+        assert_eq!(dmabuf.strides().collect::<Vec<_>>(), vec![64, 64]);
+        assert_eq!(dmabuf.offsets().collect::<Vec<_>>(), vec![0, 64 * 64]);
+        // every plane owns its fd
+        let handles = dmabuf.handles().collect::<Vec<_>>();
+        assert_ne!(handles[0], handles[1]);
+        assert!(!dmabuf.is_disjoint());
+        assert_eq!(dmabuf.validate(), Ok(()));
+
+        let fd = OwnedFd::from(std::fs::File::open("/dev/null").unwrap());
+        let mismatch = Dmabuf::from_prime_fd(fd, (64, 64), format, DmabufFlags::empty(), &[64, 64], &[0]);
+        assert_eq!(mismatch.unwrap_err().kind(), std::io::ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn describe() {
+        let plane = |offset| plane_info(std::fs::File::open("/dev/null").unwrap().into_raw_fd(), offset);
+        let dmabuf = Dmabuf::from_planes(
+            (64, 32),
+            Fourcc::Nv12,
+            DmabufFlags::Y_INVERT,
+            &[plane(0), plane(32 * 256)],
+        )
+        .unwrap();
+        let descriptor = dmabuf.describe();
+        assert_eq!(
+            descriptor,
+            DmabufDescriptor {
+                width: 64,
+                height: 32,
+                format: Fourcc::Nv12,
+                modifier: Modifier::Linear,
+                y_inverted: true,
+                planes: vec![
+                    PlaneDescriptor {
+                        plane_idx: 0,
+                        offset: 0,
+                        stride: 256,
+                    },
+                    PlaneDescriptor {
+                        plane_idx: 1,
+                        offset: 32 * 256,
+                        stride: 256,
+                    },
+                ],
+            }
+        );
+        // the descriptor outlives the fds of the buffer
+        drop(dmabuf);
+        assert_eq!(descriptor.clone(), descriptor);
+    }
+
+    #[test]
+    fn disjoint() {
+        let file = std::fs::File::open("/dev/null").unwrap();
+        let shared = Dmabuf::from_planes(
+            (64, 64),
+            Fourcc::Nv12,
+            DmabufFlags::empty(),
+            &[
+                plane_info(file.try_clone().unwrap().into_raw_fd(), 0),
+                plane_info(file.into_raw_fd(), 64 * 256),
+            ],
+        )
+        .unwrap();
+        assert!(!shared.is_disjoint());
+
+        let disjoint = Dmabuf::from_planes(
+            (64, 64),
+            Fourcc::Nv12,
+            DmabufFlags::empty(),
+            &[
+                plane_info(std::fs::File::open("/dev/null").unwrap().into_raw_fd(), 0),
+                plane_info(std::fs::File::open("/dev/zero").unwrap().into_raw_fd(), 0),
+            ],
+        )
+        .unwrap();
+        assert!(disjoint.is_disjoint());
+    }
+
+    #[test]
+    fn id_follows_memory() {
+        let file = std::fs::File::open("/dev/zero").unwrap();
+        let dmabuf = |offset| {
+            let fd = nix::unistd::dup(file.as_raw_fd()).unwrap();
+            Dmabuf::from_planes(
+                (64, 64),
+                Fourcc::Argb8888,
+                DmabufFlags::empty(),
+                &[plane_info(fd, offset)],
+            )
+            .unwrap()
+        };
+        let first = dmabuf(0);
+        assert_ne!(first, dmabuf(0));
+        assert_eq!(first.id(), dmabuf(0).id());
+        assert_ne!(first.id(), dmabuf(256).id());
+    }
+
+    #[test]
+    fn without_planes() {
+        // bypasses the builder, which refuses to build dmabufs without planes
+        let dmabuf = Dmabuf(Arc::new(DmabufInternal {
+            planes: Vec::new(),
+            size: (64, 64).into(),
+            format: Fourcc::Argb8888,
+            flags: DmabufFlags::empty(),
+            id: DmabufId::unique(),
+        }));
+        assert_eq!(dmabuf.format().modifier, Modifier::Invalid);
+        assert!(!dmabuf.has_modifier());
+        assert!(!dmabuf.is_disjoint());
+    }
 }
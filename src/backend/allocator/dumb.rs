@@ -59,6 +59,13 @@ impl<A: AsRawFd + 'static> Allocator<DumbBuffer<A>> for DrmDevice<A> {
             },
         })
     }
+
+    fn preferred_format(&self) -> Option<Format> {
+        Some(Format {
+            code: Fourcc::Xrgb8888,
+            modifier: Modifier::Linear,
+        })
+    }
 }
 
 impl<A: AsRawFd + 'static> Buffer for DumbBuffer<A> {
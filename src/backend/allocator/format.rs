@@ -0,0 +1,627 @@
+//! Memory layout information of pixel formats
+//!
+//! Provides a table describing how buffers of a given [`Fourcc`] are laid out in memory,
+//! like the number of planes, the bytes used per pixel of every plane and the subsampling
+//! applied to chroma planes. The most commonly needed information is also available directly
+//! on the [`Fourcc`] through [`FourccExt`].
+//!
+//! [`FormatExt`] converts a [`Format`] from and to the names of its fourcc code and modifier,
+//! e.g. to load a list of formats from a configuration file. [`ModifierExt`] names common
+//! vendor modifiers.
+
+use super::{Format, Fourcc, Modifier};
+use std::convert::{TryFrom, TryInto};
+
+/// Memory layout of a [`Fourcc`] format
+///
+/// This describes the linear layout of a format, tiled modifiers may require additional memory.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FormatInfo {
+    planes: usize,
+    cpp: [u32; 3],
+    hsub: u32,
+    vsub: u32,
+}
+
+const fn info(planes: usize, cpp: [u32; 3], hsub: u32, vsub: u32) -> Option<FormatInfo> {
+    Some(FormatInfo {
+        planes,
+        cpp,
+        hsub,
+        vsub,
+    })
+}
+
+impl FormatInfo {
+    /// Look up the layout of `format`
+    ///
+    /// Returns `None` for formats, whose layout is not known, e.g. compressed or tiled formats.
+    pub fn for_fourcc(format: Fourcc) -> Option<FormatInfo> {
+        use Fourcc::*;
+
+        match format {
+            C8 | R8 | Rgb332 | Bgr233 => info(1, [1, 0, 0], 1, 1),
+            R16 | Rg88 | Gr88 | Xrgb4444 | Xbgr4444 | Rgbx4444 | Bgrx4444 | Argb4444 | Abgr4444
+            | Rgba4444 | Bgra4444 | Xrgb1555 | Xbgr1555 | Rgbx5551 | Bgrx5551 | Argb1555 | Abgr1555
+            | Rgba5551 | Bgra5551 | Rgb565 | Bgr565 => info(1, [2, 0, 0], 1, 1),
+            Rgb888 | Bgr888 | Vuy888 => info(1, [3, 0, 0], 1, 1),
+            Xrgb8888 | Xbgr8888 | Rgbx8888 | Bgrx8888 | Argb8888 | Abgr8888 | Rgba8888 | Bgra8888
+            | Xrgb2101010 | Xbgr2101010 | Rgbx1010102 | Bgrx1010102 | Argb2101010 | Abgr2101010
+            | Rgba1010102 | Bgra1010102 | Rg1616 | Gr1616 | Ayuv | Xyuv8888 | Y410 => {
+                info(1, [4, 0, 0], 1, 1)
+            }
+            Xrgb16161616f | Xbgr16161616f | Argb16161616f | Abgr16161616f | Axbxgxrx106106106106 | Y412
+            | Y416 | Xvyu12_16161616 | Xvyu16161616 => info(1, [8, 0, 0], 1, 1),
+            // packed 4:2:2 formats, two pixels share their chroma samples
+            Yuyv | Yvyu | Uyvy | Vyuy => info(1, [2, 0, 0], 2, 1),
+            Y210 | Y212 | Y216 => info(1, [4, 0, 0], 2, 1),
+            // color planes with a separate alpha plane
+            Rgb565_a8 | Bgr565_a8 => info(2, [2, 1, 0], 1, 1),
+            Rgb888_a8 | Bgr888_a8 => info(2, [3, 1, 0], 1, 1),
+            Xrgb8888_a8 | Xbgr8888_a8 | Rgbx8888_a8 | Bgrx8888_a8 => info(2, [4, 1, 0], 1, 1),
+            // semi-planar yuv formats
+            Nv12 | Nv21 => info(2, [1, 2, 0], 2, 2),
+            Nv16 | Nv61 => info(2, [1, 2, 0], 2, 1),
+            Nv24 | Nv42 => info(2, [1, 2, 0], 1, 1),
+            P010 | P012 | P016 => info(2, [2, 4, 0], 2, 2),
+            P210 => info(2, [2, 4, 0], 2, 1),
+            // planar yuv formats
+            Yuv410 | Yvu410 => info(3, [1, 1, 1], 4, 4),
+            Yuv411 | Yvu411 => info(3, [1, 1, 1], 4, 1),
+            Yuv420 | Yvu420 => info(3, [1, 1, 1], 2, 2),
+            Yuv422 | Yvu422 => info(3, [1, 1, 1], 2, 1),
+            Yuv444 | Yvu444 => info(3, [1, 1, 1], 1, 1),
+            _ => None,
+        }
+    }
+
+    /// Number of planes used by the format
+    pub fn plane_count(&self) -> usize {
+        self.planes
+    }
+
+    /// Horizontal and vertical subsampling factors applied to `plane`
+    ///
+    /// Returns `None` if the format has no such plane.
+    pub fn subsampling(&self, plane: usize) -> Option<(u32, u32)> {
+        match plane {
+            0 => Some((1, 1)),
+            x if x < self.planes => Some((self.hsub, self.vsub)),
+            _ => None,
+        }
+    }
+
+    /// Bytes used by a single (possibly subsampled) pixel of `plane`
+    ///
+    /// Returns `None` if the format has no such plane.
+    pub fn bytes_per_pixel(&self, plane: usize) -> Option<u32> {
+        if plane < self.planes {
+            Some(self.cpp[plane])
+        } else {
+            None
+        }
+    }
+
+    /// Number of pixels per row of `plane` for a buffer of the given `width`
+    ///
+    /// Returns `None` if the format has no such plane.
+    pub fn plane_width(&self, plane: usize, width: u32) -> Option<u32> {
+        self.subsampling(plane)
+            .map(|(hsub, _)| width.saturating_add(hsub - 1) / hsub)
+    }
+
+    /// Number of rows of `plane` for a buffer of the given `height`
+    ///
+    /// Returns `None` if the format has no such plane.
+    pub fn plane_height(&self, plane: usize, height: u32) -> Option<u32> {
+        self.subsampling(plane)
+            .map(|(_, vsub)| height.saturating_add(vsub - 1) / vsub)
+    }
+}
+
+/// Layout information of a [`Fourcc`] format
+///
+/// Shorthand for the methods of the [`FormatInfo`] of the format.
+pub trait FourccExt {
+    /// Number of planes used by the format
+    ///
+    /// Returns `None` if the layout of the format is unknown.
+    fn plane_count(&self) -> Option<usize>;
+
+    /// Horizontal and vertical subsampling factors applied to `plane`
+    ///
+    /// Returns `None` if the layout of the format is unknown or the format has no such plane.
+    fn subsampling(&self, plane: usize) -> Option<(u32, u32)>;
+
+    /// Parse the four character code of a format, e.g. `"XR24"` for [`Fourcc::Xrgb8888`]
+    ///
+    /// This is the inverse of the `Display` implementation of [`Fourcc`].
+    /// Returns `None` if `code` is not the code of a known format.
+    fn parse(code: &str) -> Option<Fourcc>;
+
+    /// Whether the format contains an alpha channel
+    ///
+    /// Buffers of formats without alpha are always opaque, which allows renderers to skip blending
+    /// them and to cull anything covered by them. Formats with padding bits in place of the alpha
+    /// channel, e.g. [`Fourcc::Xrgb8888`], do not have alpha.
+    fn has_alpha(&self) -> bool;
+}
+
+impl FourccExt for Fourcc {
+    fn plane_count(&self) -> Option<usize> {
+        FormatInfo::for_fourcc(*self).map(|info| info.plane_count())
+    }
+
+    fn subsampling(&self, plane: usize) -> Option<(u32, u32)> {
+        FormatInfo::for_fourcc(*self).and_then(|info| info.subsampling(plane))
+    }
+
+    fn parse(code: &str) -> Option<Fourcc> {
+        let bytes: [u8; 4] = code.as_bytes().try_into().ok()?;
+        Fourcc::try_from(u32::from_le_bytes(bytes)).ok()
+    }
+
+    fn has_alpha(&self) -> bool {
+        use Fourcc::*;
+
+        match self {
+            Argb4444 | Abgr4444 | Rgba4444 | Bgra4444 | Argb1555 | Abgr1555 | Rgba5551 | Bgra5551
+            | Argb8888 | Abgr8888 | Rgba8888 | Bgra8888 | Argb2101010 | Abgr2101010 | Rgba1010102
+            | Bgra1010102 | Argb16161616f | Abgr16161616f | Axbxgxrx106106106106 | Ayuv | Y410 | Y412
+            | Y416 => true,
+            // color planes with a separate alpha plane
+            Rgb565_a8 | Bgr565_a8 | Rgb888_a8 | Bgr888_a8 | Xrgb8888_a8 | Xbgr8888_a8 | Rgbx8888_a8
+            | Bgrx8888_a8 => true,
+            _ => false,
+        }
+    }
+}
+
+macro_rules! named {
+    ($ty:ident { $($name:ident),* $(,)? }) => {
+        [$(($ty::$name, stringify!($name))),*]
+    };
+}
+
+/// Fourcc codes by the name of their variant
+const NAMED_FOURCCS: &[(Fourcc, &str)] = &named!(Fourcc {
+    Abgr1555,
+    Abgr16161616f,
+    Abgr2101010,
+    Abgr4444,
+    Abgr8888,
+    Argb1555,
+    Argb16161616f,
+    Argb2101010,
+    Argb4444,
+    Argb8888,
+    Axbxgxrx106106106106,
+    Ayuv,
+    Bgr233,
+    Bgr565,
+    Bgr565_a8,
+    Bgr888,
+    Bgr888_a8,
+    Bgra1010102,
+    Bgra4444,
+    Bgra5551,
+    Bgra8888,
+    Bgrx1010102,
+    Bgrx4444,
+    Bgrx5551,
+    Bgrx8888,
+    Bgrx8888_a8,
+    C8,
+    Gr1616,
+    Gr88,
+    Nv12,
+    Nv15,
+    Nv16,
+    Nv21,
+    Nv24,
+    Nv42,
+    Nv61,
+    P010,
+    P012,
+    P016,
+    P210,
+    Q401,
+    Q410,
+    R16,
+    R8,
+    Rg1616,
+    Rg88,
+    Rgb332,
+    Rgb565,
+    Rgb565_a8,
+    Rgb888,
+    Rgb888_a8,
+    Rgba1010102,
+    Rgba4444,
+    Rgba5551,
+    Rgba8888,
+    Rgbx1010102,
+    Rgbx4444,
+    Rgbx5551,
+    Rgbx8888,
+    Rgbx8888_a8,
+    Uyvy,
+    Vuy101010,
+    Vuy888,
+    Vyuy,
+    X0l0,
+    X0l2,
+    Xbgr1555,
+    Xbgr16161616f,
+    Xbgr2101010,
+    Xbgr4444,
+    Xbgr8888,
+    Xbgr8888_a8,
+    Xrgb1555,
+    Xrgb16161616f,
+    Xrgb2101010,
+    Xrgb4444,
+    Xrgb8888,
+    Xrgb8888_a8,
+    Xvyu12_16161616,
+    Xvyu16161616,
+    Xvyu2101010,
+    Xyuv8888,
+    Y0l0,
+    Y0l2,
+    Y210,
+    Y212,
+    Y216,
+    Y410,
+    Y412,
+    Y416,
+    Yuv410,
+    Yuv411,
+    Yuv420,
+    Yuv420_10bit,
+    Yuv420_8bit,
+    Yuv422,
+    Yuv444,
+    Yuyv,
+    Yvu410,
+    Yvu411,
+    Yvu420,
+    Yvu422,
+    Yvu444,
+    Yvyu,
+});
+
+/// Modifiers by the name of their variant
+const NAMED_MODIFIERS: &[(Modifier, &str)] = &named!(Modifier {
+    Linear,
+    Invalid,
+    Allwinner_tiled,
+    Broadcom_sand128,
+    Broadcom_sand256,
+    Broadcom_sand32,
+    Broadcom_sand64,
+    Broadcom_uif,
+    Broadcom_vc4_t_tiled,
+    Generic_16_16_tile,
+    Nvidia_16bx2_block_eight_gob,
+    Nvidia_16bx2_block_four_gob,
+    Nvidia_16bx2_block_one_gob,
+    Nvidia_16bx2_block_sixteen_gob,
+    Nvidia_16bx2_block_thirtytwo_gob,
+    Nvidia_16bx2_block_two_gob,
+    Nvidia_tegra_tiled,
+    Qcom_compressed,
+    Samsung_16_16_tile,
+    Samsung_64_32_tile,
+    Vivante_split_super_tiled,
+    Vivante_split_tiled,
+    Vivante_super_tiled,
+    Vivante_tiled,
+    I915_x_tiled,
+    I915_y_tiled,
+    I915_y_tiled_ccs,
+    I915_y_tiled_gen12_mc_ccs,
+    I915_y_tiled_gen12_rc_ccs,
+});
+
+/// Conversion of a [`Format`] from and to the names used by the kernel headers
+///
+/// Fourcc codes are named like the `DRM_FORMAT_*` constants, e.g. `"XRGB8888"`, and
+/// modifiers like the `*_FORMAT_MOD_*` constants, e.g. `"I915_FORMAT_MOD_Y_TILED"`.
+pub trait FormatExt: Sized {
+    /// Parse a format from the name of its fourcc code and modifier
+    ///
+    /// Names are matched case-insensitively and the `DRM_FORMAT_` prefix of the fourcc code is optional.
+    /// The fourcc code may also be given as its four character code (e.g. `"XR24"`)
+    /// and the modifier as a hexadecimal value (e.g. `"0x100000000000001"`).
+    /// Returns `None` if either name is unknown.
+    fn from_strings(fourcc: &str, modifier: &str) -> Option<Self>;
+
+    /// Names of the fourcc code and modifier of the format
+    ///
+    /// This is the inverse of [`FormatExt::from_strings`], modifiers without a name are
+    /// returned as hexadecimal value.
+    fn to_strings(&self) -> (String, String);
+
+    /// Minimum size in bytes of a buffer of this format with the given dimensions
+    ///
+    /// This is the size of a tightly packed linear buffer and as such a lower bound for
+    /// every modifier. Tiled or compressed modifiers may require additional memory for
+    /// padding or auxiliary planes, which is not accounted for.
+    ///
+    /// Returns `None` if the layout of the format is unknown or the size does not fit into an `usize`.
+    fn min_buffer_size(&self, width: u32, height: u32) -> Option<usize>;
+}
+
+impl FormatExt for Format {
+    fn from_strings(fourcc: &str, modifier: &str) -> Option<Format> {
+        Some(Format {
+            code: parse_fourcc_name(fourcc)?,
+            modifier: parse_modifier_name(modifier)?,
+        })
+    }
+
+    fn to_strings(&self) -> (String, String) {
+        (fourcc_name(self.code), modifier_name(self.modifier))
+    }
+
+    fn min_buffer_size(&self, width: u32, height: u32) -> Option<usize> {
+        let info = FormatInfo::for_fourcc(self.code)?;
+        (0..info.plane_count()).try_fold(0usize, |size, plane| {
+            let row = (info.plane_width(plane, width)? as usize)
+                .checked_mul(info.bytes_per_pixel(plane)? as usize)?;
+            let plane_size = row.checked_mul(info.plane_height(plane, height)? as usize)?;
+            size.checked_add(plane_size)
+        })
+    }
+}
+
+fn fourcc_name(code: Fourcc) -> String {
+    NAMED_FOURCCS
+        .iter()
+        .find(|(fourcc, _)| *fourcc == code)
+        .map(|(_, name)| name.to_uppercase())
+        .unwrap_or_else(|| code.to_string())
+}
+
+fn parse_fourcc_name(name: &str) -> Option<Fourcc> {
+    let upper = name.to_uppercase();
+    let stripped = upper.strip_prefix("DRM_FORMAT_").unwrap_or(&upper);
+    NAMED_FOURCCS
+        .iter()
+        .find(|(_, name)| name.eq_ignore_ascii_case(stripped))
+        .map(|(fourcc, _)| *fourcc)
+        .or_else(|| Fourcc::parse(name))
+}
+
+fn modifier_name(modifier: Modifier) -> String {
+    match NAMED_MODIFIERS.iter().find(|(named, _)| *named == modifier) {
+        Some((_, name)) => match name.strip_prefix("I915_") {
+            Some(rest) => format!("I915_FORMAT_MOD_{}", rest.to_uppercase()),
+            None => format!("DRM_FORMAT_MOD_{}", name.to_uppercase()),
+        },
+        None => format!("{:#x}", u64::from(modifier)),
+    }
+}
+
+fn parse_modifier_name(name: &str) -> Option<Modifier> {
+    if let Some(hex) = name.strip_prefix("0x").or_else(|| name.strip_prefix("0X")) {
+        return u64::from_str_radix(hex, 16).ok().map(Modifier::from);
+    }
+    NAMED_MODIFIERS
+        .iter()
+        .map(|(modifier, _)| *modifier)
+        .find(|modifier| modifier_name(*modifier).eq_ignore_ascii_case(name))
+}
+
+/// Well-known vendor modifiers
+///
+/// [`Modifier`] only has variants for a fixed set of modifiers, many modifiers in use today,
+/// like the ones of AMD, are composed out of several fields instead. These constants name
+/// the most common ones, so advertised formats can be written without raw values. They compare
+/// equal to the corresponding variants of [`Modifier`], if there are any.
+///
+/// The vendor of any modifier can be decoded using [`Modifier::vendor`].
+pub trait ModifierExt {
+    /// Intel X-tiling
+    const I915_X_TILED: Modifier = Modifier::I915_x_tiled;
+    /// Intel Y-tiling
+    const I915_Y_TILED: Modifier = Modifier::I915_y_tiled;
+    /// AMD GFX9 64KiB standard swizzle
+    const AMD_GFX9_64K_S: Modifier = amd_gfx9(AMD_TILE_64K_S);
+    /// AMD GFX9 64KiB display swizzle
+    const AMD_GFX9_64K_D: Modifier = amd_gfx9(AMD_TILE_64K_D);
+    /// AMD GFX9 64KiB standard swizzle with xor
+    const AMD_GFX9_64K_S_X: Modifier = amd_gfx9(AMD_TILE_64K_S_X);
+    /// AMD GFX9 64KiB display swizzle with xor
+    const AMD_GFX9_64K_D_X: Modifier = amd_gfx9(AMD_TILE_64K_D_X);
+    /// AMD GFX9 64KiB render swizzle with xor
+    const AMD_GFX9_64K_R_X: Modifier = amd_gfx9(AMD_TILE_64K_R_X);
+    /// NVIDIA block-linear layout with blocks of one GOB, as used by Tegra
+    const NVIDIA_BLOCK_LINEAR_ONE_GOB: Modifier = Modifier::Nvidia_16bx2_block_one_gob;
+    /// NVIDIA block-linear layout with blocks of sixteen GOBs, as used by Tegra
+    const NVIDIA_BLOCK_LINEAR_SIXTEEN_GOB: Modifier = Modifier::Nvidia_16bx2_block_sixteen_gob;
+    /// ARM AFBC compression with 16x16 superblocks
+    const ARM_AFBC_16X16: Modifier = arm_afbc(AFBC_BLOCK_SIZE_16X16);
+    /// ARM AFBC compression with 16x16 superblocks and the YUV-like color transform
+    const ARM_AFBC_16X16_YTR: Modifier = arm_afbc(AFBC_BLOCK_SIZE_16X16 | AFBC_YTR);
+    /// ARM AFBC compression with 16x16 superblocks, the YUV-like color transform and sparse layout
+    const ARM_AFBC_16X16_YTR_SPARSE: Modifier = arm_afbc(AFBC_BLOCK_SIZE_16X16 | AFBC_YTR | AFBC_SPARSE);
+}
+
+impl ModifierExt for Modifier {}
+
+const VENDOR_AMD: u64 = 0x02;
+const VENDOR_ARM: u64 = 0x08;
+
+const AMD_TILE_VERSION_GFX9: u64 = 1;
+const AMD_TILE_64K_S: u64 = 9;
+const AMD_TILE_64K_D: u64 = 10;
+const AMD_TILE_64K_S_X: u64 = 25;
+const AMD_TILE_64K_D_X: u64 = 26;
+const AMD_TILE_64K_R_X: u64 = 27;
+
+const AFBC_BLOCK_SIZE_16X16: u64 = 1;
+const AFBC_YTR: u64 = 1 << 4;
+const AFBC_SPARSE: u64 = 1 << 6;
+
+/// `fourcc_mod_code` of the kernel headers
+const fn vendor_modifier(vendor: u64, value: u64) -> Modifier {
+    Modifier::Unrecognized((vendor << 56) | (value & 0x00ff_ffff_ffff_ffff))
+}
+
+const fn amd_gfx9(tile: u64) -> Modifier {
+    vendor_modifier(VENDOR_AMD, AMD_TILE_VERSION_GFX9 | (tile << 8))
+}
+
+const fn arm_afbc(mode: u64) -> Modifier {
+    // the afbc type is zero
+    vendor_modifier(VENDOR_ARM, mode)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn plane_counts() {
+        assert_eq!(Fourcc::Argb8888.plane_count(), Some(1));
+        assert_eq!(Fourcc::Nv12.plane_count(), Some(2));
+        assert_eq!(Fourcc::Yuv420.plane_count(), Some(3));
+        assert_eq!(Fourcc::Yuv420.subsampling(2), Some((2, 2)));
+        // compressed format with an unknown layout
+        assert_eq!(Fourcc::Q401.plane_count(), None);
+    }
+
+    #[test]
+    fn parse_fourcc() {
+        assert_eq!(Fourcc::parse("XR24"), Some(Fourcc::Xrgb8888));
+        assert_eq!(Fourcc::parse("NV12"), Some(Fourcc::Nv12));
+        assert_eq!(
+            Fourcc::parse(&Fourcc::Argb8888.to_string()),
+            Some(Fourcc::Argb8888)
+        );
+        assert_eq!(Fourcc::parse("XR2"), None);
+        assert_eq!(Fourcc::parse("ABCD"), None);
+    }
+
+    #[test]
+    fn alpha_channels() {
+        assert!(Fourcc::Argb8888.has_alpha());
+        assert!(!Fourcc::Xrgb8888.has_alpha());
+        assert!(Fourcc::Abgr2101010.has_alpha());
+        assert!(!Fourcc::Xbgr2101010.has_alpha());
+        assert!(Fourcc::Rgba4444.has_alpha());
+        assert!(!Fourcc::Rgbx4444.has_alpha());
+        assert!(Fourcc::Argb1555.has_alpha());
+        assert!(!Fourcc::Rgb565.has_alpha());
+        assert!(!Fourcc::Rgb888.has_alpha());
+        assert!(Fourcc::Xrgb8888_a8.has_alpha());
+        assert!(!Fourcc::Nv12.has_alpha());
+    }
+
+    #[test]
+    fn format_strings_round_trip() {
+        let format = Format::from_strings("XRGB8888", "I915_FORMAT_MOD_Y_TILED").unwrap();
+        assert_eq!(format.code, Fourcc::Xrgb8888);
+        assert_eq!(format.modifier, Modifier::I915_y_tiled);
+        assert_eq!(
+            format.to_strings(),
+            ("XRGB8888".to_string(), "I915_FORMAT_MOD_Y_TILED".to_string())
+        );
+
+        assert_eq!(
+            Format::from_strings("DRM_FORMAT_nv12", "drm_format_mod_linear"),
+            Some(Format {
+                code: Fourcc::Nv12,
+                modifier: Modifier::Linear,
+            })
+        );
+        assert_eq!(
+            Format::from_strings("AR24", "0x1").map(|format| format.code),
+            Some(Fourcc::Argb8888)
+        );
+        assert_eq!(Format::from_strings("XRGB8889", "DRM_FORMAT_MOD_LINEAR"), None);
+        assert_eq!(Format::from_strings("XRGB8888", "DRM_FORMAT_MOD_TILED"), None);
+
+        for (code, _) in NAMED_FOURCCS {
+            for (modifier, _) in NAMED_MODIFIERS {
+                let format = Format {
+                    code: *code,
+                    modifier: *modifier,
+                };
+                let (fourcc, modifier) = format.to_strings();
+                assert_eq!(Format::from_strings(&fourcc, &modifier), Some(format));
+            }
+        }
+        let unnamed = Format {
+            code: Fourcc::Argb8888,
+            modifier: Modifier::Unrecognized(0x0500_0000_0000_0042),
+        };
+        let (fourcc, modifier) = unnamed.to_strings();
+        assert_eq!(modifier, "0x500000000000042");
+        assert_eq!(Format::from_strings(&fourcc, &modifier), Some(unnamed));
+    }
+
+    #[test]
+    fn min_buffer_sizes() {
+        let format = |code| Format {
+            code,
+            modifier: Modifier::Linear,
+        };
+        assert_eq!(
+            format(Fourcc::Xrgb8888).min_buffer_size(1920, 1080),
+            Some(1920 * 1080 * 4)
+        );
+        assert_eq!(format(Fourcc::Rgb565).min_buffer_size(3, 3), Some(18));
+        assert_eq!(
+            format(Fourcc::Nv12).min_buffer_size(1920, 1080),
+            Some(1920 * 1080 * 3 / 2)
+        );
+        // odd dimensions round the chroma planes up
+        assert_eq!(format(Fourcc::Nv12).min_buffer_size(3, 3), Some(9 + 2 * 2 * 2));
+        assert_eq!(format(Fourcc::Yuv420).min_buffer_size(4, 4), Some(16 + 4 + 4));
+        assert_eq!(format(Fourcc::Q401).min_buffer_size(64, 64), None);
+    }
+
+    #[test]
+    fn modifier_vendors() {
+        use super::super::Vendor;
+
+        assert_eq!(Modifier::I915_X_TILED, Modifier::I915_x_tiled);
+        assert_eq!(Modifier::I915_Y_TILED.vendor(), Ok(Some(Vendor::Intel)));
+        assert_eq!(u64::from(Modifier::AMD_GFX9_64K_S_X), 0x0200_0000_0000_1901);
+        assert_eq!(Modifier::AMD_GFX9_64K_S_X.vendor(), Ok(Some(Vendor::Amd)));
+        assert_eq!(
+            Modifier::NVIDIA_BLOCK_LINEAR_ONE_GOB.vendor(),
+            Ok(Some(Vendor::Nvidia))
+        );
+        assert_eq!(u64::from(Modifier::ARM_AFBC_16X16), 0x0800_0000_0000_0001);
+        assert_eq!(
+            Modifier::ARM_AFBC_16X16_YTR_SPARSE.vendor(),
+            Ok(Some(Vendor::Arm))
+        );
+        // linear and implicit buffers have no vendor
+        assert_eq!(Modifier::Linear.vendor(), Ok(None));
+    }
+
+    #[test]
+    fn nv12_chroma_is_half_height() {
+        let info = FormatInfo::for_fourcc(Fourcc::Nv12).unwrap();
+        assert_eq!(info.plane_count(), 2);
+        assert_eq!(info.plane_height(0, 1080), Some(1080));
+        assert_eq!(info.plane_height(1, 1080), Some(540));
+        assert_eq!(info.plane_height(1, 11), Some(6));
+        assert_eq!(info.plane_height(2, 1080), None);
+    }
+
+    #[test]
+    fn yuv444_is_not_subsampled() {
+        let info = FormatInfo::for_fourcc(Fourcc::Yuv444).unwrap();
+        assert_eq!(info.plane_count(), 3);
+        for plane in 0..3 {
+            assert_eq!(info.plane_height(plane, 1080), Some(1080));
+            assert_eq!(info.bytes_per_pixel(plane), Some(1));
+        }
+    }
+}
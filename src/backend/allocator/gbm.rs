@@ -3,17 +3,328 @@
 //! The re-exported [`GbmDevice`](gbm::Device) implements the [`Allocator`](super::Allocator) trait
 //! and [`GbmBuffer`](gbm::BufferObject) satisfies the [`Buffer`](super::Buffer) trait while also allowing
 //! conversions to and from [dmabufs](super::dmabuf).
+//!
+//! To allocate buffers with other usage flags than scan-out and rendering, wrap the device
+//! into a [`GbmAllocator`]. On systems with separate render and scan-out devices,
+//! a [`GbmScanoutAllocator`] allocates scan-out buffers on the device displaying them.
+//! The contents of a buffer can be accessed by the cpu using [`GbmBufferMapExt`].
 
 use super::{
-    dmabuf::{AsDmabuf, Dmabuf, DmabufFlags, MAX_PLANES},
+    dmabuf::{AsDmabuf, Dmabuf, DmabufFlags, PlaneLayout, MAX_PLANES},
+    format::FormatInfo,
     Allocator, Buffer, Format, Fourcc, Modifier,
 };
-use crate::utils::{Buffer as BufferCoords, Size};
+use crate::utils::{Buffer as BufferCoords, Rectangle, Size};
+use gbm::AsRaw;
 pub use gbm::{BufferObject as GbmBuffer, BufferObjectFlags as GbmBufferFlags, Device as GbmDevice};
-use std::os::unix::io::AsRawFd;
+use std::collections::HashSet;
+use std::convert::TryFrom;
+use std::marker::PhantomData;
+use std::os::unix::io::{AsRawFd, FromRawFd, IntoRawFd, OwnedFd};
+
+/// Additional functionality of a [`GbmDevice`]
+pub trait GbmDeviceExt {
+    /// Filter the given `candidates` for the formats the device can allocate
+    ///
+    /// libgbm offers no way to enumerate all supported formats and modifiers, so these need to be
+    /// provided, e.g. the formats supported by your renderer via
+    /// [`ImportDma::dmabuf_formats`](crate::backend::renderer::ImportDma::dmabuf_formats)
+    /// or the formats of a drm plane. The result can be directly used to initialize a dmabuf global.
+    ///
+    /// Formats are queried using `gbm_device_get_format_modifier_plane_count`. If that fails, e.g. because
+    /// the driver does not support modifier queries, formats using [`Modifier::Invalid`] or
+    /// [`Modifier::Linear`] fall back to `gbm_device_is_format_supported` for rendering.
+    fn supported_formats(&self, candidates: impl IntoIterator<Item = Format>) -> HashSet<Format>;
+
+    /// Number of planes of buffers using `format` on this device
+    ///
+    /// Queried using `gbm_device_get_format_modifier_plane_count`, which may differ from the generic
+    /// plane count of the fourcc code, e.g. for modifiers adding compression planes. Returns `None`,
+    /// if the format is not supported or the driver does not support modifier queries.
+    fn format_plane_count(&self, format: Format) -> Option<u32>;
+
+    /// Maximum size of cursor buffers supported by the device
+    ///
+    /// Falls back to 64x64, if the driver does not report its cursor size.
+    fn cursor_size(&self) -> (u32, u32);
+
+    /// Allocate a buffer suitable for hardware cursor planes
+    ///
+    /// The buffer is allocated with [`GbmBufferFlags::CURSOR`] and [`GbmBufferFlags::WRITE`]
+    /// using an implicit modifier. Fails with [`GbmAllocatorError::CursorTooLarge`], if the size
+    /// exceeds the [`cursor_size`](GbmDeviceExt::cursor_size) of the device, and with
+    /// [`GbmAllocatorError::UnsupportedFormat`], if `fourcc` cannot be used for cursors.
+    ///
+    /// Many drivers only accept cursor buffers of exactly the reported cursor size,
+    /// so prefer allocating that size and leaving the remainder transparent.
+    fn create_cursor_buffer<T>(
+        &self,
+        width: u32,
+        height: u32,
+        fourcc: Fourcc,
+    ) -> Result<GbmBuffer<T>, GbmAllocatorError>;
+}
+
+impl<A: AsRawFd + 'static> GbmDeviceExt for GbmDevice<A> {
+    fn supported_formats(&self, candidates: impl IntoIterator<Item = Format>) -> HashSet<Format> {
+        candidates
+            .into_iter()
+            .filter(|format| {
+                if format.modifier != Modifier::Invalid && self.format_plane_count(*format).is_some() {
+                    return true;
+                }
+                (format.modifier == Modifier::Invalid || format.modifier == Modifier::Linear)
+                    && self.is_format_supported(format.code, GbmBufferFlags::RENDERING)
+            })
+            .collect()
+    }
+
+    fn format_plane_count(&self, format: Format) -> Option<u32> {
+        let planes = unsafe {
+            gbm_sys::gbm_device_get_format_modifier_plane_count(
+                self.as_raw() as *mut _,
+                format.code as u32,
+                format.modifier.into(),
+            )
+        };
+        if planes > 0 {
+            Some(planes as u32)
+        } else {
+            None
+        }
+    }
+
+    fn cursor_size(&self) -> (u32, u32) {
+        let cap = |cap| {
+            drm_ffi::get_capability(self.as_raw_fd(), cap as u64)
+                .ok()
+                .map(|cap| cap.value as u32)
+                .filter(|&value| value != 0)
+                .unwrap_or(64)
+        };
+        (
+            cap(drm_ffi::DRM_CAP_CURSOR_WIDTH),
+            cap(drm_ffi::DRM_CAP_CURSOR_HEIGHT),
+        )
+    }
+
+    fn create_cursor_buffer<T>(
+        &self,
+        width: u32,
+        height: u32,
+        fourcc: Fourcc,
+    ) -> Result<GbmBuffer<T>, GbmAllocatorError> {
+        let usage = GbmBufferFlags::CURSOR | GbmBufferFlags::WRITE;
+
+        let max = self.cursor_size();
+        if width > max.0 || height > max.1 {
+            return Err(GbmAllocatorError::CursorTooLarge {
+                requested: (width, height),
+                max,
+            });
+        }
+        if !self.is_format_supported(fourcc, usage) {
+            return Err(GbmAllocatorError::UnsupportedFormat(fourcc, usage));
+        }
+        // cursor planes only scan out a single plane, the query fails on drivers without modifier support
+        let planes = self.format_plane_count(Format {
+            code: fourcc,
+            modifier: Modifier::Linear,
+        });
+        if planes.unwrap_or(1) > 1 {
+            return Err(GbmAllocatorError::UnsupportedFormat(fourcc, usage));
+        }
+
+        self.create_buffer_object(width, height, fourcc, usage)
+            .map_err(GbmAllocatorError::Gbm)
+    }
+}
+
+/// Wrapper around a [`GbmDevice`] implementing [`Allocator`] with configurable usage flags
+///
+/// The [`Allocator`] implementation of [`GbmDevice`] always allocates buffers suitable for scan-out
+/// and rendering, which may waste scarce memory on buffers, that will never be scanned out.
+///
+/// Buffers requesting only the implicit modifier ([`Modifier::Invalid`]) are allocated without any
+/// explicit modifier, so the driver chooses their layout based on the usage flags alone. Allocating
+/// them without [`GbmBufferFlags::SCANOUT`], e.g. for buffers only used for rendering, avoids layouts
+/// constrained by the display engine. The layout chosen by the driver is not necessarily one of the
+/// explicit modifiers advertised for the format. [`Buffer::format`] reports the modifier chosen by
+/// the driver, or [`Modifier::Invalid`] if the driver does not expose it.
+#[derive(Debug, Clone)]
+pub struct GbmAllocator<A: AsRawFd + 'static> {
+    device: GbmDevice<A>,
+    default_flags: GbmBufferFlags,
+}
+
+impl<A: AsRawFd + 'static> GbmAllocator<A> {
+    /// Create a new allocator using the given usage flags for all allocated buffers
+    pub fn new(device: GbmDevice<A>, default_flags: GbmBufferFlags) -> GbmAllocator<A> {
+        GbmAllocator {
+            device,
+            default_flags,
+        }
+    }
+
+    /// Returns the underlying [`GbmDevice`]
+    pub fn device(&self) -> &GbmDevice<A> {
+        &self.device
+    }
+
+    /// Returns the usage flags used for allocating buffers
+    pub fn default_flags(&self) -> GbmBufferFlags {
+        self.default_flags
+    }
+
+    /// Allocate a single buffer with the given usage flags instead of the default ones
+    ///
+    /// Useful e.g. for allocating implicit buffers only used for rendering with an allocator
+    /// otherwise allocating scan-out buffers.
+    pub fn create_buffer_with_flags<T>(
+        &self,
+        width: u32,
+        height: u32,
+        fourcc: Fourcc,
+        modifiers: &[Modifier],
+        usage: GbmBufferFlags,
+    ) -> Result<GbmBuffer<T>, GbmAllocatorError> {
+        create_buffer(&self.device, width, height, fourcc, modifiers, usage)
+    }
+}
+
+impl<A: AsRawFd + 'static, T> Allocator<GbmBuffer<T>> for GbmAllocator<A> {
+    type Error = GbmAllocatorError;
+
+    fn create_buffer(
+        &mut self,
+        width: u32,
+        height: u32,
+        fourcc: Fourcc,
+        modifiers: &[Modifier],
+    ) -> Result<GbmBuffer<T>, Self::Error> {
+        create_buffer(&self.device, width, height, fourcc, modifiers, self.default_flags)
+    }
+
+    fn preferred_format(&self) -> Option<Format> {
+        preferred_format(&self.device, self.default_flags)
+    }
+}
+
+/// Allocator for systems with separate render and scan-out devices
+///
+/// Regular buffers are allocated on the render device, using the wrapped [`GbmAllocator`].
+/// Scan-out buffers are allocated on the device they are supposed to be displayed on,
+/// e.g. the target device of a scan-out tranche of the dmabuf feedback, using
+/// [`create_scanout_buffer`](GbmScanoutAllocator::create_scanout_buffer).
+/// These have to be imported into the render device to be rendered to.
+#[derive(Debug)]
+pub struct GbmScanoutAllocator<A: AsRawFd + 'static> {
+    render: GbmAllocator<A>,
+    render_device: libc::dev_t,
+    scanout_devices: Vec<(libc::dev_t, GbmDevice<A>)>,
+}
+
+impl<A: AsRawFd + 'static> GbmScanoutAllocator<A> {
+    /// Create a new allocator using `render` for all buffers, that are not scanned out by another device
+    ///
+    /// Fails if the device number of the render device cannot be queried.
+    pub fn new(render: GbmAllocator<A>) -> std::io::Result<GbmScanoutAllocator<A>> {
+        let render_device = device_id(render.device())?;
+        Ok(GbmScanoutAllocator {
+            render,
+            render_device,
+            scanout_devices: Vec::new(),
+        })
+    }
+
+    /// Add a device scan-out buffers can be allocated for
+    ///
+    /// Returns the device number identifying the device in
+    /// [`create_scanout_buffer`](GbmScanoutAllocator::create_scanout_buffer).
+    /// Adding a device a second time replaces the previously added one.
+    pub fn add_scanout_device(&mut self, device: GbmDevice<A>) -> std::io::Result<libc::dev_t> {
+        let dev = device_id(&device)?;
+        self.scanout_devices.retain(|(id, _)| *id != dev);
+        self.scanout_devices.push((dev, device));
+        Ok(dev)
+    }
+
+    /// Remove a previously added scan-out device, e.g. after it was unplugged
+    pub fn remove_scanout_device(&mut self, dev: libc::dev_t) -> Option<GbmDevice<A>> {
+        let idx = self.scanout_devices.iter().position(|(id, _)| *id == dev)?;
+        Some(self.scanout_devices.remove(idx).1)
+    }
+
+    /// Returns the allocator used for the render device
+    pub fn render_allocator(&self) -> &GbmAllocator<A> {
+        &self.render
+    }
+
+    /// Device number of the render device
+    pub fn render_device(&self) -> libc::dev_t {
+        self.render_device
+    }
+
+    /// Allocate a buffer to be scanned out by the device `target_dev`
+    ///
+    /// If `target_dev` is the render device, the buffer is allocated for rendering and scan-out.
+    /// Otherwise it is allocated on the added scan-out device using [`GbmBufferFlags::SCANOUT`]
+    /// only. Fails with [`GbmAllocatorError::UnknownDevice`], if no such device was added.
+    pub fn create_scanout_buffer<T>(
+        &mut self,
+        width: u32,
+        height: u32,
+        fourcc: Fourcc,
+        modifiers: &[Modifier],
+        target_dev: libc::dev_t,
+    ) -> Result<GbmBuffer<T>, GbmAllocatorError> {
+        if target_dev == self.render_device {
+            return create_buffer(
+                self.render.device(),
+                width,
+                height,
+                fourcc,
+                modifiers,
+                GbmBufferFlags::SCANOUT | GbmBufferFlags::RENDERING,
+            );
+        }
+        let device = self
+            .scanout_devices
+            .iter()
+            .find(|(id, _)| *id == target_dev)
+            .map(|(_, device)| device)
+            .ok_or(GbmAllocatorError::UnknownDevice(target_dev))?;
+        create_buffer(device, width, height, fourcc, modifiers, GbmBufferFlags::SCANOUT)
+    }
+}
+
+impl<A: AsRawFd + 'static, T> Allocator<GbmBuffer<T>> for GbmScanoutAllocator<A> {
+    type Error = GbmAllocatorError;
+
+    fn create_buffer(
+        &mut self,
+        width: u32,
+        height: u32,
+        fourcc: Fourcc,
+        modifiers: &[Modifier],
+    ) -> Result<GbmBuffer<T>, Self::Error> {
+        self.render.create_buffer(width, height, fourcc, modifiers)
+    }
+
+    fn preferred_format(&self) -> Option<Format> {
+        Allocator::<GbmBuffer<T>>::preferred_format(&self.render)
+    }
+}
+
+/// Device number of the drm node opened by `device`
+fn device_id<A: AsRawFd + 'static>(device: &GbmDevice<A>) -> std::io::Result<libc::dev_t> {
+    nix::sys::stat::fstat(device.as_raw_fd())
+        .map(|stat| stat.st_rdev)
+        .map_err(std::io::Error::from)
+}
 
 impl<A: AsRawFd + 'static, T> Allocator<GbmBuffer<T>> for GbmDevice<A> {
-    type Error = std::io::Error;
+    type Error = GbmAllocatorError;
 
     fn create_buffer(
         &mut self,
@@ -22,21 +333,114 @@ impl<A: AsRawFd + 'static, T> Allocator<GbmBuffer<T>> for GbmDevice<A> {
         fourcc: Fourcc,
         modifiers: &[Modifier],
     ) -> Result<GbmBuffer<T>, Self::Error> {
-        match self.create_buffer_object_with_modifiers(width, height, fourcc, modifiers.iter().copied()) {
-            Ok(bo) => Ok(bo),
-            Err(err) => {
-                if modifiers.contains(&Modifier::Invalid) || modifiers.contains(&Modifier::Linear) {
-                    let mut usage = GbmBufferFlags::SCANOUT | GbmBufferFlags::RENDERING;
-                    if !modifiers.contains(&Modifier::Invalid) {
-                        usage |= GbmBufferFlags::LINEAR;
-                    }
-                    self.create_buffer_object(width, height, fourcc, usage)
-                } else {
-                    Err(err)
-                }
-            }
+        create_buffer(
+            self,
+            width,
+            height,
+            fourcc,
+            modifiers,
+            GbmBufferFlags::SCANOUT | GbmBufferFlags::RENDERING,
+        )
+    }
+
+    fn preferred_format(&self) -> Option<Format> {
+        preferred_format(self, GbmBufferFlags::SCANOUT | GbmBufferFlags::RENDERING)
+    }
+}
+
+/// The first common scan-out format supported by `device` for `usage`
+///
+/// libgbm cannot enumerate the modifiers of a format, so the implicit modifier is returned,
+/// which lets the driver choose the optimal layout on allocation.
+fn preferred_format<A: AsRawFd + 'static>(device: &GbmDevice<A>, usage: GbmBufferFlags) -> Option<Format> {
+    [Fourcc::Xrgb8888, Fourcc::Argb8888]
+        .iter()
+        .find(|&&code| device.is_format_supported(code, usage))
+        .map(|&code| Format {
+            code,
+            modifier: Modifier::Invalid,
+        })
+}
+
+/// Errors of the [`Allocator`] implementations for gbm
+#[derive(thiserror::Error, Debug)]
+pub enum GbmAllocatorError {
+    /// The format is not supported by the device for the requested usage
+    #[error("The format {0:?} is not supported for usage {1:?}")]
+    UnsupportedFormat(Fourcc, GbmBufferFlags),
+    /// The requested cursor buffer exceeds the cursor size of the device
+    #[error("The cursor size {requested:?} exceeds the maximum cursor size {max:?}")]
+    CursorTooLarge {
+        /// Requested size of the buffer
+        requested: (u32, u32),
+        /// Maximum cursor size of the device
+        max: (u32, u32),
+    },
+    /// No device with the requested device number is known to the allocator
+    #[error("Unknown device {0}")]
+    UnknownDevice(libc::dev_t),
+    /// Explicit modifiers cannot be combined with the usage flags, and neither the implicit
+    /// nor the linear modifier is acceptable to allocate the buffer without them
+    #[error("None of the modifiers {modifiers:?} can be allocated for usage {usage:?}")]
+    UnsupportedModifiers {
+        /// Acceptable modifiers of the buffer
+        modifiers: Vec<Modifier>,
+        /// Requested usage of the buffer
+        usage: GbmBufferFlags,
+    },
+    /// The allocation failed inside of libgbm
+    #[error("The gbm allocation failed: {0}")]
+    Gbm(#[source] std::io::Error),
+}
+
+fn create_buffer<A: AsRawFd + 'static, T>(
+    device: &GbmDevice<A>,
+    width: u32,
+    height: u32,
+    fourcc: Fourcc,
+    modifiers: &[Modifier],
+    usage: GbmBufferFlags,
+) -> Result<GbmBuffer<T>, GbmAllocatorError> {
+    if !device.is_format_supported(fourcc, usage) {
+        return Err(GbmAllocatorError::UnsupportedFormat(fourcc, usage));
+    }
+
+    // All modifiers are passed to gbm, so the driver can choose the best one for the buffer.
+    // Buffers allocated with explicit modifiers cannot be given any usage flags,
+    // which is only fine, if they are used for scan-out and rendering.
+    // Implicit buffers are always allocated using the usage flags, letting the driver choose their layout.
+    let implicit_only = modifiers.iter().all(|modifier| *modifier == Modifier::Invalid);
+    let fallback = fallback_usage(modifiers, usage);
+    if !implicit_only && (GbmBufferFlags::SCANOUT | GbmBufferFlags::RENDERING).contains(usage) {
+        match device.create_buffer_object_with_modifiers(width, height, fourcc, modifiers.iter().copied()) {
+            Ok(bo) => return Ok(bo),
+            Err(err) if fallback.is_none() => return Err(GbmAllocatorError::Gbm(err)),
+            Err(_) => {}
         }
     }
+
+    match fallback {
+        Some(usage) => device
+            .create_buffer_object(width, height, fourcc, usage)
+            .map_err(GbmAllocatorError::Gbm),
+        None => Err(GbmAllocatorError::UnsupportedModifiers {
+            modifiers: modifiers.to_vec(),
+            usage,
+        }),
+    }
+}
+
+/// Usage flags of the allocation without explicit modifiers, if any of `modifiers` permits it
+///
+/// Linear buffers need to be requested explicitly, unless the implicit modifier is acceptable as well.
+fn fallback_usage(modifiers: &[Modifier], usage: GbmBufferFlags) -> Option<GbmBufferFlags> {
+    if modifiers.contains(&Modifier::Invalid) {
+        Some(usage)
+    } else if modifiers.contains(&Modifier::Linear) {
+        Some(usage | GbmBufferFlags::LINEAR)
+    } else {
+        None
+    }
 }
 
 impl<T> Buffer for GbmBuffer<T> {
@@ -56,27 +460,187 @@ impl<T> Buffer for GbmBuffer<T> {
     }
 }
 
+/// Error importing a [`Dmabuf`] using libgbm
+#[derive(thiserror::Error, Debug)]
+#[error(
+    "Failed to import a {}x{} dmabuf of format {} with modifier {:?} and {} planes: {source}",
+    .size.0, .size.1, .format.code, .format.modifier, .planes
+)]
+pub struct GbmImportError {
+    /// Size of the dmabuf
+    pub size: (u32, u32),
+    /// Format and modifier of the dmabuf
+    pub format: Format,
+    /// Number of planes of the dmabuf
+    pub planes: usize,
+    /// Error returned by libgbm
+    #[source]
+    pub source: std::io::Error,
+}
+
 /// Errors during conversion to a dmabuf handle from a gbm buffer object
 #[derive(thiserror::Error, Debug)]
 pub enum GbmConvertError {
     /// The gbm device was destroyed
     #[error("The gbm device was destroyed")]
     DeviceDestroyed(#[from] gbm::DeviceDestroyedError),
-    /// The buffer consists out of multiple file descriptions, which is currently unsupported
-    #[error("Buffer consists out of multiple file descriptors, which is currently unsupported")]
+    /// The buffer consists out of multiple file descriptions, which cannot be exported
+    /// by the used version of libgbm
+    #[error("Buffer consists out of multiple file descriptors, which is unsupported by libgbm")]
     UnsupportedBuffer,
     /// The conversion returned an invalid file descriptor
     #[error("Buffer returned invalid file descriptor")]
     InvalidFD,
+    /// The buffer consists of more planes than a [`Dmabuf`] can hold
+    #[error("Buffer consists of {0} planes, at most {} are supported", MAX_PLANES)]
+    TooManyPlanes(u32),
 }
 
 impl<T> AsDmabuf for GbmBuffer<T> {
     type Error = GbmConvertError;
 
     fn export(&self) -> Result<Dmabuf, GbmConvertError> {
-        let planes = self.plane_count()? as i32;
+        self.export_with_flags(DmabufFlags::empty())
+    }
+
+    fn export_with(&self, flags: DmabufFlags) -> Result<Dmabuf, GbmConvertError> {
+        self.export_with_flags(flags)
+    }
+}
+
+/// Export of a [`GbmBuffer`] with additional [`DmabufFlags`] or as a drm framebuffer
+pub trait GbmBufferExportExt {
+    /// Export this buffer as a new Dmabuf carrying the given `flags`
+    ///
+    /// gbm does not track any of these flags itself. Equivalent to [`AsDmabuf::export_with`].
+    fn export_with_flags(&self, flags: DmabufFlags) -> Result<Dmabuf, GbmConvertError>;
+
+    /// Collect the parameters needed to add this buffer as a drm framebuffer
+    ///
+    /// The handles are only valid for the drm device the gbm device of the buffer was created from.
+    fn framebuffer_info(&self) -> Result<FramebufferInfo, GbmConvertError>;
+}
+
+/// Parameters of a buffer needed to add it as a drm framebuffer
+///
+/// Mirrors the arguments of `drmModeAddFB2WithModifiers`: unused planes have a handle, pitch and
+/// offset of zero. With the `backend_drm` feature enabled, it implements [`drm::buffer::PlanarBuffer`],
+/// so it can be passed directly to `add_planar_framebuffer` together with
+/// [`modifiers`](FramebufferInfo::modifiers) and [`flags`](FramebufferInfo::flags).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FramebufferInfo {
+    /// Width and height of the buffer
+    pub size: (u32, u32),
+    /// Format of the buffer
+    pub format: Fourcc,
+    /// Modifier of the buffer, [`Modifier::Invalid`] for buffers using an implicit modifier
+    pub modifier: Modifier,
+    /// Number of planes of the buffer
+    pub num_planes: usize,
+    /// Gem handles of the planes
+    pub handles: [u32; MAX_PLANES],
+    /// Strides of the planes
+    pub pitches: [u32; MAX_PLANES],
+    /// Offsets of the planes
+    pub offsets: [u32; MAX_PLANES],
+}
+
+impl FramebufferInfo {
+    /// Assemble the info out of the handle, pitch and offset of every plane
+    fn new(
+        size: (u32, u32),
+        format: Fourcc,
+        modifier: Modifier,
+        planes: impl ExactSizeIterator<Item = (u32, u32, u32)>,
+    ) -> Result<FramebufferInfo, GbmConvertError> {
+        let num_planes = checked_plane_count(planes.len() as u32)? as usize;
+        let mut info = FramebufferInfo {
+            size,
+            format,
+            modifier,
+            num_planes,
+            handles: [0; MAX_PLANES],
+            pitches: [0; MAX_PLANES],
+            offsets: [0; MAX_PLANES],
+        };
+        for (idx, (handle, pitch, offset)) in planes.enumerate() {
+            info.handles[idx] = handle;
+            info.pitches[idx] = pitch;
+            info.offsets[idx] = offset;
+        }
+        Ok(info)
+    }
+
+    /// Modifier of every used plane, `None` for unused planes or an implicit modifier
+    pub fn modifiers(&self) -> [Option<Modifier>; MAX_PLANES] {
+        let mut modifiers = [None; MAX_PLANES];
+        if self.modifier != Modifier::Invalid {
+            for modifier in modifiers.iter_mut().take(self.num_planes) {
+                *modifier = Some(self.modifier);
+            }
+        }
+        modifiers
+    }
+
+    /// Flags to add the framebuffer with, `DRM_MODE_FB_MODIFIERS` for explicit modifiers
+    pub fn flags(&self) -> u32 {
+        if self.modifier != Modifier::Invalid {
+            drm_ffi::DRM_MODE_FB_MODIFIERS
+        } else {
+            0
+        }
+    }
+}
+
+#[cfg(feature = "backend_drm")]
+impl drm::buffer::PlanarBuffer for FramebufferInfo {
+    fn size(&self) -> (u32, u32) {
+        self.size
+    }
+
+    fn format(&self) -> Fourcc {
+        self.format
+    }
+
+    fn pitches(&self) -> [u32; 4] {
+        self.pitches
+    }
+
+    fn handles(&self) -> [Option<drm::buffer::Handle>; 4] {
+        let mut handles = [None; 4];
+        for (handle, raw) in handles.iter_mut().zip(self.handles.iter()) {
+            *handle = std::num::NonZeroU32::new(*raw).map(drm::buffer::Handle::from);
+        }
+        handles
+    }
+
+    fn offsets(&self) -> [u32; 4] {
+        self.offsets
+    }
+}
+
+impl<T> GbmBufferExportExt for GbmBuffer<T> {
+    fn export_with_flags(&self, flags: DmabufFlags) -> Result<Dmabuf, GbmConvertError> {
+        let planes = checked_plane_count(self.plane_count()?)?;
+
+        // Prefer a separate fd for every plane, which also allows exporting disjoint buffers.
+        // This is not supported by older versions of libgbm or all of its backends.
+        let mut builder = Dmabuf::builder_from_buffer(self, flags);
+        for idx in 0..planes {
+            let (offset, stride, modifier) =
+                (self.offset(idx)?, self.stride_for_plane(idx)?, self.modifier()?);
+            let fd = match self.fd_for_plane(idx) {
+                Some(fd) => fd,
+                None => break,
+            };
+            builder.add_plane(fd.into_raw_fd(), idx as u32, offset, stride, modifier);
+            if idx == planes - 1 {
+                return Ok(builder.build().unwrap());
+            }
+        }
+        // closes the fds of the planes added so far
+        drop(builder);
 
-        //TODO switch to gbm_bo_get_plane_fd when it lands
         let mut iter = (0i32..planes).map(|i| self.handle_for_plane(i));
         let first = iter.next().expect("Encountered a buffer with zero planes");
         // check that all handles are the same
@@ -96,45 +660,354 @@ impl<T> AsDmabuf for GbmBuffer<T> {
             return Err(GbmConvertError::UnsupportedBuffer); //TODO
         }
 
-        if self.fd()? == 0 {
+        let fd = self.fd()?;
+        if fd < 0 {
             return Err(GbmConvertError::InvalidFD);
         }
+        // the fd is used for the first plane, and closed if the export fails before
+        let mut first = Some(unsafe { OwnedFd::from_raw_fd(fd) });
 
-        let mut builder = Dmabuf::builder_from_buffer(self, DmabufFlags::empty());
+        let mut builder = Dmabuf::builder_from_buffer(self, flags);
         for idx in 0..planes {
-            builder.add_plane(
-                self.fd()?,
-                idx as u32,
-                self.offset(idx)?,
-                self.stride_for_plane(idx)?,
-                self.modifier()?,
-            );
+            let (offset, stride, modifier) =
+                (self.offset(idx)?, self.stride_for_plane(idx)?, self.modifier()?);
+            let fd = match first.take() {
+                Some(fd) => fd.into_raw_fd(),
+                None => self.fd()?,
+            };
+            builder.add_plane(fd, idx as u32, offset, stride, modifier);
         }
         Ok(builder.build().unwrap())
     }
+
+    fn framebuffer_info(&self) -> Result<FramebufferInfo, GbmConvertError> {
+        let planes = checked_plane_count(self.plane_count()?)?;
+        let planes = (0..planes)
+            .map(|idx| {
+                Ok((
+                    unsafe { self.handle_for_plane(idx)?.u32_ },
+                    self.stride_for_plane(idx)?,
+                    self.offset(idx)?,
+                ))
+            })
+            .collect::<Result<Vec<_>, gbm::DeviceDestroyedError>>()?;
+        FramebufferInfo::new(
+            (self.width()?, self.height()?),
+            self.format()?,
+            self.modifier()?,
+            planes.into_iter(),
+        )
+    }
+}
+
+/// Refuse buffers, that would otherwise be truncated to the first [`MAX_PLANES`] planes
+fn checked_plane_count(planes: u32) -> Result<i32, GbmConvertError> {
+    if planes as usize > MAX_PLANES {
+        return Err(GbmConvertError::TooManyPlanes(planes));
+    }
+    Ok(planes as i32)
+}
+
+/// Convert the layout of the planes to the signed values libgbm expects,
+/// refusing strides and offsets that would otherwise wrap around
+fn checked_plane_layout(
+    layout: &[Option<PlaneLayout>; MAX_PLANES],
+) -> std::io::Result<([i32; MAX_PLANES], [i32; MAX_PLANES])> {
+    let mut strides = [0i32; MAX_PLANES];
+    let mut offsets = [0i32; MAX_PLANES];
+    for (i, plane) in layout.iter().enumerate() {
+        if let Some(plane) = plane {
+            let convert = |value: u32, name| {
+                i32::try_from(value).map_err(|_| {
+                    std::io::Error::new(
+                        std::io::ErrorKind::InvalidInput,
+                        format!("The {} of plane {} does not fit into an i32: {}", name, i, value),
+                    )
+                })
+            };
+            strides[i] = convert(plane.stride, "stride")?;
+            offsets[i] = convert(plane.offset, "offset")?;
+        }
+    }
+    Ok((strides, offsets))
+}
+
+type GetFdForPlane = unsafe extern "C" fn(*mut gbm_sys::gbm_bo, libc::c_int) -> libc::c_int;
+
+lazy_static::lazy_static! {
+    // `gbm_bo_get_fd_for_plane` is looked up at runtime, so linking against older versions of libgbm still works
+    static ref GET_FD_FOR_PLANE: Option<GetFdForPlane> = unsafe {
+        let sym = libc::dlsym(libc::RTLD_DEFAULT, b"gbm_bo_get_fd_for_plane\0".as_ptr() as *const _);
+        if sym.is_null() {
+            None
+        } else {
+            Some(std::mem::transmute::<*mut libc::c_void, GetFdForPlane>(sym))
+        }
+    };
+}
+
+trait GbmBufferExt {
+    fn fd_for_plane(&self, plane: i32) -> Option<OwnedFd>;
+}
+
+impl<T> GbmBufferExt for GbmBuffer<T> {
+    /// Export a single plane of the buffer as a new file descriptor
+    ///
+    /// Returns `None`, if the loaded version of libgbm does not support this (added in mesa 21.1).
+    fn fd_for_plane(&self, plane: i32) -> Option<OwnedFd> {
+        let get_fd_for_plane = (*GET_FD_FOR_PLANE)?;
+        let fd = unsafe { get_fd_for_plane(self.as_raw() as *mut _, plane) };
+        if fd < 0 {
+            None
+        } else {
+            Some(unsafe { OwnedFd::from_raw_fd(fd) })
+        }
+    }
+}
+
+bitflags::bitflags! {
+    /// Access requested when mapping a [`GbmBuffer`] for cpu access
+    pub struct GbmTransferFlags: u32 {
+        /// The mapping is readable, libgbm copies the current contents of the buffer into it
+        const READ = gbm_sys::gbm_bo_transfer_flags::GBM_BO_TRANSFER_READ;
+        /// The mapping is writable, its contents are written back to the buffer on unmap
+        const WRITE = gbm_sys::gbm_bo_transfer_flags::GBM_BO_TRANSFER_WRITE;
+    }
+}
+
+/// Cpu access to the contents of a [`GbmBuffer`]
+pub trait GbmBufferMapExt {
+    /// Map the region `rect` of the buffer for reading
+    fn map_cpu(&self, rect: Rectangle<i32, BufferCoords>) -> std::io::Result<GbmMapping<'_>>;
+
+    /// Map the region `rect` of the buffer with the given access `flags`
+    ///
+    /// Requires exclusive access, as writable mappings alias the buffer contents.
+    fn map_cpu_mut(
+        &mut self,
+        rect: Rectangle<i32, BufferCoords>,
+        flags: GbmTransferFlags,
+    ) -> std::io::Result<GbmMapping<'_>>;
+}
+
+impl<T> GbmBufferMapExt for GbmBuffer<T> {
+    fn map_cpu(&self, rect: Rectangle<i32, BufferCoords>) -> std::io::Result<GbmMapping<'_>> {
+        let bytes_per_pixel = mapped_bytes_per_pixel(self, rect)?;
+        unsafe {
+            GbmMapping::new(
+                self.as_raw() as *mut _,
+                rect,
+                bytes_per_pixel,
+                GbmTransferFlags::READ,
+            )
+        }
+    }
+
+    fn map_cpu_mut(
+        &mut self,
+        rect: Rectangle<i32, BufferCoords>,
+        flags: GbmTransferFlags,
+    ) -> std::io::Result<GbmMapping<'_>> {
+        let bytes_per_pixel = mapped_bytes_per_pixel(self, rect)?;
+        unsafe { GbmMapping::new(self.as_raw() as *mut _, rect, bytes_per_pixel, flags) }
+    }
+}
+
+/// Bytes per pixel of the first plane of `buffer`, if `rect` can be mapped
+fn mapped_bytes_per_pixel<T>(
+    buffer: &GbmBuffer<T>,
+    rect: Rectangle<i32, BufferCoords>,
+) -> std::io::Result<u32> {
+    let destroyed = |err: gbm::DeviceDestroyedError| std::io::Error::new(std::io::ErrorKind::NotFound, err);
+    check_map_region(
+        rect,
+        (
+            buffer.width().map_err(destroyed)?,
+            buffer.height().map_err(destroyed)?,
+        ),
+        buffer.format().map_err(destroyed)?,
+    )
+}
+
+/// Check that `rect` lies within a buffer of `size` in `format`, returning its bytes per pixel
+///
+/// Only the first plane of formats with a known layout can be mapped.
+fn check_map_region(
+    rect: Rectangle<i32, BufferCoords>,
+    size: (u32, u32),
+    format: Fourcc,
+) -> std::io::Result<u32> {
+    let invalid = |msg| std::io::Error::new(std::io::ErrorKind::InvalidInput, msg);
+    if rect.loc.x < 0 || rect.loc.y < 0 || rect.size.w <= 0 || rect.size.h <= 0 {
+        return Err(invalid("Invalid region to map"));
+    }
+    if rect.loc.x as u64 + rect.size.w as u64 > size.0 as u64
+        || rect.loc.y as u64 + rect.size.h as u64 > size.1 as u64
+    {
+        return Err(invalid("Region to map exceeds the buffer"));
+    }
+    FormatInfo::for_fourcc(format)
+        .and_then(|info| info.bytes_per_pixel(0))
+        .ok_or_else(|| invalid("Layout of the buffer format is unknown"))
+}
+
+/// Length of a mapping of `rect` with rows `stride` bytes apart
+///
+/// The mapping ends with the last pixel of the region, the padding after it
+/// on the last row may lie outside of the mapped memory.
+fn mapping_len(rect: Rectangle<i32, BufferCoords>, stride: u32, bytes_per_pixel: u32) -> usize {
+    stride as usize * (rect.size.h as usize - 1) + rect.size.w as usize * bytes_per_pixel as usize
+}
+
+/// A region of a [`GbmBuffer`] mapped for cpu access
+///
+/// The region is unmapped again once the mapping is dropped.
+/// Depending on the driver the mapping may be a copy of the buffer contents,
+/// in which case writes are only visible after unmapping.
+#[derive(Debug)]
+pub struct GbmMapping<'a> {
+    bo: *mut gbm_sys::gbm_bo,
+    map_data: *mut libc::c_void,
+    ptr: *mut u8,
+    len: usize,
+    stride: u32,
+    rect: Rectangle<i32, BufferCoords>,
+    flags: GbmTransferFlags,
+    _buffer: PhantomData<&'a ()>,
+}
+
+impl<'a> GbmMapping<'a> {
+    /// Map `rect` of `bo`, which has to be checked by [`check_map_region`] first
+    unsafe fn new(
+        bo: *mut gbm_sys::gbm_bo,
+        rect: Rectangle<i32, BufferCoords>,
+        bytes_per_pixel: u32,
+        flags: GbmTransferFlags,
+    ) -> std::io::Result<GbmMapping<'a>> {
+        let mut stride = 0;
+        let mut map_data = std::ptr::null_mut();
+        let ptr = gbm_sys::gbm_bo_map(
+            bo,
+            rect.loc.x as u32,
+            rect.loc.y as u32,
+            rect.size.w as u32,
+            rect.size.h as u32,
+            flags.bits(),
+            &mut stride,
+            &mut map_data,
+        );
+        if ptr.is_null() {
+            return Err(std::io::Error::last_os_error());
+        }
+
+        Ok(GbmMapping {
+            bo,
+            map_data,
+            ptr: ptr as *mut u8,
+            len: mapping_len(rect, stride, bytes_per_pixel),
+            stride,
+            rect,
+            flags,
+            _buffer: PhantomData,
+        })
+    }
+
+    /// Number of bytes between the starts of two consecutive rows of the mapping
+    pub fn stride(&self) -> u32 {
+        self.stride
+    }
+
+    /// The mapped region of the buffer
+    pub fn region(&self) -> Rectangle<i32, BufferCoords> {
+        self.rect
+    }
+
+    /// The access flags the region was mapped with
+    pub fn flags(&self) -> GbmTransferFlags {
+        self.flags
+    }
+
+    /// Contents of the mapped region, starting with the top-left pixel of the region
+    ///
+    /// Rows are [`stride`](GbmMapping::stride) bytes apart, the data ends with the last pixel
+    /// of the region. Returns `None` if the region was not mapped for reading.
+    pub fn data(&self) -> Option<&[u8]> {
+        if self.flags.contains(GbmTransferFlags::READ) {
+            Some(unsafe { std::slice::from_raw_parts(self.ptr, self.len) })
+        } else {
+            None
+        }
+    }
+
+    /// Mutable contents of the mapped region, starting with the top-left pixel of the region
+    ///
+    /// Returns `None` if the region was not mapped for writing.
+    pub fn data_mut(&mut self) -> Option<&mut [u8]> {
+        if self.flags.contains(GbmTransferFlags::WRITE) {
+            Some(unsafe { std::slice::from_raw_parts_mut(self.ptr, self.len) })
+        } else {
+            None
+        }
+    }
+}
+
+impl<'a> Drop for GbmMapping<'a> {
+    fn drop(&mut self) {
+        unsafe { gbm_sys::gbm_bo_unmap(self.bo, self.map_data) };
+    }
 }
 
 impl Dmabuf {
     /// Import a Dmabuf using libgbm, creating a gbm Buffer Object to the same underlying data.
+    ///
+    /// Buffers using an explicit modifier, multiple planes or an offset are imported using
+    /// `gbm_bo_import` with `GBM_BO_IMPORT_FD_MODIFIER`, all others using the legacy `GBM_BO_IMPORT_FD`.
     pub fn import_to<A: AsRawFd + 'static, T>(
         &self,
         gbm: &GbmDevice<A>,
         usage: GbmBufferFlags,
-    ) -> std::io::Result<GbmBuffer<T>> {
-        let mut handles = [0; MAX_PLANES];
-        for (i, handle) in self.handles().take(MAX_PLANES).enumerate() {
-            handles[i] = handle;
-        }
-        let mut strides = [0i32; MAX_PLANES];
-        for (i, stride) in self.strides().take(MAX_PLANES).enumerate() {
-            strides[i] = stride as i32;
+    ) -> Result<GbmBuffer<T>, GbmImportError> {
+        self.import_to_with(gbm, usage, false)
+    }
+
+    /// Test if the dmabuf can be imported into `gbm` for the given `usage`
+    ///
+    /// The buffer object of the import is destroyed right away. Useful to probe, which formats
+    /// and modifiers actually import on a device, e.g. before advertising them in a dmabuf feedback.
+    pub fn test_import<A: AsRawFd + 'static>(&self, gbm: &GbmDevice<A>, usage: GbmBufferFlags) -> bool {
+        self.import_to::<A, ()>(gbm, usage).is_ok()
+    }
+
+    /// Import a Dmabuf using libgbm, optionally forcing the modifier-aware import
+    ///
+    /// If `force_modifiers` is set, the buffer is always imported with `GBM_BO_IMPORT_FD_MODIFIER`,
+    /// passing the modifier of the buffer as is. Otherwise this behaves like [`Dmabuf::import_to`].
+    pub fn import_to_with<A: AsRawFd + 'static, T>(
+        &self,
+        gbm: &GbmDevice<A>,
+        usage: GbmBufferFlags,
+        force_modifiers: bool,
+    ) -> Result<GbmBuffer<T>, GbmImportError> {
+        let error = |source| GbmImportError {
+            size: (self.width(), self.height()),
+            format: self.format(),
+            planes: self.num_planes(),
+            source,
+        };
+        if self.num_planes() == 0 {
+            return Err(error(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "Dmabuf has no planes",
+            )));
         }
-        let mut offsets = [0i32; MAX_PLANES];
-        for (i, offset) in self.offsets().take(MAX_PLANES).enumerate() {
-            offsets[i] = offset as i32;
+
+        let mut handles = [0; MAX_PLANES];
+        for (i, plane) in self.planes().take(MAX_PLANES).enumerate() {
+            handles[i] = plane.fd();
         }
+        let (strides, offsets) = checked_plane_layout(&self.plane_layout()).map_err(error)?;
 
-        if self.has_modifier() || self.num_planes() > 1 || self.offsets().next().unwrap() != 0 {
+        let result = if force_modifiers || self.requires_modifier_import() {
             gbm.import_buffer_object_from_dma_buf_with_modifiers(
                 self.num_planes() as u32,
                 handles,
@@ -159,6 +1032,360 @@ impl Dmabuf {
                     usage
                 },
             )
-        }
+        };
+        result.map_err(error)
+    }
+
+    /// Returns if the buffer cannot be described by the legacy `GBM_BO_IMPORT_FD`,
+    /// which only supports a single plane without an offset
+    fn requires_modifier_import(&self) -> bool {
+        self.has_modifier()
+            || self.num_planes() > 1
+            || self.planes().next().map(|plane| plane.offset()).unwrap_or(0) != 0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::allocator::dmabuf::PlaneInfo;
+    use std::os::unix::io::{AsFd, IntoRawFd};
+
+    #[test]
+    fn implicit_and_linear_fallback() {
+        let render = GbmBufferFlags::RENDERING;
+        assert_eq!(fallback_usage(&[Modifier::Invalid], render), Some(render));
+        assert_eq!(
+            fallback_usage(&[Modifier::Invalid, Modifier::Linear], render),
+            Some(render)
+        );
+        assert_eq!(
+            fallback_usage(&[Modifier::Linear], render),
+            Some(render | GbmBufferFlags::LINEAR)
+        );
+        assert_eq!(fallback_usage(&[Modifier::I915_x_tiled], render), None);
+    }
+
+    #[test]
+    fn too_many_planes() {
+        assert_eq!(
+            checked_plane_count(MAX_PLANES as u32).ok(),
+            Some(MAX_PLANES as i32)
+        );
+        assert!(matches!(
+            checked_plane_count(MAX_PLANES as u32 + 1),
+            Err(GbmConvertError::TooManyPlanes(planes)) if planes == MAX_PLANES as u32 + 1
+        ));
+    }
+
+    #[test]
+    fn plane_layout_overflow() {
+        let plane = PlaneInfo {
+            fd: std::fs::File::open("/dev/zero").unwrap().into_raw_fd(),
+            offset: 0,
+            stride: i32::MAX as u32 + 1,
+            modifier: Modifier::Linear,
+        };
+        let dmabuf = Dmabuf::from_planes((64, 64), Fourcc::Argb8888, DmabufFlags::empty(), &[plane]).unwrap();
+        let layout = dmabuf.plane_layout();
+        assert_eq!(
+            layout[0],
+            Some(PlaneLayout {
+                offset: 0,
+                stride: i32::MAX as u32 + 1
+            })
+        );
+        assert_eq!(layout[1], None);
+        let err = checked_plane_layout(&layout).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput);
+
+        let mut layout = [None; MAX_PLANES];
+        layout[0] = Some(PlaneLayout {
+            offset: 0,
+            stride: 256,
+        });
+        layout[1] = Some(PlaneLayout {
+            offset: u32::MAX,
+            stride: 256,
+        });
+        assert!(checked_plane_layout(&layout).is_err());
+        layout[1] = Some(PlaneLayout {
+            offset: 16384,
+            stride: 128,
+        });
+        assert_eq!(
+            checked_plane_layout(&layout).unwrap(),
+            ([256, 128, 0, 0], [0, 16384, 0, 0])
+        );
+    }
+
+    #[test]
+    fn map_region() {
+        let rect = |x, y, w, h| Rectangle::<i32, BufferCoords>::from_loc_and_size((x, y), (w, h));
+        assert_eq!(
+            check_map_region(rect(16, 8, 48, 56), (64, 64), Fourcc::Argb8888).ok(),
+            Some(4)
+        );
+        assert_eq!(
+            check_map_region(rect(0, 0, 64, 64), (64, 64), Fourcc::Nv12).ok(),
+            Some(1)
+        );
+        // regions exceeding the buffer
+        assert!(check_map_region(rect(16, 0, 49, 64), (64, 64), Fourcc::Argb8888).is_err());
+        assert!(check_map_region(rect(0, 8, 64, 57), (64, 64), Fourcc::Argb8888).is_err());
+        assert!(check_map_region(rect(-1, 0, 16, 16), (64, 64), Fourcc::Argb8888).is_err());
+        assert!(check_map_region(rect(0, 0, 0, 16), (64, 64), Fourcc::Argb8888).is_err());
+        // formats with an unknown layout
+        assert!(check_map_region(rect(0, 0, 16, 16), (64, 64), Fourcc::Vuy101010).is_err());
+
+        // the last row of a region right of the buffer origin ends with its last pixel
+        assert_eq!(mapping_len(rect(16, 8, 48, 56), 256, 4), 256 * 55 + 48 * 4);
+        assert_eq!(mapping_len(rect(16, 8, 48, 1), 256, 4), 48 * 4);
+    }
+
+    #[test]
+    #[ignore = "requires a gpu"]
+    fn map_offset_region() {
+        let node = std::fs::File::open("/dev/dri/renderD128").unwrap();
+        let device = GbmDevice::new(node).unwrap();
+        let buffer = device
+            .create_buffer_object::<()>(
+                64,
+                64,
+                Fourcc::Argb8888,
+                GbmBufferFlags::RENDERING | GbmBufferFlags::LINEAR,
+            )
+            .unwrap();
+
+        let rect = Rectangle::from_loc_and_size((16, 8), (48, 56));
+        let mapping = buffer.map_cpu(rect).unwrap();
+        let data = mapping.data().unwrap();
+        assert_eq!(data.len(), mapping.stride() as usize * 55 + 48 * 4);
+        // reading the last pixel of the region must not fault
+        let last = data.len() - 4;
+        let _pixel = u32::from_ne_bytes([data[last], data[last + 1], data[last + 2], data[last + 3]]);
+        drop(mapping);
+
+        assert!(buffer
+            .map_cpu(Rectangle::from_loc_and_size((16, 0), (64, 64)))
+            .is_err());
+    }
+
+    #[test]
+    fn framebuffer_info() {
+        let planes = [(1, 256, 0), (1, 128, 16384)];
+        let info = FramebufferInfo::new(
+            (64, 64),
+            Fourcc::Nv12,
+            Modifier::I915_y_tiled,
+            planes.iter().copied(),
+        )
+        .unwrap();
+        assert_eq!(info.num_planes, 2);
+        assert_eq!(info.handles, [1, 1, 0, 0]);
+        assert_eq!(info.pitches, [256, 128, 0, 0]);
+        assert_eq!(info.offsets, [0, 16384, 0, 0]);
+        assert_eq!(
+            info.modifiers(),
+            [
+                Some(Modifier::I915_y_tiled),
+                Some(Modifier::I915_y_tiled),
+                None,
+                None
+            ]
+        );
+        assert_eq!(info.flags(), drm_ffi::DRM_MODE_FB_MODIFIERS);
+
+        let info = FramebufferInfo::new(
+            (64, 64),
+            Fourcc::Argb8888,
+            Modifier::Invalid,
+            [(1, 256, 0)].iter().copied(),
+        )
+        .unwrap();
+        assert_eq!(info.modifiers(), [None; MAX_PLANES]);
+        assert_eq!(info.flags(), 0);
+
+        let too_many = FramebufferInfo::new(
+            (64, 64),
+            Fourcc::Argb8888,
+            Modifier::Linear,
+            vec![(1, 256, 0); 5].into_iter(),
+        );
+        assert!(matches!(too_many, Err(GbmConvertError::TooManyPlanes(5))));
+    }
+
+    #[test]
+    #[ignore = "requires a gpu"]
+    fn import_without_planes() {
+        let node = std::fs::File::open("/dev/dri/renderD128").unwrap();
+        let device = GbmDevice::new(node).unwrap();
+
+        // the builder refuses to build dmabufs without planes, so drop them afterwards
+        let file = tempfile::tempfile().unwrap();
+        let mut builder = Dmabuf::builder((64, 64), Fourcc::Argb8888, DmabufFlags::empty());
+        builder.add_plane(file.into_raw_fd(), 0, 0, 256, Modifier::Linear);
+        let mut dmabuf = builder.build().unwrap();
+        std::sync::Arc::get_mut(&mut dmabuf.0).unwrap().planes.clear();
+
+        let err = match dmabuf.import_to::<_, ()>(&device, GbmBufferFlags::RENDERING) {
+            Err(err) => err,
+            Ok(_) => panic!("imported a dmabuf without planes"),
+        };
+        assert!(matches!(
+            err,
+            GbmImportError { planes: 0, ref source, .. }
+                if source.kind() == std::io::ErrorKind::InvalidInput
+        ));
+    }
+
+    #[test]
+    fn import_error_describes_dmabuf() {
+        let err = GbmImportError {
+            size: (64, 32),
+            format: Format {
+                code: Fourcc::Nv12,
+                modifier: Modifier::I915_y_tiled,
+            },
+            planes: 2,
+            source: std::io::Error::from_raw_os_error(libc::EINVAL),
+        };
+        let message = err.to_string();
+        assert!(message.contains("64x32"));
+        assert!(message.contains(&Fourcc::Nv12.to_string()));
+        assert!(message.contains(&format!("{:?}", Modifier::I915_y_tiled)));
+        assert!(message.contains("2 planes"));
+        assert_eq!(
+            std::error::Error::source(&err)
+                .and_then(|source| source.downcast_ref::<std::io::Error>())
+                .and_then(|source| source.raw_os_error()),
+            Some(libc::EINVAL)
+        );
+    }
+
+    #[test]
+    #[ignore = "requires a gpu"]
+    fn import_error_names_modifier() {
+        let node = std::fs::File::open("/dev/dri/renderD128").unwrap();
+        let device = GbmDevice::new(node).unwrap();
+
+        // no desktop driver supports the tiling of the raspberry pi
+        let modifier = Modifier::Broadcom_vc4_t_tiled;
+        let plane = PlaneInfo {
+            fd: std::fs::File::open("/dev/zero").unwrap().into_raw_fd(),
+            offset: 0,
+            stride: 256,
+            modifier,
+        };
+        let dmabuf = Dmabuf::from_planes((64, 64), Fourcc::Argb8888, DmabufFlags::empty(), &[plane]).unwrap();
+        let err = dmabuf
+            .import_to::<_, ()>(&device, GbmBufferFlags::RENDERING)
+            .unwrap_err();
+        assert_eq!(err.format.modifier, modifier);
+        assert!(err.to_string().contains(&format!("{:?}", modifier)));
+    }
+
+    #[test]
+    #[ignore = "requires a gpu"]
+    fn test_import() {
+        let node = std::fs::File::open("/dev/dri/renderD128").unwrap();
+        let device = GbmDevice::new(node).unwrap();
+
+        let buffer = device
+            .create_buffer_object::<()>(
+                64,
+                64,
+                Fourcc::Argb8888,
+                GbmBufferFlags::RENDERING | GbmBufferFlags::LINEAR,
+            )
+            .unwrap();
+        let dmabuf = buffer.export().unwrap();
+        assert!(dmabuf.test_import(&device, GbmBufferFlags::RENDERING));
+
+        // no desktop driver supports the tiling of the raspberry pi
+        let plane = PlaneInfo {
+            fd: nix::unistd::dup(dmabuf.planes().next().unwrap().fd()).unwrap(),
+            offset: 0,
+            stride: 256,
+            modifier: Modifier::Broadcom_vc4_t_tiled,
+        };
+        let tiled = Dmabuf::from_planes((64, 64), Fourcc::Argb8888, DmabufFlags::empty(), &[plane]).unwrap();
+        assert!(!tiled.test_import(&device, GbmBufferFlags::RENDERING));
+    }
+
+    #[test]
+    #[ignore = "requires a gpu"]
+    fn import_prime_fd() {
+        let node = std::fs::File::open("/dev/dri/renderD128").unwrap();
+        let device = GbmDevice::new(node).unwrap();
+
+        // the prime fd of a linear gbm buffer stands in for the one of another api
+        let buffer = device
+            .create_buffer_object::<()>(
+                64,
+                64,
+                Fourcc::Argb8888,
+                GbmBufferFlags::RENDERING | GbmBufferFlags::LINEAR,
+            )
+            .unwrap();
+        let fd = buffer
+            .export()
+            .unwrap()
+            .planes()
+            .next()
+            .unwrap()
+            .as_fd()
+            .try_clone_to_owned()
+            .unwrap();
+        let dmabuf = Dmabuf::from_prime_fd(
+            fd,
+            (64, 64),
+            Format {
+                code: Fourcc::Argb8888,
+                modifier: Modifier::Linear,
+            },
+            DmabufFlags::empty(),
+            &[buffer.stride().unwrap()],
+            &[0],
+        )
+        .unwrap();
+        assert!(dmabuf
+            .import_to::<_, ()>(&device, GbmBufferFlags::RENDERING)
+            .is_ok());
+    }
+
+    #[test]
+    fn import_path() {
+        let dmabuf = |modifier, offsets: &[u32]| {
+            let planes = offsets
+                .iter()
+                .map(|&offset| PlaneInfo {
+                    fd: std::fs::File::open("/dev/null").unwrap().into_raw_fd(),
+                    offset,
+                    stride: 256,
+                    modifier,
+                })
+                .collect::<Vec<_>>();
+            Dmabuf::from_planes((64, 64), Fourcc::Argb8888, DmabufFlags::empty(), &planes).unwrap()
+        };
+
+        assert!(!dmabuf(Modifier::Linear, &[0]).requires_modifier_import());
+        assert!(!dmabuf(Modifier::Invalid, &[0]).requires_modifier_import());
+        assert!(dmabuf(Modifier::Linear, &[256]).requires_modifier_import());
+        assert!(dmabuf(Modifier::Linear, &[0, 16384]).requires_modifier_import());
+        assert!(dmabuf(Modifier::I915_x_tiled, &[0]).requires_modifier_import());
+    }
+
+    #[test]
+    #[ignore = "requires a gpu"]
+    fn chosen_modifier_is_a_candidate() {
+        let node = std::fs::File::open("/dev/dri/renderD128").unwrap();
+        let mut device = GbmDevice::new(node).unwrap();
+
+        let modifiers = [Modifier::Linear, Modifier::Invalid];
+        let buffer: GbmBuffer<()> = device
+            .create_buffer(64, 64, Fourcc::Argb8888, &modifiers)
+            .unwrap();
+        assert!(modifiers.contains(&Buffer::format(&buffer).modifier));
     }
 }
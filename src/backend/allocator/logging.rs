@@ -0,0 +1,146 @@
+//! Module for logging the allocations of another allocator
+//!
+//! The [`LoggingAllocator`] wraps any [`Allocator`], logs every call to
+//! [`create_buffer`](Allocator::create_buffer) and keeps [`AllocationStats`] about them,
+//! which helps finding out which parts of a compositor churn through buffers.
+
+use slog::{debug, o, warn};
+
+use super::{format::FormatExt, Allocator, Buffer, Format, Fourcc, Modifier};
+
+/// Allocator logging all allocations of the wrapped allocator
+#[derive(Debug)]
+pub struct LoggingAllocator<A> {
+    allocator: A,
+    stats: AllocationStats,
+    log: ::slog::Logger,
+}
+
+/// Statistics about the allocations of a [`LoggingAllocator`]
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct AllocationStats {
+    /// Number of successful allocations
+    pub allocations: usize,
+    /// Number of failed allocations
+    pub failures: usize,
+    /// Total number of bytes allocated
+    ///
+    /// The size of every buffer is estimated as the size of a tightly packed buffer of its format,
+    /// see [`FormatExt::min_buffer_size`]. Allocators usually add some padding, and buffers of formats
+    /// with an unknown memory layout are not accounted for at all. Freed buffers are not subtracted.
+    pub bytes: u64,
+}
+
+impl<A> LoggingAllocator<A> {
+    /// Wrap `allocator`, logging all its allocations to `logger`
+    pub fn new<L>(allocator: A, logger: L) -> LoggingAllocator<A>
+    where
+        L: Into<Option<::slog::Logger>>,
+    {
+        LoggingAllocator {
+            allocator,
+            stats: AllocationStats::default(),
+            log: crate::slog_or_fallback(logger).new(o!("smithay_module" => "allocator_logging")),
+        }
+    }
+
+    /// Statistics about all allocations so far
+    pub fn stats(&self) -> AllocationStats {
+        self.stats
+    }
+
+    /// Reset the statistics
+    pub fn reset_stats(&mut self) {
+        self.stats = AllocationStats::default();
+    }
+
+    /// The wrapped allocator
+    pub fn allocator(&self) -> &A {
+        &self.allocator
+    }
+
+    /// Unwrap the allocator
+    pub fn into_inner(self) -> A {
+        self.allocator
+    }
+}
+
+impl<A, B> Allocator<B> for LoggingAllocator<A>
+where
+    A: Allocator<B>,
+    B: Buffer,
+{
+    type Error = A::Error;
+
+    fn create_buffer(
+        &mut self,
+        width: u32,
+        height: u32,
+        fourcc: Fourcc,
+        modifiers: &[Modifier],
+    ) -> Result<B, Self::Error> {
+        match self.allocator.create_buffer(width, height, fourcc, modifiers) {
+            Ok(buffer) => {
+                let format = buffer.format();
+                let bytes = format.min_buffer_size(width, height).unwrap_or(0) as u64;
+                self.stats.allocations += 1;
+                self.stats.bytes += bytes;
+                debug!(
+                    self.log,
+                    "Allocated a {}x{} buffer of format {:?}", width, height, format;
+                    "bytes" => bytes,
+                    "total_bytes" => self.stats.bytes,
+                );
+                Ok(buffer)
+            }
+            Err(err) => {
+                self.stats.failures += 1;
+                warn!(
+                    self.log,
+                    "Failed to allocate a {}x{} buffer of format {:?}: {}", width, height, fourcc, err;
+                    "modifiers" => ?modifiers,
+                );
+                Err(err)
+            }
+        }
+    }
+
+    fn reset(&mut self) -> Result<(), Self::Error> {
+        self.allocator.reset()
+    }
+
+    fn preferred_format(&self) -> Option<Format> {
+        self.allocator.preferred_format()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::allocator::memfd::{MemfdAllocator, MemfdBuffer};
+
+    #[test]
+    fn tally() {
+        let mut allocator = LoggingAllocator::new(MemfdAllocator::new(), None);
+        let _argb: MemfdBuffer = allocator
+            .create_buffer(64, 64, Fourcc::Argb8888, &[Modifier::Linear])
+            .unwrap();
+        let _nv12: MemfdBuffer = allocator
+            .create_buffer(64, 64, Fourcc::Nv12, &[Modifier::Linear])
+            .unwrap();
+        let tiled: Result<MemfdBuffer, _> =
+            allocator.create_buffer(64, 64, Fourcc::Argb8888, &[Modifier::I915_x_tiled]);
+        assert!(tiled.is_err());
+
+        assert_eq!(
+            allocator.stats(),
+            AllocationStats {
+                allocations: 2,
+                failures: 1,
+                bytes: 64 * 64 * 4 + 64 * 64 * 3 / 2,
+            }
+        );
+        allocator.reset_stats();
+        assert_eq!(allocator.stats(), AllocationStats::default());
+    }
+}
@@ -0,0 +1,261 @@
+//! Module for buffers backed by anonymous memory
+//!
+//! The [`MemfdAllocator`] allocates linear buffers using `memfd_create`, without requiring any gpu.
+//! The resulting [`MemfdBuffer`]s can be exported as [`Dmabuf`]s, with every plane referring to a
+//! duplicate of the file descriptor of the buffer. This makes them useful for testing code handling
+//! buffers and dmabufs headlessly, but they cannot be imported by any graphics api.
+
+use std::ffi::CStr;
+use std::fs::File;
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+
+use nix::fcntl::{fcntl, FcntlArg};
+use nix::sys::memfd::{memfd_create, MemFdCreateFlag};
+
+use super::{
+    dmabuf::{AsDmabuf, Dmabuf, DmabufFlags, MAX_PLANES},
+    format::FormatInfo,
+    Allocator, Buffer, Format, Fourcc, Modifier,
+};
+use crate::utils::{Buffer as BufferCoords, Size};
+
+/// Alignment of the stride and offset of every plane
+const ALIGNMENT: u32 = 64;
+
+/// Allocator creating linear buffers backed by anonymous memory
+#[derive(Debug, Default, Clone, Copy)]
+pub struct MemfdAllocator;
+
+impl MemfdAllocator {
+    /// Create a new allocator
+    pub fn new() -> MemfdAllocator {
+        MemfdAllocator
+    }
+}
+
+/// Buffer backed by anonymous memory, created by a [`MemfdAllocator`]
+#[derive(Debug)]
+pub struct MemfdBuffer {
+    file: File,
+    size: Size<i32, BufferCoords>,
+    format: Fourcc,
+    /// Offset and stride of every plane
+    planes: Vec<(u32, u32)>,
+}
+
+/// Errors of the [`MemfdAllocator`]
+#[derive(thiserror::Error, Debug)]
+pub enum MemfdAllocatorError {
+    /// The memory layout of the format is unknown
+    #[error("The format {0:?} is not supported")]
+    UnsupportedFormat(Fourcc),
+    /// Neither the linear nor the implicit modifier was requested
+    #[error("Only linear buffers are supported, requested modifiers: {0:?}")]
+    UnsupportedModifiers(Vec<Modifier>),
+    /// The buffer is too large
+    #[error("The buffer size {0}x{1} is too large")]
+    TooLarge(u32, u32),
+    /// Creating the memory of the buffer failed
+    #[error("Failed to allocate memory: {0}")]
+    Io(#[source] std::io::Error),
+}
+
+impl Allocator<MemfdBuffer> for MemfdAllocator {
+    type Error = MemfdAllocatorError;
+
+    fn create_buffer(
+        &mut self,
+        width: u32,
+        height: u32,
+        fourcc: Fourcc,
+        modifiers: &[Modifier],
+    ) -> Result<MemfdBuffer, Self::Error> {
+        if !modifiers.contains(&Modifier::Linear) && !modifiers.contains(&Modifier::Invalid) {
+            return Err(MemfdAllocatorError::UnsupportedModifiers(modifiers.to_vec()));
+        }
+        let info = FormatInfo::for_fourcc(fourcc).ok_or(MemfdAllocatorError::UnsupportedFormat(fourcc))?;
+        if info.plane_count() > MAX_PLANES || width > i32::MAX as u32 || height > i32::MAX as u32 {
+            return Err(MemfdAllocatorError::TooLarge(width, height));
+        }
+
+        let mut planes = Vec::with_capacity(info.plane_count());
+        let mut len = 0u64;
+        for plane in 0..info.plane_count() {
+            let plane_width = info.plane_width(plane, width).unwrap() as u64;
+            let stride = align(plane_width * info.bytes_per_pixel(plane).unwrap() as u64);
+            let plane_height = info.plane_height(plane, height).unwrap() as u64;
+            let offset = align(len);
+            len = offset + stride * plane_height;
+            if len > u32::MAX as u64 {
+                return Err(MemfdAllocatorError::TooLarge(width, height));
+            }
+            planes.push((offset as u32, stride as u32));
+        }
+
+        let name = CStr::from_bytes_with_nul(b"smithay-memfd-buffer\0").unwrap();
+        let fd = memfd_create(name, MemFdCreateFlag::MFD_CLOEXEC)
+            .map_err(|err| MemfdAllocatorError::Io(err.into()))?;
+        // Safety: the fd was just created and is not owned by anything else
+        let file = unsafe { File::from_raw_fd(fd) };
+        file.set_len(len).map_err(MemfdAllocatorError::Io)?;
+
+        Ok(MemfdBuffer {
+            file,
+            size: (width as i32, height as i32).into(),
+            format: fourcc,
+            planes,
+        })
+    }
+
+    fn preferred_format(&self) -> Option<Format> {
+        Some(Format {
+            code: Fourcc::Xrgb8888,
+            modifier: Modifier::Linear,
+        })
+    }
+}
+
+fn align(value: u64) -> u64 {
+    let alignment = ALIGNMENT as u64;
+    value + (alignment - value % alignment) % alignment
+}
+
+impl MemfdBuffer {
+    /// Number of planes of this buffer
+    pub fn num_planes(&self) -> usize {
+        self.planes.len()
+    }
+
+    /// Offset of `plane` from the start of the file descriptor
+    pub fn offset(&self, plane: usize) -> Option<u32> {
+        self.planes.get(plane).map(|(offset, _)| *offset)
+    }
+
+    /// Stride of `plane`
+    pub fn stride(&self, plane: usize) -> Option<u32> {
+        self.planes.get(plane).map(|(_, stride)| *stride)
+    }
+
+    /// The file containing the memory of all planes of this buffer
+    pub fn file(&self) -> &File {
+        &self.file
+    }
+}
+
+impl AsRawFd for MemfdBuffer {
+    fn as_raw_fd(&self) -> RawFd {
+        self.file.as_raw_fd()
+    }
+}
+
+impl Buffer for MemfdBuffer {
+    fn size(&self) -> Size<i32, BufferCoords> {
+        self.size
+    }
+
+    fn format(&self) -> Format {
+        Format {
+            code: self.format,
+            modifier: Modifier::Linear,
+        }
+    }
+}
+
+impl AsDmabuf for MemfdBuffer {
+    type Error = std::io::Error;
+
+    fn export(&self) -> Result<Dmabuf, std::io::Error> {
+        self.export_with(DmabufFlags::empty())
+    }
+
+    fn export_with(&self, flags: DmabufFlags) -> Result<Dmabuf, std::io::Error> {
+        let mut builder = Dmabuf::builder_from_buffer(self, flags);
+        for (idx, (offset, stride)) in self.planes.iter().enumerate() {
+            let fd =
+                fcntl(self.file.as_raw_fd(), FcntlArg::F_DUPFD_CLOEXEC(0)).map_err(std::io::Error::from)?;
+            builder.add_plane(fd, idx as u32, *offset, *stride, Modifier::Linear);
+        }
+        Ok(builder.build().unwrap())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn nv12_layout() {
+        let buffer = MemfdAllocator
+            .create_buffer(33, 10, Fourcc::Nv12, &[Modifier::Linear])
+            .unwrap();
+        assert_eq!(buffer.num_planes(), 2);
+        assert_eq!(buffer.stride(0), Some(64));
+        assert_eq!(buffer.offset(1), Some(640));
+        // 17 chroma samples of two bytes each for 5 rows
+        assert_eq!(buffer.stride(1), Some(64));
+        assert_eq!(buffer.file().metadata().unwrap().len(), 640 + 5 * 64);
+
+        let dmabuf = buffer.export().unwrap();
+        assert_eq!(dmabuf.num_planes(), 2);
+        assert_eq!(dmabuf.offsets().collect::<Vec<_>>(), vec![0, 640]);
+        assert_eq!(dmabuf.format(), buffer.format());
+        assert!(!dmabuf.is_disjoint());
+    }
+
+    #[test]
+    fn exported_fds_are_cloexec() {
+        use nix::fcntl::FdFlag;
+
+        let buffer = MemfdAllocator
+            .create_buffer(64, 64, Fourcc::Nv12, &[Modifier::Linear])
+            .unwrap();
+        let dmabuf = buffer.export().unwrap();
+        for fd in dmabuf.handles() {
+            let flags = FdFlag::from_bits_truncate(fcntl(fd, FcntlArg::F_GETFD).unwrap());
+            assert!(flags.contains(FdFlag::FD_CLOEXEC));
+        }
+    }
+
+    #[test]
+    fn export_y_inverted() {
+        let buffer = MemfdAllocator
+            .create_buffer(64, 64, Fourcc::Argb8888, &[Modifier::Linear])
+            .unwrap();
+        assert!(!buffer.export().unwrap().y_inverted());
+        let dmabuf = buffer.export_with(DmabufFlags::Y_INVERT).unwrap();
+        assert_eq!(dmabuf.flags(), DmabufFlags::Y_INVERT);
+    }
+
+    #[test]
+    fn preferred_format_can_be_allocated() {
+        let mut allocator = MemfdAllocator::new();
+        let format = Allocator::<MemfdBuffer>::preferred_format(&allocator).unwrap();
+        let buffer = allocator
+            .create_buffer(64, 64, format.code, &[format.modifier])
+            .unwrap();
+        assert_eq!(buffer.format(), format);
+    }
+
+    #[test]
+    fn chosen_modifier_is_a_candidate() {
+        for modifiers in &[
+            &[Modifier::Linear, Modifier::Invalid][..],
+            &[Modifier::I915_x_tiled, Modifier::Linear][..],
+        ] {
+            let buffer = MemfdAllocator
+                .create_buffer(64, 64, Fourcc::Argb8888, modifiers)
+                .unwrap();
+            assert!(modifiers.contains(&buffer.format().modifier));
+            assert_eq!(buffer.export().unwrap().format(), buffer.format());
+        }
+    }
+
+    #[test]
+    fn tiled_modifiers_are_rejected() {
+        let result = MemfdAllocator.create_buffer(64, 64, Fourcc::Argb8888, &[Modifier::I915_x_tiled]);
+        assert!(matches!(
+            result,
+            Err(MemfdAllocatorError::UnsupportedModifiers(_))
+        ));
+    }
+}
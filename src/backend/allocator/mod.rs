@@ -6,6 +6,7 @@
 //! Allocators provided:
 //! - Dumb Buffers through [`crate::backend::drm::DrmDevice`]
 //! - Gbm Buffers through [`::gbm::Device`]
+//! - Buffers backed by anonymous memory through [`MemfdAllocator`](memfd::MemfdAllocator)
 //!
 //! Buffer types supported:
 //! - [DumbBuffers](dumb::DumbBuffer)
@@ -14,15 +15,24 @@
 //!
 //! Helpers:
 //! - [`Swapchain`] to help with buffer management for framebuffers
+//! - [`Pool`] to re-use buffers of varying sizes and formats
+//! - [`FormatInfo`](format::FormatInfo) describing the memory layout of pixel formats
+//! - [`LoggingAllocator`](logging::LoggingAllocator) logging the allocations of another allocator
 
 pub mod dmabuf;
 #[cfg(feature = "backend_drm")]
 pub mod dumb;
+pub mod format;
 #[cfg(feature = "backend_gbm")]
 pub mod gbm;
+pub mod logging;
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub mod memfd;
 
+mod pool;
 mod swapchain;
 use crate::utils::{Buffer as BufferCoords, Size};
+pub use pool::{Pool, PooledBuffer};
 pub use swapchain::{Slot, Swapchain};
 
 pub use drm_fourcc::{
@@ -43,6 +53,9 @@ pub trait Buffer {
     /// Size of the two-dimensional buffer
     fn size(&self) -> Size<i32, BufferCoords>;
     /// Pixel format of the buffer
+    ///
+    /// For allocated buffers the modifier is the one actually chosen by the allocator,
+    /// which is one of the modifiers passed to [`Allocator::create_buffer`].
     fn format(&self) -> Format;
 }
 
@@ -52,6 +65,11 @@ pub trait Allocator<B: Buffer> {
     type Error: std::error::Error;
 
     /// Try to create a buffer with the given dimensions and pixel format
+    ///
+    /// `modifiers` is the list of all modifiers acceptable for the new buffer. Allocators should pass
+    /// all of them to the underlying api, if possible, and let the driver pick the optimal one,
+    /// instead of just trying them one after another. The modifier chosen for the buffer can be
+    /// queried from the returned buffer via [`Buffer::format`].
     fn create_buffer(
         &mut self,
         width: u32,
@@ -59,4 +77,78 @@ pub trait Allocator<B: Buffer> {
         fourcc: Fourcc,
         modifiers: &[Modifier],
     ) -> Result<B, Self::Error>;
+
+    /// Release all internal state of the allocator
+    ///
+    /// Called e.g. after the underlying device was lost or reset, to recover the allocator
+    /// for future allocations. Buffers allocated before are not affected and need to be
+    /// dropped by their owners. The default implementation does nothing.
+    fn reset(&mut self) -> Result<(), Self::Error> {
+        Ok(())
+    }
+
+    /// A sensible default format for buffers of this allocator
+    ///
+    /// This is usually a common scan-out format like [`Fourcc::Xrgb8888`], together with the modifier
+    /// the allocator is expected to handle best. Returns `None`, if the allocator has no preference,
+    /// in which case callers need to fall back to a default of their own. The default implementation
+    /// returns `None`.
+    fn preferred_format(&self) -> Option<Format> {
+        None
+    }
+}
+
+/// Group `formats` by their fourcc code
+///
+/// Every code is listed once, together with all of its modifiers, in the order they first
+/// appear in `formats`. Duplicated formats are ignored.
+pub fn group_by_fourcc(formats: &[Format]) -> Vec<(Fourcc, Vec<Modifier>)> {
+    let mut groups: Vec<(Fourcc, Vec<Modifier>)> = Vec::new();
+    for format in formats {
+        match groups.iter_mut().find(|(code, _)| *code == format.code) {
+            Some((_, modifiers)) => {
+                if !modifiers.contains(&format.modifier) {
+                    modifiers.push(format.modifier);
+                }
+            }
+            None => groups.push((format.code, vec![format.modifier])),
+        }
+    }
+    groups
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn grouped_formats() {
+        let format = |code, modifier| Format { code, modifier };
+        let formats = [
+            format(Fourcc::Argb8888, Modifier::Linear),
+            format(Fourcc::Xrgb8888, Modifier::Invalid),
+            format(Fourcc::Argb8888, Modifier::Invalid),
+            format(Fourcc::Argb8888, Modifier::Linear),
+        ];
+
+        let mut groups = group_by_fourcc(&formats);
+        assert_eq!(
+            groups,
+            vec![
+                (Fourcc::Argb8888, vec![Modifier::Linear, Modifier::Invalid]),
+                (Fourcc::Xrgb8888, vec![Modifier::Invalid]),
+            ]
+        );
+
+        for (_, modifiers) in &mut groups {
+            modifiers.retain(|modifier| *modifier != Modifier::Invalid);
+        }
+        assert_eq!(
+            groups,
+            vec![
+                (Fourcc::Argb8888, vec![Modifier::Linear]),
+                (Fourcc::Xrgb8888, vec![]),
+            ]
+        );
+    }
 }
@@ -0,0 +1,214 @@
+use std::collections::VecDeque;
+use std::ops::{Deref, DerefMut};
+use std::sync::{Arc, Mutex, Weak};
+
+use crate::backend::allocator::{Allocator, Buffer, Fourcc, Modifier};
+
+/// Pool caching released buffers for re-use
+///
+/// Allocating buffers can be expensive, which is noticeable for surfaces churning through
+/// buffers of different sizes every frame, like cursors or overlays. Unlike a [`Swapchain`](super::Swapchain),
+/// which manages a fixed set of buffers of a single size and format, a `Pool` hands out buffers of
+/// any size and format.
+///
+/// Buffers returned by [`acquire`](Pool::acquire) are put back into the pool once the
+/// returned [`PooledBuffer`] is dropped. A later call to `acquire` with matching dimensions
+/// and format re-uses them instead of allocating a new buffer.
+///
+/// The pool holds at most `capacity` released buffers. If more buffers are released,
+/// the least recently released ones are destroyed.
+#[derive(Debug)]
+pub struct Pool<A: Allocator<B>, B: Buffer> {
+    /// Allocator used by the pool
+    pub allocator: A,
+    cache: Arc<Mutex<Cache<B>>>,
+}
+
+#[derive(Debug)]
+struct Cache<B> {
+    capacity: usize,
+    buffers: VecDeque<B>,
+}
+
+/// Buffer acquired from a [`Pool`]
+///
+/// The buffer is returned to the pool once this struct is dropped.
+/// If the pool was already dropped, the buffer is destroyed instead.
+#[derive(Debug)]
+pub struct PooledBuffer<B: Buffer> {
+    buffer: Option<B>,
+    cache: Weak<Mutex<Cache<B>>>,
+}
+
+impl<B: Buffer> PooledBuffer<B> {
+    /// Take the buffer out of the pool, it will not be returned once dropped
+    pub fn detach(mut self) -> B {
+        self.buffer.take().unwrap()
+    }
+}
+
+impl<B: Buffer> Deref for PooledBuffer<B> {
+    type Target = B;
+    fn deref(&self) -> &B {
+        self.buffer.as_ref().unwrap()
+    }
+}
+
+impl<B: Buffer> DerefMut for PooledBuffer<B> {
+    fn deref_mut(&mut self) -> &mut B {
+        self.buffer.as_mut().unwrap()
+    }
+}
+
+impl<B: Buffer> Drop for PooledBuffer<B> {
+    fn drop(&mut self) {
+        if let (Some(buffer), Some(cache)) = (self.buffer.take(), self.cache.upgrade()) {
+            let mut cache = cache.lock().unwrap();
+            if cache.capacity == 0 {
+                return;
+            }
+            if cache.buffers.len() >= cache.capacity {
+                cache.buffers.pop_front();
+            }
+            cache.buffers.push_back(buffer);
+        }
+    }
+}
+
+impl<A, B> Pool<A, B>
+where
+    A: Allocator<B>,
+    B: Buffer,
+{
+    /// Create a new pool using `allocator`, holding up to `capacity` released buffers
+    pub fn new(allocator: A, capacity: usize) -> Pool<A, B> {
+        Pool {
+            allocator,
+            cache: Arc::new(Mutex::new(Cache {
+                capacity,
+                buffers: VecDeque::with_capacity(capacity),
+            })),
+        }
+    }
+
+    /// Acquire a buffer with the given dimensions and pixel format
+    ///
+    /// Re-uses a released buffer, if one matches the dimensions, the fourcc code
+    /// and one of the given `modifiers`. Otherwise a new buffer is allocated.
+    pub fn acquire(
+        &mut self,
+        width: u32,
+        height: u32,
+        fourcc: Fourcc,
+        modifiers: &[Modifier],
+    ) -> Result<PooledBuffer<B>, A::Error> {
+        let cached = {
+            let mut cache = self.cache.lock().unwrap();
+            // prefer the most recently released buffer
+            let idx = cache.buffers.iter().rposition(|buffer| {
+                let format = buffer.format();
+                buffer.width() == width
+                    && buffer.height() == height
+                    && format.code == fourcc
+                    && (modifiers.contains(&format.modifier) || modifiers.contains(&Modifier::Invalid))
+            });
+            idx.and_then(|idx| cache.buffers.remove(idx))
+        };
+
+        let buffer = match cached {
+            Some(buffer) => buffer,
+            None => self.allocator.create_buffer(width, height, fourcc, modifiers)?,
+        };
+        Ok(PooledBuffer {
+            buffer: Some(buffer),
+            cache: Arc::downgrade(&self.cache),
+        })
+    }
+
+    /// Change the maximum number of released buffers held by the pool
+    ///
+    /// If the pool currently holds more buffers, the least recently released ones are destroyed.
+    pub fn set_capacity(&mut self, capacity: usize) {
+        let mut cache = self.cache.lock().unwrap();
+        cache.capacity = capacity;
+        while cache.buffers.len() > capacity {
+            cache.buffers.pop_front();
+        }
+    }
+
+    /// Destroy all released buffers held by the pool
+    pub fn clear(&mut self) {
+        self.cache.lock().unwrap().buffers.clear();
+    }
+
+    /// Number of released buffers currently held by the pool
+    pub fn len(&self) -> usize {
+        self.cache.lock().unwrap().buffers.len()
+    }
+
+    /// Returns `true` if the pool holds no released buffers
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::allocator::Format;
+    use crate::utils::{Buffer as BufferCoords, Size};
+
+    #[derive(Debug)]
+    struct TestBuffer(u32, u32, Fourcc);
+
+    impl Buffer for TestBuffer {
+        fn size(&self) -> Size<i32, BufferCoords> {
+            (self.0 as i32, self.1 as i32).into()
+        }
+
+        fn format(&self) -> Format {
+            Format {
+                code: self.2,
+                modifier: Modifier::Linear,
+            }
+        }
+    }
+
+    #[derive(Debug, Default)]
+    struct TestAllocator(usize);
+
+    impl Allocator<TestBuffer> for TestAllocator {
+        type Error = std::io::Error;
+
+        fn create_buffer(
+            &mut self,
+            width: u32,
+            height: u32,
+            fourcc: Fourcc,
+            _modifiers: &[Modifier],
+        ) -> Result<TestBuffer, Self::Error> {
+            self.0 += 1;
+            Ok(TestBuffer(width, height, fourcc))
+        }
+    }
+
+    #[test]
+    fn released_buffers_are_reused() {
+        let mut pool = Pool::new(TestAllocator::default(), 2);
+        let modifiers = [Modifier::Linear];
+
+        let a = pool.acquire(64, 64, Fourcc::Argb8888, &modifiers).unwrap();
+        let b = pool.acquire(64, 64, Fourcc::Argb8888, &modifiers).unwrap();
+        let c = pool.acquire(32, 32, Fourcc::Argb8888, &modifiers).unwrap();
+        assert_eq!(pool.allocator.0, 3);
+        drop((a, b, c));
+        // the least recently released buffer was evicted
+        assert_eq!(pool.len(), 2);
+
+        let _a = pool.acquire(32, 32, Fourcc::Argb8888, &modifiers).unwrap();
+        let _b = pool.acquire(64, 64, Fourcc::Argb8888, &modifiers).unwrap();
+        assert_eq!(pool.allocator.0, 3);
+        let _c = pool.acquire(64, 64, Fourcc::Xrgb8888, &modifiers).unwrap();
+        assert_eq!(pool.allocator.0, 4);
+    }
+}
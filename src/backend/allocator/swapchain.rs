@@ -27,12 +27,15 @@ pub const SLOT_CAP: usize = 4;
 /// Re-using and managing these buffers becomes increasingly complex the more buffers you
 /// introduce, which is where `Swapchain` comes into play.
 ///
-/// `Swapchain` allocates buffers for you and transparently re-created them, e.g. when resizing.
+/// `Swapchain` allocates buffers for you and transparently re-created them, e.g. when resizing
+/// or changing the pixel format.
 /// All you tell the swapchain is: *"Give me the next free buffer"* (by calling [`acquire`](Swapchain::acquire)).
 /// You then hold on to the returned buffer during rendering and swapping and free it once it is displayed.
-/// Efficient re-use of the buffers is done by the swapchain.
+/// Efficient re-use of the buffers is done by the swapchain. The modifier chosen for the buffers out of the
+/// provided list can be queried via [`Buffer::format`] of the returned slots.
 ///
-/// If you have associated resources for each buffer that can be reused (e.g. framebuffer `Handle`s for a `DrmDevice`),
+/// If you have associated resources for each buffer that can be reused (e.g. framebuffer `Handle`s for a `DrmDevice`
+/// or the [`Dmabuf`](crate::backend::allocator::dmabuf::Dmabuf) exported from the buffer),
 /// you can store then in the `Slot`s userdata field. If a buffer is re-used, its userdata is preserved for the next time
 /// it is returned by `acquire()`.
 #[derive(Debug)]
@@ -156,4 +159,27 @@ where
         self.height = height;
         self.slots = Default::default();
     }
+
+    /// Change the pixel format and acceptable modifiers of newly returned buffers.
+    ///
+    /// Already obtained buffers are unaffected and will be cleaned up on drop.
+    pub fn set_format(&mut self, fourcc: Fourcc, modifiers: Vec<Modifier>) {
+        if self.fourcc == fourcc && self.modifiers == modifiers {
+            return;
+        }
+
+        self.fourcc = fourcc;
+        self.modifiers = modifiers;
+        self.slots = Default::default();
+    }
+
+    /// Dimensions of newly returned buffers
+    pub fn size(&self) -> (u32, u32) {
+        (self.width, self.height)
+    }
+
+    /// Pixel format of newly returned buffers
+    pub fn format(&self) -> Fourcc {
+        self.fourcc
+    }
 }
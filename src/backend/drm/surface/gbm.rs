@@ -2,13 +2,12 @@ use std::collections::HashSet;
 use std::os::unix::io::AsRawFd;
 use std::sync::Arc;
 
-use drm::buffer::PlanarBuffer;
 use drm::control::{connector, crtc, framebuffer, plane, Device, Mode};
 use gbm::{BufferObject, Device as GbmDevice};
 
 use crate::backend::allocator::{
     dmabuf::{AsDmabuf, Dmabuf},
-    gbm::GbmConvertError,
+    gbm::{GbmAllocatorError, GbmBufferExportExt as _, GbmConvertError},
     Format, Fourcc, Modifier, Slot, Swapchain,
 };
 use crate::backend::drm::{device::DevPath, surface::DrmSurfaceInternal, DrmError, DrmSurface};
@@ -380,33 +379,19 @@ fn attach_framebuffer<A>(drm: &Arc<DrmSurface<A>>, bo: &BufferObject<()>) -> Res
 where
     A: AsRawFd + 'static,
 {
-    let modifier = match bo.modifier().unwrap() {
-        Modifier::Invalid => None,
-        x => Some(x),
-    };
+    let info = bo.framebuffer_info()?;
 
     let logger = match &*(*drm).internal {
         DrmSurfaceInternal::Atomic(surf) => surf.logger.clone(),
         DrmSurfaceInternal::Legacy(surf) => surf.logger.clone(),
     };
 
-    let fb = match if modifier.is_some() {
-        let num = bo.plane_count().unwrap();
-        let modifiers = [
-            modifier,
-            if num > 1 { modifier } else { None },
-            if num > 2 { modifier } else { None },
-            if num > 3 { modifier } else { None },
-        ];
-        drm.add_planar_framebuffer(bo, &modifiers, drm_ffi::DRM_MODE_FB_MODIFIERS)
-    } else {
-        drm.add_planar_framebuffer(bo, &[None, None, None, None], 0)
-    } {
+    let fb = match drm.add_planar_framebuffer(&info, &info.modifiers(), info.flags()) {
         Ok(fb) => fb,
         Err(source) => {
             // We only support this as a fallback of last resort for ARGB8888 visuals,
             // like xf86-video-modesetting does.
-            if drm::buffer::Buffer::format(bo) != Fourcc::Argb8888 || bo.handles()[1].is_some() {
+            if info.format != Fourcc::Argb8888 || info.num_planes > 1 {
                 return Err(Error::DrmError(DrmError::Access {
                     errmsg: "Failed to add framebuffer",
                     dev: drm.dev_path(),
@@ -446,9 +431,9 @@ pub enum Error {
     /// Error accessing the drm device
     #[error("The underlying drm surface encounted an error: {0}")]
     DrmError(#[from] DrmError),
-    /// Error importing the rendered buffer to libgbm for scan-out
+    /// Error allocating a buffer for scan-out using libgbm
     #[error("The underlying gbm device encounted an error: {0}")]
-    GbmError(#[from] std::io::Error),
+    GbmError(#[from] GbmAllocatorError),
     /// Error exporting as Dmabuf
     #[error("The allocated buffer could not be exported as a dmabuf: {0}")]
     AsDmabufError(#[from] GbmConvertError),
@@ -471,19 +471,14 @@ impl EGLDisplay {
             ],
         ];
 
-        for (i, ((fd, offset), stride)) in dmabuf
-            .handles()
-            .zip(dmabuf.offsets())
-            .zip(dmabuf.strides())
-            .enumerate()
-        {
+        for (i, plane) in dmabuf.planes().enumerate() {
             out.extend(&[
                 names[i][0] as i32,
-                fd,
+                plane.fd(),
                 names[i][1] as i32,
-                offset as i32,
+                plane.offset() as i32,
                 names[i][2] as i32,
-                stride as i32,
+                plane.stride() as i32,
             ]);
             if dmabuf.has_modifier() {
                 out.extend(&[
@@ -550,6 +545,16 @@ impl EGLDisplay {
     }
 }
 
+impl Dmabuf {
+    /// Import a Dmabuf as an [`EGLImage`] of the given [`EGLDisplay`]
+    ///
+    /// Shorthand for [`EGLDisplay::create_image_from_dmabuf`]. The caller is responsible
+    /// for destroying the returned image.
+    pub fn import_to_egl(&self, display: &EGLDisplay) -> Result<EGLImage, Error> {
+        display.create_image_from_dmabuf(self)
+    }
+}
+
 fn get_dmabuf_formats(
     display: &ffi::egl::types::EGLDisplay,
     extensions: &[String],
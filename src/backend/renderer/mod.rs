@@ -382,10 +382,7 @@ pub trait ImportDma: Renderer {
         &mut self,
         buffer: &wl_buffer::WlBuffer,
     ) -> Result<<Self as Renderer>::TextureId, <Self as Renderer>::Error> {
-        let dmabuf = buffer
-            .as_ref()
-            .user_data()
-            .get::<Dmabuf>()
+        let dmabuf = crate::wayland::dmabuf::get_dmabuf(buffer)
             .expect("import_dma_buffer without checking buffer type?");
         self.import_dmabuf(dmabuf)
     }
@@ -500,7 +497,7 @@ pub enum BufferType {
 /// or otherwise not supported (e.g. not initialized using one of smithays [`crate::wayland`]-handlers).
 #[cfg(feature = "wayland_frontend")]
 pub fn buffer_type(buffer: &wl_buffer::WlBuffer) -> Option<BufferType> {
-    if buffer.as_ref().user_data().get::<Dmabuf>().is_some() {
+    if crate::wayland::dmabuf::get_dmabuf(buffer).is_some() {
         return Some(BufferType::Dma);
     }
 
@@ -530,7 +527,7 @@ pub fn buffer_type(buffer: &wl_buffer::WlBuffer) -> Option<BufferType> {
 pub fn buffer_dimensions(buffer: &wl_buffer::WlBuffer) -> Option<Size<i32, Physical>> {
     use crate::backend::allocator::Buffer;
 
-    if let Some(buf) = buffer.as_ref().user_data().get::<Dmabuf>() {
+    if let Some(buf) = crate::wayland::dmabuf::get_dmabuf(buffer) {
         return Some((buf.width() as i32, buf.height() as i32).into());
     }
 
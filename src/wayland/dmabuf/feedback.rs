@@ -0,0 +1,410 @@
+use std::{
+    cell::RefCell,
+    fs::File,
+    io::Write,
+    ops::Deref as _,
+    os::unix::io::AsRawFd,
+    sync::{Arc, Mutex},
+};
+
+use slog::warn;
+use tempfile::tempfile;
+use wayland_server::{protocol::wl_surface::WlSurface, Main};
+
+use super::protocol::zwp_linux_dmabuf_feedback_v1::{TrancheFlags, ZwpLinuxDmabufFeedbackV1};
+use crate::{backend::allocator::Format, wayland::compositor::with_states};
+
+/// Feedback about the preferred dmabuf parameters
+///
+/// Clients binding version 4 of the dmabuf global request this feedback instead of receiving
+/// the flat list of `format`/`modifier` events. It consists of a main device, that the compositor
+/// prefers to use for importing buffers, and a table of the supported formats.
+///
+/// The formats are grouped into tranches of descending preference, each targeting a specific device.
+/// The last tranche always targets the main device and contains all formats of the table.
+///
+/// `DmabufFeedback` is a cheap handle and can be freely cloned.
+#[derive(Debug, Clone)]
+pub struct DmabufFeedback(Arc<DmabufFeedbackInner>);
+
+#[derive(Debug)]
+struct DmabufFeedbackInner {
+    main_device: libc::dev_t,
+    formats: Vec<Format>,
+    tranches: Vec<DmabufFeedbackTranche>,
+    /// Read-only file containing the format table, shared by all clients
+    format_table: Mutex<Option<(File, u32)>>,
+}
+
+#[derive(Debug)]
+struct DmabufFeedbackTranche {
+    target_device: libc::dev_t,
+    flags: TrancheFlags,
+    indices: Vec<u16>,
+}
+
+impl DmabufFeedback {
+    /// Create a new feedback advertising all given `formats` for `main_device`
+    ///
+    /// The formats are sent as a single tranche targeting the main device.
+    /// Use a [`DmabufFeedbackBuilder`] to advertise additional tranches.
+    pub fn new(main_device: libc::dev_t, formats: Vec<Format>) -> DmabufFeedback {
+        DmabufFeedbackBuilder::new(main_device, formats)
+            .build()
+            .expect("A feedback without preference tranches is always valid")
+    }
+
+    /// The main device advertised by this feedback
+    pub fn main_device(&self) -> libc::dev_t {
+        self.0.main_device
+    }
+
+    /// The formats contained in the format table of this feedback
+    pub fn formats(&self) -> &[Format] {
+        &self.0.formats
+    }
+
+    /// Send this feedback to a `zwp_linux_dmabuf_feedback_v1` object
+    ///
+    /// Fails if the format table could not be shared with the client.
+    pub(super) fn send(&self, feedback: &ZwpLinuxDmabufFeedbackV1) -> std::io::Result<()> {
+        let mut format_table = self.0.format_table.lock().unwrap();
+        if format_table.is_none() {
+            *format_table = Some(self.create_format_table()?);
+        }
+        let (file, size) = format_table.as_ref().unwrap();
+        feedback.format_table(file.as_raw_fd(), *size);
+
+        feedback.main_device(self.0.main_device.to_ne_bytes().to_vec());
+        for tranche in &self.0.tranches {
+            feedback.tranche_target_device(tranche.target_device.to_ne_bytes().to_vec());
+            feedback.tranche_flags(tranche.flags);
+            let mut indices = Vec::with_capacity(tranche.indices.len() * 2);
+            for idx in &tranche.indices {
+                indices.extend_from_slice(&idx.to_ne_bytes());
+            }
+            feedback.tranche_formats(indices);
+            feedback.tranche_done();
+        }
+        feedback.done();
+
+        Ok(())
+    }
+
+    /// Write the format table into a file, that can be shared with all clients
+    ///
+    /// The table is only written once and the file is reopened read-only,
+    /// so that clients cannot modify the table received by other clients.
+    /// Fails instead of sharing the writable file, if it cannot be reopened.
+    fn create_format_table(&self) -> std::io::Result<(File, u32)> {
+        let table = self.format_table();
+        let mut file = tempfile()?;
+        file.write_all(&table)?;
+        file.flush()?;
+        let file = File::open(format!("/proc/self/fd/{}", file.as_raw_fd()))?;
+        Ok((file, table.len() as u32))
+    }
+
+    /// Serialize the format table as specified by the protocol
+    ///
+    /// Every entry is 16 bytes wide: the fourcc code as an u32, 4 bytes of padding
+    /// and the modifier as an u64, all in native endianness.
+    fn format_table(&self) -> Vec<u8> {
+        let mut table = Vec::with_capacity(self.0.formats.len() * 16);
+        for format in &self.0.formats {
+            table.extend_from_slice(&(format.code as u32).to_ne_bytes());
+            table.extend_from_slice(&[0; 4]);
+            table.extend_from_slice(&Into::<u64>::into(format.modifier).to_ne_bytes());
+        }
+        table
+    }
+}
+
+/// Builder for [`DmabufFeedback`]
+#[derive(Debug)]
+pub struct DmabufFeedbackBuilder {
+    main_device: libc::dev_t,
+    formats: Vec<Format>,
+    preferred_tranches: Vec<(libc::dev_t, TrancheFlags, Vec<Format>)>,
+}
+
+impl DmabufFeedbackBuilder {
+    /// Create a new builder for a feedback advertising `formats` for `main_device`
+    pub fn new(main_device: libc::dev_t, formats: Vec<Format>) -> DmabufFeedbackBuilder {
+        DmabufFeedbackBuilder {
+            main_device,
+            formats,
+            preferred_tranches: Vec::new(),
+        }
+    }
+
+    /// Add a tranche of formats preferred over the formats of the main device
+    ///
+    /// Tranches are advertised in the order they are added, all of them preceding the tranche
+    /// of the main device. All formats have to be part of the formats of the main device,
+    /// which make up the format table shared by all tranches.
+    pub fn add_preference_tranche(
+        mut self,
+        target_device: libc::dev_t,
+        flags: TrancheFlags,
+        formats: impl IntoIterator<Item = Format>,
+    ) -> DmabufFeedbackBuilder {
+        self.preferred_tranches
+            .push((target_device, flags, formats.into_iter().collect()));
+        self
+    }
+
+    /// Add multiple tranches of preferred formats, e.g. one per gpu
+    ///
+    /// The tranches are advertised in the given order, see
+    /// [`add_preference_tranche`](DmabufFeedbackBuilder::add_preference_tranche).
+    pub fn add_preference_tranches(
+        self,
+        tranches: impl IntoIterator<Item = (libc::dev_t, Vec<Format>, TrancheFlags)>,
+    ) -> DmabufFeedbackBuilder {
+        tranches
+            .into_iter()
+            .fold(self, |builder, (target_device, formats, flags)| {
+                builder.add_preference_tranche(target_device, flags, formats)
+            })
+    }
+
+    /// Build the [`DmabufFeedback`]
+    ///
+    /// Fails if the target device of a preference tranche is neither the main device
+    /// nor a drm device present on this system, or if a tranche contains a format
+    /// missing from the formats of the main device.
+    ///
+    /// *Note*: Tranches reference formats by a 16-bit index, any formats exceeding
+    /// that range are ignored.
+    pub fn build(self) -> Result<DmabufFeedback, DmabufFeedbackError> {
+        self.build_with_devices(is_drm_device)
+    }
+
+    /// Build the feedback, checking the target devices using `is_known_device`
+    pub(super) fn build_with_devices(
+        self,
+        is_known_device: impl Fn(libc::dev_t) -> bool,
+    ) -> Result<DmabufFeedback, DmabufFeedbackError> {
+        for (target_device, _, _) in &self.preferred_tranches {
+            if *target_device != self.main_device && !is_known_device(*target_device) {
+                return Err(DmabufFeedbackError::UnknownDevice(*target_device));
+            }
+        }
+
+        let mut formats = self.formats;
+        let mut tranches = Vec::with_capacity(self.preferred_tranches.len() + 1);
+        for (target_device, flags, tranche_formats) in self.preferred_tranches {
+            let indices = tranche_formats
+                .into_iter()
+                .map(|format| {
+                    formats
+                        .iter()
+                        .position(|f| *f == format)
+                        .ok_or(DmabufFeedbackError::UnknownFormat(format))
+                })
+                .collect::<Result<Vec<_>, _>>()?;
+            tranches.push((target_device, flags, indices));
+        }
+        // the main device supports all formats of the table
+        tranches.push((
+            self.main_device,
+            TrancheFlags::empty(),
+            (0..formats.len()).collect(),
+        ));
+
+        formats.truncate(u16::MAX as usize + 1);
+        let tranches = tranches
+            .into_iter()
+            .map(|(target_device, flags, indices)| DmabufFeedbackTranche {
+                target_device,
+                flags,
+                indices: indices
+                    .into_iter()
+                    .filter(|&idx| idx < formats.len())
+                    .map(|idx| idx as u16)
+                    .collect(),
+            })
+            .collect();
+
+        Ok(DmabufFeedback(Arc::new(DmabufFeedbackInner {
+            main_device: self.main_device,
+            formats,
+            tranches,
+            format_table: Mutex::new(None),
+        })))
+    }
+}
+
+/// Errors building a [`DmabufFeedback`]
+#[derive(Debug, thiserror::Error)]
+pub enum DmabufFeedbackError {
+    /// The target device of a tranche is not a known drm device
+    #[error("The target device {0} of a tranche is not a known drm device")]
+    UnknownDevice(libc::dev_t),
+    /// A tranche contains a format, which is not supported by the main device
+    #[error("The format {0:?} of a tranche is not supported by the main device")]
+    UnknownFormat(Format),
+}
+
+/// Check if `device` is the number of a drm device present on this system
+fn is_drm_device(device: libc::dev_t) -> bool {
+    let path = format!(
+        "/sys/dev/char/{}:{}/subsystem",
+        nix::sys::stat::major(device),
+        nix::sys::stat::minor(device)
+    );
+    matches!(
+        std::fs::read_link(path),
+        Ok(subsystem) if subsystem.file_name() == Some("drm".as_ref())
+    )
+}
+
+/// Dmabuf feedback state of a surface, stored in its data map
+#[derive(Debug, Default)]
+struct SurfaceFeedbackState {
+    /// Feedback replacing the default feedback for this surface
+    feedback: Option<DmabufFeedback>,
+    /// Feedback objects created for this surface
+    instances: Vec<ZwpLinuxDmabufFeedbackV1>,
+}
+
+/// Track a feedback object created for `surface` and send it the current feedback of the surface
+///
+/// If the surface is already dead, the feedback object is inert. The feedback object has
+/// to be pruned by [`prune_surface_feedback`] once it is destroyed.
+pub(super) fn init_surface_feedback(
+    feedback: Main<ZwpLinuxDmabufFeedbackV1>,
+    surface: WlSurface,
+    default_feedback: &DmabufFeedback,
+) -> std::io::Result<()> {
+    let current = with_states(&surface, |states| {
+        states
+            .data_map
+            .insert_if_missing(|| RefCell::new(SurfaceFeedbackState::default()));
+        let mut state = states
+            .data_map
+            .get::<RefCell<SurfaceFeedbackState>>()
+            .unwrap()
+            .borrow_mut();
+        state.instances.push(feedback.deref().clone());
+        state.feedback.clone()
+    });
+    let current = match current {
+        Ok(current) => current,
+        Err(_) => return Ok(()),
+    };
+
+    current.as_ref().unwrap_or(default_feedback).send(&feedback)
+}
+
+/// Stop tracking the destroyed feedback objects created for `surface`
+pub(super) fn prune_surface_feedback(surface: &WlSurface) {
+    let _ = with_states(surface, |states| {
+        if let Some(state) = states.data_map.get::<RefCell<SurfaceFeedbackState>>() {
+            state.borrow_mut().instances.retain(|f| f.as_ref().is_alive());
+        }
+    });
+}
+
+/// Check if `surface` uses a feedback other than the default feedback
+pub(super) fn has_surface_feedback(surface: &WlSurface) -> bool {
+    with_states(surface, |states| {
+        matches!(
+            states.data_map.get::<RefCell<SurfaceFeedbackState>>(),
+            Some(state) if state.borrow().feedback.is_some()
+        )
+    })
+    .unwrap_or(false)
+}
+
+/// Replace the feedback of `surface` and resend it to all its feedback objects
+///
+/// Passing `None` resets the surface to use `default_feedback`.
+pub(super) fn set_surface_feedback(
+    surface: &WlSurface,
+    feedback: Option<DmabufFeedback>,
+    default_feedback: &DmabufFeedback,
+    log: &::slog::Logger,
+) {
+    let _ = with_states(surface, |states| {
+        states
+            .data_map
+            .insert_if_missing(|| RefCell::new(SurfaceFeedbackState::default()));
+        let mut state = states
+            .data_map
+            .get::<RefCell<SurfaceFeedbackState>>()
+            .unwrap()
+            .borrow_mut();
+        state.feedback = feedback;
+
+        let current = state.feedback.as_ref().unwrap_or(default_feedback);
+        for instance in &state.instances {
+            if let Err(err) = current.send(instance) {
+                warn!(log,
+                    "Failed to send dmabuf feedback to client";
+                    "err" => ?err
+                );
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::allocator::{Fourcc, Modifier};
+    use std::io::Read;
+
+    #[test]
+    fn tranches_require_known_devices() {
+        let formats = vec![Format {
+            code: Fourcc::Argb8888,
+            modifier: Modifier::Linear,
+        }];
+
+        let feedback = DmabufFeedbackBuilder::new(0, formats.clone())
+            .add_preference_tranche(0, TrancheFlags::Scanout, formats.clone())
+            .build();
+        assert!(feedback.is_ok());
+
+        // /dev/null is not a drm device
+        let null = nix::sys::stat::makedev(1, 3);
+        let feedback = DmabufFeedbackBuilder::new(0, formats.clone())
+            .add_preference_tranche(null, TrancheFlags::Scanout, formats)
+            .build();
+        assert!(matches!(feedback, Err(DmabufFeedbackError::UnknownDevice(dev)) if dev == null));
+    }
+
+    #[test]
+    fn tranches_require_known_formats() {
+        let format = |modifier| Format {
+            code: Fourcc::Argb8888,
+            modifier,
+        };
+
+        let feedback = DmabufFeedbackBuilder::new(0, vec![format(Modifier::Linear)])
+            .add_preference_tranche(0, TrancheFlags::Scanout, vec![format(Modifier::I915_x_tiled)])
+            .build();
+        assert!(matches!(
+            feedback,
+            Err(DmabufFeedbackError::UnknownFormat(f)) if f == format(Modifier::I915_x_tiled)
+        ));
+    }
+
+    #[test]
+    fn format_table_is_read_only() {
+        let formats = vec![Format {
+            code: Fourcc::Argb8888,
+            modifier: Modifier::Linear,
+        }];
+        let feedback = DmabufFeedback::new(0, formats);
+
+        let (mut file, size) = feedback.create_format_table().unwrap();
+        assert_eq!(size, 16);
+        let mut table = Vec::new();
+        file.read_to_end(&mut table).unwrap();
+        assert_eq!(table, feedback.format_table());
+        assert!(file.write_all(&[0; 16]).is_err());
+    }
+}
@@ -29,84 +29,1150 @@
 //! let formats = vec![
 //!     /* ... */
 //! ];
-//! let dmabuf_global = init_dmabuf_global(
+//! let (dmabuf_state, dmabuf_global) = init_dmabuf_global(
 //!     &mut display,
 //!     formats,
 //!     |buffer, dispatch_data| {
 //!         /* validate the dmabuf and import it into your renderer state */
-//!         true
+//!         Ok(())
 //!     },
 //!     None // we don't provide a logger in this example
 //! );
 //! ```
+//!
+//! The returned [`DmabufState`] can be used to update the list of supported formats at runtime
+//! via [`DmabufState::set_formats`], e.g. when a new GPU was added.
+//!
+//! Further options, like limiting the number of planes per buffer, can be configured by creating
+//! the global through a [`DmabufGlobalBuilder`] instead. It also allows the handler to access your
+//! compositor state through the dispatch data via [`DmabufGlobalBuilder::build_with_state`].
+//!
+//! ### Dmabuf feedback
+//!
+//! Version 4 of the protocol replaces the list of `format`/`modifier` events by dmabuf feedback,
+//! which additionally informs clients about the device the compositor prefers to use for importing
+//! their buffers. To advertise it, create a [`DmabufFeedback`] from the `dev_t` of your main device
+//! and your supported formats and use [`init_dmabuf_global_with_feedback`] instead. Clients only
+//! supporting older versions of the protocol still receive the formats of the feedback.
+//!
+//! The returned [`DmabufState`] can then be used to advertise a different feedback for specific
+//! surfaces, for example a tranche of scan-out capable formats with [`TrancheFlags::Scanout`]
+//! built using a [`DmabufFeedbackBuilder`], when a surface could be displayed on a hardware plane.
+//!
+//! ```
+//! # extern crate wayland_server;
+//! # extern crate smithay;
+//! use smithay::wayland::dmabuf::{init_dmabuf_global_with_feedback, DmabufFeedback};
+//!
+//! # let mut display = wayland_server::Display::new();
+//! # let main_device = 0;
+//! # let formats = vec![];
+//! let default_feedback = DmabufFeedback::new(main_device, formats);
+//! let (dmabuf_state, dmabuf_global) = init_dmabuf_global_with_feedback(
+//!     &mut display,
+//!     &default_feedback,
+//!     |buffer, dispatch_data| {
+//!         /* validate the dmabuf and import it into your renderer state */
+//!         Ok(())
+//!     },
+//!     None // we don't provide a logger in this example
+//! );
+//! ```
+
+mod feedback;
+pub mod protocol;
+#[cfg(any(test, feature = "test_util"))]
+mod test_util;
+
+pub use self::feedback::{DmabufFeedback, DmabufFeedbackBuilder, DmabufFeedbackError};
+pub use self::protocol::zwp_linux_dmabuf_feedback_v1::TrancheFlags;
+#[cfg(all(any(test, feature = "test_util"), feature = "backend_gbm"))]
+pub use self::test_util::round_trip;
+#[cfg(any(test, feature = "test_util"))]
+pub use self::test_util::{submit_dmabuf, RoundTripError, TestDmabufHandler};
 
 use std::{
+    any::Any,
     cell::RefCell,
     convert::TryFrom,
-    os::unix::io::{IntoRawFd, RawFd},
+    fmt,
+    ops::Deref as _,
+    os::unix::io::{IntoRawFd, OwnedFd, RawFd},
     rc::Rc,
+    sync::{Arc, Mutex},
 };
 
-use wayland_protocols::unstable::linux_dmabuf::v1::server::{
+use self::protocol::{
     zwp_linux_buffer_params_v1::{
         Error as ParamError, Flags as BufferFlags, Request as ParamsRequest,
         ZwpLinuxBufferParamsV1 as BufferParams,
     },
+    zwp_linux_dmabuf_feedback_v1::ZwpLinuxDmabufFeedbackV1,
     zwp_linux_dmabuf_v1,
 };
-use wayland_server::{protocol::wl_buffer, DispatchData, Display, Filter, Global, Main};
+use wayland_server::{
+    protocol::{wl_buffer, wl_surface::WlSurface},
+    Client, DispatchData, Display, Filter, Global, Main, UserDataMap,
+};
 
-use slog::{o, trace};
+use slog::{debug, error, o, trace, warn};
 
+#[cfg(feature = "backend_gbm")]
+use crate::backend::allocator::gbm::{GbmDevice, GbmDeviceExt as _};
 use crate::backend::allocator::{
-    dmabuf::{Dmabuf, DmabufFlags, Plane},
-    Format, Fourcc, Modifier,
+    dmabuf::{Dmabuf, DmabufFlags, Plane, MAX_PLANES},
+    format::{FormatExt as _, FormatInfo, FourccExt as _},
+    group_by_fourcc, Buffer as _, Format, Fourcc, Modifier,
 };
+use crate::utils::{Buffer as BufferCoords, Rectangle};
+
+/// Retrieve the [`Dmabuf`] of a `wl_buffer` created by a dmabuf global
+///
+/// Returns `None` if the buffer was not created through one of smithays dmabuf globals.
+/// The flags submitted by the client are available through [`Dmabuf::flags`].
+///
+/// The dmabuf is stored in the user data of the `wl_buffer`, which can only hold a single value.
+/// Use [`buffer_user_data`] to attach your own data to dmabuf-backed buffers instead.
+pub fn get_dmabuf(buffer: &wl_buffer::WlBuffer) -> Option<&Dmabuf> {
+    buffer
+        .as_ref()
+        .user_data()
+        .get::<DmabufBuffer>()
+        .map(|buffer| &buffer.dmabuf)
+}
+
+/// Retrieve the data attached to a `wl_buffer` by the handler of a dmabuf global
+///
+/// Returns `None` if the buffer was not created through one of smithays dmabuf globals, or
+/// if no data of type `T` was attached to it, see [`DmabufGlobalBuilder::build_with_data`].
+pub fn get_buffer_data<T: Send + Sync + 'static>(buffer: &wl_buffer::WlBuffer) -> Option<Arc<T>> {
+    let data = buffer
+        .as_ref()
+        .user_data()
+        .get::<DmabufBuffer>()?
+        .data
+        .lock()
+        .unwrap()
+        .clone()?;
+    data.downcast::<T>().ok()
+}
+
+/// Retrieve the user data map of a `wl_buffer` created by a dmabuf global
+///
+/// The user data of the `wl_buffer` itself is occupied by the dmabuf global, this map allows
+/// to store any number of values of distinct types alongside the [`Dmabuf`], e.g. per-buffer
+/// state of the compositor. Unlike the data returned by the handler, see [`get_buffer_data`],
+/// values can be inserted at any time after the buffer was created.
+///
+/// Returns `None` if the buffer was not created through one of smithays dmabuf globals.
+pub fn buffer_user_data(buffer: &wl_buffer::WlBuffer) -> Option<&UserDataMap> {
+    buffer
+        .as_ref()
+        .user_data()
+        .get::<DmabufBuffer>()
+        .map(|buffer| &buffer.user_data)
+}
+
+/// Version of `zwp_linux_dmabuf_v1` bound by the client, that created a `wl_buffer`
+///
+/// Clients binding versions prior to 3 do not receive any modifiers and can only use the
+/// implicit modifier, while clients binding version 4 learn about the supported formats and modifiers
+/// through dmabuf feedback. This allows treating buffers of such legacy clients differently.
+///
+/// Returns `None` if the buffer was not created through one of smithays dmabuf globals.
+pub fn dmabuf_version(buffer: &wl_buffer::WlBuffer) -> Option<u32> {
+    buffer
+        .as_ref()
+        .user_data()
+        .get::<DmabufBuffer>()
+        .map(|buffer| buffer.version)
+}
+
+/// Set the acquire fence of a `wl_buffer` created by a dmabuf global
+///
+/// The acquire fence signals once the client finished writing to the buffer, so a renderer has to wait
+/// for it before sampling from the buffer. The dmabuf global does not implement any explicit
+/// synchronization protocol itself, the fences are merely stored alongside the buffer, so the
+/// protocol implementation and the renderer can hand them over to each other.
+///
+/// A previously set acquire fence, that was not taken yet, is closed.
+/// Returns `false` and closes `fence`, if the buffer was not created through one of smithays dmabuf globals.
+pub fn set_acquire_fence(buffer: &wl_buffer::WlBuffer, fence: OwnedFd) -> bool {
+    with_fences(buffer, |fences| fences.acquire = Some(fence)).is_some()
+}
+
+/// Take the acquire fence of a `wl_buffer` created by a dmabuf global, see [`set_acquire_fence`]
+pub fn take_acquire_fence(buffer: &wl_buffer::WlBuffer) -> Option<OwnedFd> {
+    with_fences(buffer, |fences| fences.acquire.take()).flatten()
+}
+
+/// Set the release fence of a `wl_buffer` created by a dmabuf global
+///
+/// The release fence signals once the compositor finished reading from the buffer, e.g. set by the
+/// renderer after sampling from the buffer, to be passed on to the client. See [`set_acquire_fence`].
+///
+/// A previously set release fence, that was not taken yet, is closed.
+/// Returns `false` and closes `fence`, if the buffer was not created through one of smithays dmabuf globals.
+pub fn set_release_fence(buffer: &wl_buffer::WlBuffer, fence: OwnedFd) -> bool {
+    with_fences(buffer, |fences| fences.release = Some(fence)).is_some()
+}
+
+/// Take the release fence of a `wl_buffer` created by a dmabuf global, see [`set_release_fence`]
+pub fn take_release_fence(buffer: &wl_buffer::WlBuffer) -> Option<OwnedFd> {
+    with_fences(buffer, |fences| fences.release.take()).flatten()
+}
+
+/// Point on a drm syncobj timeline
+///
+/// Used by the `wp_linux_drm_syncobj_v1` protocol for explicit synchronization. Instead of a fence
+/// per buffer, clients create timelines out of drm syncobj file descriptors once and attach a point
+/// on them to every commit, which is signalled once the buffer is ready, or may be reused respectively.
+///
+/// `SyncPoint` is a cheap handle and can be freely cloned, all clones share the file descriptor
+/// of the timeline.
+#[derive(Debug, Clone)]
+pub struct SyncPoint {
+    timeline: Arc<OwnedFd>,
+    point: u64,
+}
+
+impl SyncPoint {
+    /// Create a point on the given `timeline`
+    pub fn new(timeline: Arc<OwnedFd>, point: u64) -> SyncPoint {
+        SyncPoint { timeline, point }
+    }
+
+    /// The drm syncobj file descriptor of the timeline
+    pub fn timeline(&self) -> &Arc<OwnedFd> {
+        &self.timeline
+    }
+
+    /// The point on the timeline
+    pub fn point(&self) -> u64 {
+        self.point
+    }
+}
+
+/// Set the acquire point of a `wl_buffer` created by a dmabuf global
+///
+/// Like the acquire fence set by [`set_acquire_fence`], the renderer has to wait for the point
+/// to be signalled before sampling from the buffer. The timeline points are merely stored alongside
+/// the buffer, so the `wp_linux_drm_syncobj_v1` implementation and the renderer can hand them over.
+///
+/// A previously set acquire point, that was not taken yet, is replaced.
+/// Returns `false`, if the buffer was not created through one of smithays dmabuf globals.
+pub fn set_acquire_point(buffer: &wl_buffer::WlBuffer, point: SyncPoint) -> bool {
+    with_fences(buffer, |fences| fences.acquire_point = Some(point)).is_some()
+}
+
+/// Take the acquire point of a `wl_buffer` created by a dmabuf global, see [`set_acquire_point`]
+pub fn take_acquire_point(buffer: &wl_buffer::WlBuffer) -> Option<SyncPoint> {
+    with_fences(buffer, |fences| fences.acquire_point.take()).flatten()
+}
+
+/// Set the release point of a `wl_buffer` created by a dmabuf global
+///
+/// The compositor signals the release point once it finished reading from the buffer,
+/// see [`set_acquire_point`].
+///
+/// A previously set release point, that was not taken yet, is replaced.
+/// Returns `false`, if the buffer was not created through one of smithays dmabuf globals.
+pub fn set_release_point(buffer: &wl_buffer::WlBuffer, point: SyncPoint) -> bool {
+    with_fences(buffer, |fences| fences.release_point = Some(point)).is_some()
+}
+
+/// Take the release point of a `wl_buffer` created by a dmabuf global, see [`set_release_point`]
+pub fn take_release_point(buffer: &wl_buffer::WlBuffer) -> Option<SyncPoint> {
+    with_fences(buffer, |fences| fences.release_point.take()).flatten()
+}
+
+/// Add damage to a `wl_buffer` created by a dmabuf global
+///
+/// The damage describes the regions of the buffer, that changed since its contents were last
+/// consumed, e.g. set by the surface commit logic of the compositor, so the renderer only needs
+/// to upload or redraw these regions. Damage set before the previous damage was taken is merged with it.
+///
+/// Returns `false`, if the buffer was not created through one of smithays dmabuf globals.
+pub fn set_damage(buffer: &wl_buffer::WlBuffer, damage: &[Rectangle<i32, BufferCoords>]) -> bool {
+    match buffer.as_ref().user_data().get::<DmabufBuffer>() {
+        Some(buffer) => {
+            buffer
+                .damage
+                .lock()
+                .unwrap()
+                .get_or_insert_with(Vec::new)
+                .extend_from_slice(damage);
+            true
+        }
+        None => false,
+    }
+}
+
+/// Take the damage of a `wl_buffer` created by a dmabuf global, see [`set_damage`]
+///
+/// Returns `None`, if no damage was set since the damage was last taken, in which case the
+/// whole buffer has to be considered damaged.
+pub fn take_damage(buffer: &wl_buffer::WlBuffer) -> Option<Vec<Rectangle<i32, BufferCoords>>> {
+    buffer
+        .as_ref()
+        .user_data()
+        .get::<DmabufBuffer>()?
+        .damage
+        .lock()
+        .unwrap()
+        .take()
+}
 
-/// Handler trait for dmabuf validation
+fn with_fences<T>(buffer: &wl_buffer::WlBuffer, f: impl FnOnce(&mut Fences) -> T) -> Option<T> {
+    let buffer = buffer.as_ref().user_data().get::<DmabufBuffer>()?;
+    let mut fences = buffer.fences.lock().unwrap();
+    Some(f(&mut fences))
+}
+
+/// Data attached to the buffer returned by the handler
+type BufferData = Arc<dyn Any + Send + Sync>;
+
+/// User data of a dmabuf-backed `wl_buffer`
+#[derive(Debug)]
+struct DmabufBuffer {
+    dmabuf: Dmabuf,
+    data: Mutex<Option<BufferData>>,
+    /// Version of the dmabuf global bound by the client
+    version: u32,
+    fences: Mutex<Fences>,
+    /// Damage not yet taken by the renderer
+    damage: Mutex<Option<Vec<Rectangle<i32, BufferCoords>>>>,
+    /// Additional data attached by the compositor
+    user_data: UserDataMap,
+}
+
+/// Explicit synchronization fences and timeline points of a dmabuf-backed `wl_buffer`
+#[derive(Debug, Default)]
+struct Fences {
+    acquire: Option<OwnedFd>,
+    release: Option<OwnedFd>,
+    acquire_point: Option<SyncPoint>,
+    release_point: Option<SyncPoint>,
+}
+
+/// Reason a dmabuf could not be imported
 ///
-/// You need to provide an implementation of this trait
+/// Returned by the handler provided to [`init_dmabuf_global`], when a dmabuf submitted by a client
+/// fails validation. Buffers created via `create` are simply reported as failed to the client,
+/// while a failed `create_immed` raises a protocol error depending on the variant.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, thiserror::Error)]
+pub enum ImportError {
+    /// The format of the dmabuf is not supported
+    #[error("The format of the dmabuf is not supported")]
+    UnsupportedFormat,
+    /// The modifier of the dmabuf is not supported for its format
+    #[error("The modifier of the dmabuf is not supported")]
+    UnsupportedModifier,
+    /// There was not enough memory to import the dmabuf
+    #[error("Not enough memory to import the dmabuf")]
+    OutOfMemory,
+    /// The import failed for another reason
+    #[error("The dmabuf could not be imported")]
+    Failed,
+}
+
+/// Outcome of a buffer creation, as reported to the [`metrics`](DmabufGlobalBuilder::metrics) callback
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ImportOutcome {
+    /// The buffer was created
+    Created,
+    /// The buffer was rejected by the plane validator or the handler
+    Rejected(ImportError),
+    /// The parameters violated the protocol and the client was sent a protocol error
+    ProtocolError,
+}
 
 /// Initialize a dmabuf global.
 ///
 /// You need to provide a vector of the supported formats, as well as a closure,
 /// that will validate the parameters provided by the client and tests the import as a dmabuf.
+/// If the import fails, the closure returns the reason as an [`ImportError`].
+///
+/// The closure is given the [`Dmabuf`] assembled from the parameters of the client, there is
+/// no need to retrieve it from the `wl_buffer`. In fact the `wl_buffer` is only created after the
+/// validation succeeded, in case the client used the `create` request.
+///
+/// This global advertises version 3 of the protocol, see [`init_dmabuf_global_with_feedback`]
+/// for a global supporting dmabuf feedback.
+///
+/// This function returns the [`DmabufState`] allowing you to update the supported formats,
+/// as well as the global handle, in case you wish to remove this global in the future.
+///
+/// Destroying the global stops advertising it, clients cannot bind it anymore afterwards.
+/// Clients, that already bound the global, are not affected and can continue to create buffers.
+/// The `handler` is kept alive until all of them destroyed their objects.
 pub fn init_dmabuf_global<F, L>(
     display: &mut Display,
     formats: Vec<Format>,
     handler: F,
     logger: L,
-) -> Global<zwp_linux_dmabuf_v1::ZwpLinuxDmabufV1>
+) -> (DmabufState, Global<zwp_linux_dmabuf_v1::ZwpLinuxDmabufV1>)
+where
+    L: Into<Option<::slog::Logger>>,
+    F: for<'a> FnMut(&Dmabuf, DispatchData<'a>) -> Result<(), ImportError> + 'static,
+{
+    DmabufGlobalBuilder::new()
+        .formats(formats)
+        .logger(logger)
+        .build(display, handler)
+}
+
+/// Initialize a dmabuf global, which verifies the file descriptors submitted by clients.
+///
+/// By default the file descriptors of each plane are only checked to be large enough for the
+/// buffer. This global additionally verifies, that each file descriptor actually refers to
+/// a dma-buf, before the [`Dmabuf`] is passed to the `handler`. Clients submitting other
+/// file descriptors (e.g. a `memfd`) are killed with an `out_of_bounds` protocol error.
+///
+/// Otherwise this behaves like [`init_dmabuf_global`].
+pub fn init_dmabuf_global_strict<F, L>(
+    display: &mut Display,
+    formats: Vec<Format>,
+    handler: F,
+    logger: L,
+) -> (DmabufState, Global<zwp_linux_dmabuf_v1::ZwpLinuxDmabufV1>)
+where
+    L: Into<Option<::slog::Logger>>,
+    F: for<'a> FnMut(&Dmabuf, DispatchData<'a>) -> Result<(), ImportError> + 'static,
+{
+    DmabufGlobalBuilder::new()
+        .formats(formats)
+        .strict_fd_check(true)
+        .logger(logger)
+        .build(display, handler)
+}
+
+/// Builder for a dmabuf global
+///
+/// Allows to configure the global in more detail than the `init_dmabuf_global*` functions.
+///
+/// ```
+/// # extern crate wayland_server;
+/// # extern crate smithay;
+/// use smithay::wayland::dmabuf::DmabufGlobalBuilder;
+///
+/// # let mut display = wayland_server::Display::new();
+/// # let formats = vec![];
+/// let (dmabuf_state, dmabuf_global) = DmabufGlobalBuilder::new()
+///     .formats(formats)
+///     .max_planes(3)
+///     .strict_fd_check(true)
+///     .build(&mut display, |buffer, dispatch_data| {
+///         /* validate the dmabuf and import it into your renderer state */
+///         Ok(())
+///     });
+/// ```
+#[derive(Debug)]
+pub struct DmabufGlobalBuilder {
+    formats: Vec<Format>,
+    default_feedback: Option<DmabufFeedback>,
+    config: GlobalConfig,
+    logger: Option<::slog::Logger>,
+}
+
+/// Handling of buffers submitted with [`Modifier::Invalid`]
+///
+/// Clients submitting [`Modifier::Invalid`] request the implicit modifier, i.e. the buffer layout
+/// is negotiated between the drivers of the client and the compositor out of band. This is what
+/// clients without modifier support do, e.g. clients using older versions of Mesa or drivers
+/// that do not support modifiers, while current Mesa versions submit the explicit modifier of
+/// the buffer, if the compositor advertised any.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ImplicitModifierPolicy {
+    /// Accept the implicit modifier for all advertised formats and let the driver figure out the layout
+    Allow,
+    /// Reject buffers using the implicit modifier, if it was not advertised for their format
+    RejectUnadvertised,
+}
+
+/// Options of a dmabuf global shared by all its params objects
+struct GlobalConfig {
+    max_planes: u32,
+    strict_fd_check: bool,
+    check_plane_sizes: bool,
+    max_width: Option<u32>,
+    max_height: Option<u32>,
+    implicit_modifiers: ImplicitModifierPolicy,
+    plane_validator: Option<PlaneValidator>,
+    plane_count: Option<PlaneCountQuery>,
+    buffer_destroyed: Option<BufferDestroyedCallback>,
+    on_bind: Option<BindCallback>,
+    metrics: Option<MetricsCallback>,
+    format_advertiser: Option<FormatAdvertiser>,
+    client_policy: Option<ClientPolicy>,
+}
+
+type PlaneValidator = RefCell<Box<dyn FnMut(&[Plane]) -> Result<(), ImportError>>>;
+type PlaneCountQuery = Box<dyn Fn(Format) -> Option<u32>>;
+type BufferDestroyedCallback = RefCell<Box<dyn for<'a> FnMut(&Dmabuf, DispatchData<'a>)>>;
+type BindCallback = RefCell<Box<dyn FnMut(&Client, u32)>>;
+type MetricsCallback = RefCell<Box<dyn FnMut(Format, ImportOutcome)>>;
+type ClientPolicy = Box<dyn Fn(&Client) -> bool>;
+type FormatAdvertiser = RefCell<Box<dyn FnMut(&zwp_linux_dmabuf_v1::ZwpLinuxDmabufV1, &[Format])>>;
+
+impl fmt::Debug for GlobalConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("GlobalConfig")
+            .field("max_planes", &self.max_planes)
+            .field("strict_fd_check", &self.strict_fd_check)
+            .field("check_plane_sizes", &self.check_plane_sizes)
+            .field("max_width", &self.max_width)
+            .field("max_height", &self.max_height)
+            .field("implicit_modifiers", &self.implicit_modifiers)
+            .field("plane_validator", &self.plane_validator.as_ref().map(|_| "..."))
+            .field("plane_count", &self.plane_count.as_ref().map(|_| "..."))
+            .field("buffer_destroyed", &self.buffer_destroyed.as_ref().map(|_| "..."))
+            .field("on_bind", &self.on_bind.as_ref().map(|_| "..."))
+            .field("metrics", &self.metrics.as_ref().map(|_| "..."))
+            .field(
+                "format_advertiser",
+                &self.format_advertiser.as_ref().map(|_| "..."),
+            )
+            .field("client_policy", &self.client_policy.as_ref().map(|_| "..."))
+            .finish()
+    }
+}
+
+impl GlobalConfig {
+    /// Pass the outcome of a buffer creation to the metrics callback, if any
+    fn report(&self, format: Format, outcome: ImportOutcome) {
+        if let Some(ref metrics) = self.metrics {
+            (*metrics.borrow_mut())(format, outcome);
+        }
+    }
+}
+
+impl Default for DmabufGlobalBuilder {
+    fn default() -> Self {
+        DmabufGlobalBuilder::new()
+    }
+}
+
+impl DmabufGlobalBuilder {
+    /// Create a new builder for a global without any formats
+    pub fn new() -> DmabufGlobalBuilder {
+        DmabufGlobalBuilder {
+            formats: Vec::new(),
+            default_feedback: None,
+            config: GlobalConfig {
+                max_planes: MAX_PLANES as u32,
+                strict_fd_check: false,
+                check_plane_sizes: true,
+                max_width: None,
+                max_height: None,
+                implicit_modifiers: ImplicitModifierPolicy::Allow,
+                plane_validator: None,
+                plane_count: None,
+                buffer_destroyed: None,
+                on_bind: None,
+                metrics: None,
+                format_advertiser: None,
+                client_policy: None,
+            },
+            logger: None,
+        }
+    }
+
+    /// Set the formats supported by the global
+    ///
+    /// Ignored if a default feedback is set, the formats of the feedback are used instead.
+    pub fn formats(mut self, formats: Vec<Format>) -> DmabufGlobalBuilder {
+        self.formats = formats;
+        self
+    }
+
+    /// Enable dmabuf feedback and set the feedback advertised to clients by default
+    ///
+    /// The global then advertises version 4 of the protocol, see [`init_dmabuf_global_with_feedback`].
+    pub fn default_feedback(mut self, default_feedback: &DmabufFeedback) -> DmabufGlobalBuilder {
+        self.default_feedback = Some(default_feedback.clone());
+        self
+    }
+
+    /// Set the maximum amount of planes a client may submit for a single buffer
+    ///
+    /// Clamped to [`MAX_PLANES`], which is also the default.
+    pub fn max_planes(mut self, max_planes: u32) -> DmabufGlobalBuilder {
+        self.config.max_planes = max_planes.min(MAX_PLANES as u32);
+        self
+    }
+
+    /// Verify that the file descriptors submitted by clients are dma-bufs
+    ///
+    /// Disabled by default, see [`init_dmabuf_global_strict`].
+    pub fn strict_fd_check(mut self, strict_fd_check: bool) -> DmabufGlobalBuilder {
+        self.config.strict_fd_check = strict_fd_check;
+        self
+    }
+
+    /// Check that the planes submitted by clients fit into the memory of their file descriptors
+    ///
+    /// Enabled by default. Querying the size of every plane costs two syscalls per plane for
+    /// every created buffer. Disabling the check saves them, but allows clients to submit planes
+    /// exceeding their memory, which the driver importing the buffer may not catch, resulting in
+    /// out-of-bounds accesses of the gpu. Only disable this for trusted clients, e.g. if the global
+    /// is only exposed to the parent compositor of a nested compositor.
+    pub fn check_plane_sizes(mut self, check_plane_sizes: bool) -> DmabufGlobalBuilder {
+        self.config.check_plane_sizes = check_plane_sizes;
+        self
+    }
+
+    /// Set the maximum width of buffers created by clients
+    ///
+    /// Wider buffers are rejected with [`ParamError::InvalidDimensions`] before being passed to
+    /// the import handler. Set this to the maximum texture size of the renderer to reject
+    /// buffers, which could not be imported anyway, with a descriptive error. Unlimited by default.
+    pub fn max_width(mut self, max_width: u32) -> DmabufGlobalBuilder {
+        self.config.max_width = Some(max_width);
+        self
+    }
+
+    /// Set the maximum height of buffers created by clients
+    ///
+    /// See [`DmabufGlobalBuilder::max_width`].
+    pub fn max_height(mut self, max_height: u32) -> DmabufGlobalBuilder {
+        self.config.max_height = Some(max_height);
+        self
+    }
+
+    /// Set how buffers using the implicit modifier are handled
+    ///
+    /// Defaults to [`ImplicitModifierPolicy::Allow`].
+    pub fn implicit_modifier_policy(mut self, policy: ImplicitModifierPolicy) -> DmabufGlobalBuilder {
+        self.config.implicit_modifiers = policy;
+        self
+    }
+
+    /// Set a closure inspecting the planes submitted by clients
+    ///
+    /// The closure is given all planes added to a params object, sorted by their index, once the
+    /// client requests to create a buffer out of them. It is called after the basic protocol checks
+    /// succeeded and before the `handler` provided to [`build`](DmabufGlobalBuilder::build).
+    /// If it returns an error, the buffer is rejected as if the `handler` failed.
+    ///
+    /// This allows to apply additional per-plane checks or logging.
+    pub fn plane_validator<V>(mut self, validator: V) -> DmabufGlobalBuilder
+    where
+        V: FnMut(&[Plane]) -> Result<(), ImportError> + 'static,
+    {
+        self.config.plane_validator = Some(RefCell::new(Box::new(validator)));
+        self
+    }
+
+    /// Set a closure returning the number of planes of a format on the importing device
+    ///
+    /// Buffers are checked against the generic plane count of their format by default, allowing
+    /// any number of additional planes for explicit modifiers, which may use auxiliary planes.
+    /// If the closure returns the plane count of a format and modifier, buffers using them have to
+    /// consist of exactly that many planes and are rejected with [`ParamError::Incomplete`] otherwise.
+    /// Returning `None` falls back to the generic check.
+    ///
+    /// See [`GbmDeviceExt::format_plane_count`](crate::backend::allocator::gbm::GbmDeviceExt::format_plane_count)
+    /// and [`gbm_device`](DmabufGlobalBuilder::gbm_device).
+    pub fn plane_count<Q>(mut self, query: Q) -> DmabufGlobalBuilder
+    where
+        Q: Fn(Format) -> Option<u32> + 'static,
+    {
+        self.config.plane_count = Some(Box::new(query));
+        self
+    }
+
+    /// Check the plane count of buffers against the given gbm device
+    ///
+    /// Shorthand for [`plane_count`](DmabufGlobalBuilder::plane_count) using
+    /// [`GbmDeviceExt::format_plane_count`](crate::backend::allocator::gbm::GbmDeviceExt::format_plane_count).
+    #[cfg(feature = "backend_gbm")]
+    pub fn gbm_device<A>(self, device: GbmDevice<A>) -> DmabufGlobalBuilder
+    where
+        A: std::os::unix::io::AsRawFd + 'static,
+    {
+        self.plane_count(move |format| device.format_plane_count(format))
+    }
+
+    /// Set a closure called when a dmabuf-backed `wl_buffer` is destroyed
+    ///
+    /// This happens when the client destroys the buffer or disconnects, which allows to promptly
+    /// release any resources associated with the [`Dmabuf`], e.g. imports into your renderer.
+    pub fn buffer_destroyed<C>(mut self, callback: C) -> DmabufGlobalBuilder
+    where
+        C: for<'a> FnMut(&Dmabuf, DispatchData<'a>) + 'static,
+    {
+        self.config.buffer_destroyed = Some(RefCell::new(Box::new(callback)));
+        self
+    }
+
+    /// Set a closure called whenever a client binds the global
+    ///
+    /// The closure receives the client and the version of the protocol negotiated with it,
+    /// e.g. to track which clients make use of dmabufs.
+    pub fn on_bind<C>(mut self, callback: C) -> DmabufGlobalBuilder
+    where
+        C: FnMut(&Client, u32) + 'static,
+    {
+        self.config.on_bind = Some(RefCell::new(Box::new(callback)));
+        self
+    }
+
+    /// Set a closure called with the outcome of every `create` and `create_immed` request
+    ///
+    /// The closure receives the format of the buffer and whether it was created, rejected or caused
+    /// a protocol error, e.g. to collect statistics about the buffers clients submit. Requests using
+    /// a fourcc code unknown to smithay are not reported. The outcome of an asynchronous import is
+    /// reported once the [`PendingImport`] completes.
+    pub fn metrics<C>(mut self, callback: C) -> DmabufGlobalBuilder
+    where
+        C: FnMut(Format, ImportOutcome) + 'static,
+    {
+        self.config.metrics = Some(RefCell::new(Box::new(callback)));
+        self
+    }
+
+    /// Set a closure deciding which clients may import dmabufs
+    ///
+    /// Buffers of clients the closure returns `false` for are rejected with [`ImportError::Failed`]
+    /// before reaching the plane validator or the handler, like buffers the handler failed to import.
+    /// The parameters are still checked for protocol errors first.
+    pub fn allow_client<F>(mut self, policy: F) -> DmabufGlobalBuilder
+    where
+        F: Fn(&Client) -> bool + 'static,
+    {
+        self.config.client_policy = Some(Box::new(policy));
+        self
+    }
+
+    /// Set a closure sending the supported formats to clients binding the global
+    ///
+    /// Clients binding a version older than 4 are informed about the supported formats by a
+    /// series of `format` and `modifier` events. The closure replaces the default implementation
+    /// [`send_formats`] and receives the bound `zwp_linux_dmabuf_v1` object and the current formats,
+    /// e.g. to cache the grouping of the formats when clients bind the global very often.
+    pub fn format_advertiser<C>(mut self, advertiser: C) -> DmabufGlobalBuilder
+    where
+        C: FnMut(&zwp_linux_dmabuf_v1::ZwpLinuxDmabufV1, &[Format]) + 'static,
+    {
+        self.config.format_advertiser = Some(RefCell::new(Box::new(advertiser)));
+        self
+    }
+
+    /// Set the logger used by the global
+    pub fn logger<L>(mut self, logger: L) -> DmabufGlobalBuilder
+    where
+        L: Into<Option<::slog::Logger>>,
+    {
+        self.logger = logger.into();
+        self
+    }
+
+    /// Create the global
+    ///
+    /// The `handler` closure is used the same way as in [`init_dmabuf_global`].
+    pub fn build<F>(
+        self,
+        display: &mut Display,
+        handler: F,
+    ) -> (DmabufState, Global<zwp_linux_dmabuf_v1::ZwpLinuxDmabufV1>)
+    where
+        F: for<'a> FnMut(&Dmabuf, DispatchData<'a>) -> Result<(), ImportError> + 'static,
+    {
+        let mut handler = handler;
+        self.build_inner(display, move |dmabuf, ddata| {
+            handler(dmabuf, ddata).map(|()| None)
+        })
+    }
+
+    /// Create the global, attaching the data returned by the `handler` to the created buffers
+    ///
+    /// The `handler` closure is used the same way as in [`init_dmabuf_global`], but may return
+    /// arbitrary data on success, e.g. the texture the dmabuf was imported as. The data can be
+    /// retrieved from the `wl_buffer` using [`get_buffer_data`].
+    pub fn build_with_data<T, F>(
+        self,
+        display: &mut Display,
+        mut handler: F,
+    ) -> (DmabufState, Global<zwp_linux_dmabuf_v1::ZwpLinuxDmabufV1>)
+    where
+        T: Send + Sync + 'static,
+        F: for<'a> FnMut(&Dmabuf, DispatchData<'a>) -> Result<T, ImportError> + 'static,
+    {
+        self.build_inner(display, move |dmabuf, ddata| {
+            handler(dmabuf, ddata).map(|data| Some(Arc::new(data) as BufferData))
+        })
+    }
+
+    fn build_inner<F>(
+        self,
+        display: &mut Display,
+        handler: F,
+    ) -> (DmabufState, Global<zwp_linux_dmabuf_v1::ZwpLinuxDmabufV1>)
+    where
+        F: for<'a> FnMut(&Dmabuf, DispatchData<'a>) -> Result<Option<BufferData>, ImportError> + 'static,
+    {
+        self.build_with_handler(display, Handler::Sync(Box::new(handler)))
+    }
+
+    /// Create the global, importing the submitted dmabufs asynchronously
+    ///
+    /// Testing the import of a dmabuf may block for a noticeable time, which stalls the event loop,
+    /// if done by the `handler` passed to [`build`](DmabufGlobalBuilder::build). Instead, the `handler`
+    /// passed here receives a [`PendingImport`] and may complete it at any later point, e.g. after the
+    /// [`Dmabuf`] was imported on a worker thread and the result was sent back through a calloop channel.
+    ///
+    /// The protocol allows to defer the `created`/`failed` events of a buffer created using `create`.
+    /// Buffers created using `create_immed` already exist for the client, which may attach them
+    /// to surfaces right away. They are not backed by a dmabuf before the import was accepted, so
+    /// [`get_dmabuf`] returns `None` for them until then and the compositor has to skip them, like
+    /// any other unknown buffer. If the import is rejected, the client is killed with the
+    /// `invalid_wl_buffer` error, even if it already destroyed the params object.
+    ///
+    /// Between its creation and the rejection of the import, a client may therefore briefly use a
+    /// buffer, which can never be imported. Its contents are never shown during that window.
+    ///
+    /// [`DmabufState::revalidate`] is not supported by globals created this way.
+    pub fn build_async<F>(
+        self,
+        display: &mut Display,
+        handler: F,
+    ) -> (DmabufState, Global<zwp_linux_dmabuf_v1::ZwpLinuxDmabufV1>)
+    where
+        F: for<'a> FnMut(PendingImport, DispatchData<'a>) + 'static,
+    {
+        self.build_with_handler(display, Handler::Async(Box::new(handler)))
+    }
+
+    fn build_with_handler(
+        self,
+        display: &mut Display,
+        handler: Handler,
+    ) -> (DmabufState, Global<zwp_linux_dmabuf_v1::ZwpLinuxDmabufV1>) {
+        let log = crate::slog_or_fallback(self.logger).new(o!("smithay_module" => "dmabuf_handler"));
+        let formats = match self.default_feedback {
+            Some(ref feedback) => feedback.formats().to_vec(),
+            None => self.formats,
+        };
+        let state = DmabufState::new(formats, self.default_feedback, handler, log);
+        let global = init_global(display, state.clone(), self.config);
+        (state, global)
+    }
+
+    /// Create the global, giving the `handler` access to the state passed to [`Display::dispatch`]
+    ///
+    /// Instead of capturing your compositor state, e.g. the renderer used to test the import,
+    /// the `handler` receives the `D` provided as dispatch data. If the dispatch data is not of
+    /// type `D`, the dmabuf is rejected with [`ImportError::Failed`].
+    pub fn build_with_state<D, F>(
+        self,
+        display: &mut Display,
+        mut handler: F,
+    ) -> (DmabufState, Global<zwp_linux_dmabuf_v1::ZwpLinuxDmabufV1>)
+    where
+        D: 'static,
+        F: FnMut(&Dmabuf, &mut D) -> Result<(), ImportError> + 'static,
+    {
+        let log = crate::slog_or_fallback(self.logger.clone());
+        self.build(display, move |dmabuf, mut ddata| match ddata.get::<D>() {
+            Some(state) => handler(dmabuf, state),
+            None => {
+                error!(
+                    log,
+                    "Dispatch data of the dmabuf global is not of type {}",
+                    std::any::type_name::<D>()
+                );
+                Err(ImportError::Failed)
+            }
+        })
+    }
+}
+
+/// State of a dmabuf global
+///
+/// This handle allows you to update the formats advertised by the global and, if the global
+/// supports dmabuf feedback, to change the feedback advertised for specific surfaces.
+#[derive(Debug, Clone)]
+pub struct DmabufState {
+    inner: Rc<DmabufStateInner>,
+}
+
+struct DmabufStateInner {
+    formats: RefCell<Rc<[Format]>>,
+    default_feedback: RefCell<Option<DmabufFeedback>>,
+    /// Feedback objects of all clients, with the surface they were created for
+    feedback_instances: RefCell<Vec<(ZwpLinuxDmabufFeedbackV1, Option<WlSurface>)>>,
+    handler: RefCell<Handler>,
+    log: ::slog::Logger,
+}
+
+type ImportHandler = dyn for<'a> FnMut(&Dmabuf, DispatchData<'a>) -> Result<Option<BufferData>, ImportError>;
+type AsyncImportHandler = dyn for<'a> FnMut(PendingImport, DispatchData<'a>);
+
+enum Handler {
+    Sync(Box<ImportHandler>),
+    Async(Box<AsyncImportHandler>),
+}
+
+impl fmt::Debug for DmabufStateInner {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("DmabufStateInner")
+            .field("formats", &self.formats)
+            .field("default_feedback", &self.default_feedback)
+            .field("feedback_instances", &self.feedback_instances)
+            .field("handler", &"...")
+            .field("log", &self.log)
+            .finish()
+    }
+}
+
+impl DmabufState {
+    fn new(
+        formats: Vec<Format>,
+        default_feedback: Option<DmabufFeedback>,
+        handler: Handler,
+        log: ::slog::Logger,
+    ) -> DmabufState {
+        let formats = dedup_formats(formats, &log);
+        DmabufState {
+            inner: Rc::new(DmabufStateInner {
+                formats: RefCell::new(formats.into()),
+                default_feedback: RefCell::new(default_feedback),
+                feedback_instances: RefCell::new(Vec::new()),
+                handler: RefCell::new(handler),
+                log,
+            }),
+        }
+    }
+
+    /// The formats currently advertised by the global
+    ///
+    /// The returned list is a snapshot and not affected by later calls to [`set_formats`](DmabufState::set_formats).
+    pub fn formats(&self) -> Rc<[Format]> {
+        self.inner.formats.borrow().clone()
+    }
+
+    /// Check if `format` is currently advertised by the global
+    ///
+    /// Both the fourcc code and the modifier have to match one of the advertised formats.
+    pub fn is_format_supported(&self, format: &Format) -> bool {
+        self.inner.formats.borrow().contains(format)
+    }
+
+    /// Run the handler of the global again for an already created buffer
+    ///
+    /// This is useful if the buffer needs to be imported again, e.g. into a different gpu, after
+    /// a surface moved to another output. On success the data attached to the buffer is replaced
+    /// by the newly returned data, see [`get_buffer_data`].
+    ///
+    /// If the handler fails, the attached data is removed and `false` is returned. The buffer stays
+    /// alive, as the protocol offers no way to revoke an already created buffer, so it is up to the
+    /// compositor how to handle it, e.g. by not displaying it anymore.
+    /// Buffers not created by this global are ignored and also return `false`.
+    ///
+    /// Always returns `false` for globals created using [`DmabufGlobalBuilder::build_async`].
+    ///
+    /// *Note*: This must not be called from inside the handler.
+    pub fn revalidate(&self, buffer: &wl_buffer::WlBuffer, ddata: DispatchData<'_>) -> bool {
+        let buffer = match buffer.as_ref().user_data().get::<DmabufBuffer>() {
+            Some(buffer) => buffer,
+            None => return false,
+        };
+        let result = match *self.inner.handler.borrow_mut() {
+            Handler::Sync(ref mut handler) => handler(&buffer.dmabuf, ddata),
+            Handler::Async(_) => {
+                debug!(
+                    self.inner.log,
+                    "Revalidation is not supported by asynchronous handlers"
+                );
+                return false;
+            }
+        };
+        match result {
+            Ok(data) => {
+                *buffer.data.lock().unwrap() = data;
+                true
+            }
+            Err(err) => {
+                debug!(self.inner.log, "Revalidation of a dma wl_buffer failed: {}", err);
+                *buffer.data.lock().unwrap() = None;
+                false
+            }
+        }
+    }
+
+    /// The feedback advertised to clients by default
+    ///
+    /// Returns `None` if the global does not support dmabuf feedback.
+    pub fn default_feedback(&self) -> Option<DmabufFeedback> {
+        self.inner.default_feedback.borrow().clone()
+    }
+
+    /// Update the formats supported by the global
+    ///
+    /// Clients binding the global afterwards receive the new list of formats. Clients, which
+    /// already bound the global, have to be informed via dmabuf feedback, so this only affects
+    /// them, if the global supports version 4 of the protocol. In that case the default feedback
+    /// is replaced by a new feedback advertising `formats` for the same main device and resent to
+    /// all feedback objects, that are not using a surface specific feedback.
+    ///
+    /// Buffers submitted by clients are validated against the new formats from now on.
+    /// Already created buffers are unaffected.
+    pub fn set_formats(&self, formats: Vec<Format>) {
+        let formats = dedup_formats(formats, &self.inner.log);
+        let default_feedback = self
+            .default_feedback()
+            .map(|feedback| DmabufFeedback::new(feedback.main_device(), formats.clone()));
+        *self.inner.formats.borrow_mut() = formats.into();
+
+        if let Some(default_feedback) = default_feedback {
+            self.replace_default_feedback(default_feedback);
+        }
+    }
+
+    /// Replace the default feedback of the global, e.g. after the primary gpu changed
+    ///
+    /// The new feedback is resent to all feedback objects, that are not using a surface specific
+    /// feedback, and the formats of the global are replaced by the formats of `feedback`.
+    /// Surface specific feedback is kept as is, use [`set_surface_feedback`](DmabufState::set_surface_feedback)
+    /// to update it as well, if it refers to the previous device.
+    ///
+    /// Does nothing, if the global does not support dmabuf feedback.
+    pub fn update_feedback(&self, feedback: DmabufFeedback) {
+        if self.default_feedback().is_none() {
+            return;
+        }
+        let formats = dedup_formats(feedback.formats().to_vec(), &self.inner.log);
+        *self.inner.formats.borrow_mut() = formats.into();
+        self.replace_default_feedback(feedback);
+    }
+
+    /// Track a feedback object until it is destroyed, so it is sent updated feedback
+    fn track_feedback(&self, feedback: &Main<ZwpLinuxDmabufFeedbackV1>, surface: Option<WlSurface>) {
+        self.inner
+            .feedback_instances
+            .borrow_mut()
+            .push((feedback.deref().clone(), surface));
+
+        let inner = Rc::downgrade(&self.inner);
+        // destroyed objects are no longer alive, so they cannot be compared to the tracked ones
+        feedback.assign_destructor(Filter::new(move |_: ZwpLinuxDmabufFeedbackV1, _, _| {
+            if let Some(inner) = inner.upgrade() {
+                inner
+                    .feedback_instances
+                    .borrow_mut()
+                    .retain(|(instance, surface)| {
+                        if instance.as_ref().is_alive() {
+                            return true;
+                        }
+                        if let Some(surface) = surface {
+                            feedback::prune_surface_feedback(surface);
+                        }
+                        false
+                    });
+            }
+        }));
+    }
+
+    fn replace_default_feedback(&self, default_feedback: DmabufFeedback) {
+        *self.inner.default_feedback.borrow_mut() = Some(default_feedback.clone());
+        let mut instances = self.inner.feedback_instances.borrow_mut();
+        instances.retain(|(instance, _)| instance.as_ref().is_alive());
+        for (instance, surface) in instances.iter() {
+            if matches!(surface, Some(surface) if feedback::has_surface_feedback(surface)) {
+                continue;
+            }
+            if let Err(err) = default_feedback.send(instance) {
+                warn!(self.inner.log,
+                    "Failed to send dmabuf feedback to client";
+                    "err" => ?err
+                );
+            }
+        }
+    }
+
+    /// Set the feedback advertised for a surface
+    ///
+    /// The feedback is immediately resent to all feedback objects the client created for this
+    /// surface and replaces the default feedback for any feedback object it creates for this
+    /// surface in the future. This can be used to steer the client towards scan-out capable
+    /// formats when its surface is displayed on a hardware plane of a specific device.
+    ///
+    /// Does nothing, if the global does not support dmabuf feedback.
+    pub fn set_surface_feedback(&self, surface: &WlSurface, feedback: DmabufFeedback) {
+        if let Some(default_feedback) = self.default_feedback() {
+            feedback::set_surface_feedback(surface, Some(feedback), &default_feedback, &self.inner.log);
+        }
+    }
+
+    /// Reset the feedback advertised for a surface to the default feedback
+    pub fn reset_surface_feedback(&self, surface: &WlSurface) {
+        if let Some(default_feedback) = self.default_feedback() {
+            feedback::set_surface_feedback(surface, None, &default_feedback, &self.inner.log);
+        }
+    }
+}
+
+/// Initialize a dmabuf global supporting dmabuf feedback.
+///
+/// This global advertises version 4 of the protocol. Clients binding version 4 receive the
+/// provided `default_feedback` when requesting feedback, while clients binding an older version
+/// still receive the formats of its format table as `format`/`modifier` events.
+///
+/// The `handler` closure is used the same way as in [`init_dmabuf_global`].
+///
+/// This function returns the [`DmabufState`] allowing you to control the feedback advertised
+/// for specific surfaces, as well as the global handle, in case you wish to remove this global
+/// in the future.
+pub fn init_dmabuf_global_with_feedback<F, L>(
+    display: &mut Display,
+    default_feedback: &DmabufFeedback,
+    handler: F,
+    logger: L,
+) -> (DmabufState, Global<zwp_linux_dmabuf_v1::ZwpLinuxDmabufV1>)
 where
     L: Into<Option<::slog::Logger>>,
-    F: for<'a> FnMut(&Dmabuf, DispatchData<'a>) -> bool + 'static,
+    F: for<'a> FnMut(&Dmabuf, DispatchData<'a>) -> Result<(), ImportError> + 'static,
 {
-    let log = crate::slog_or_fallback(logger).new(o!("smithay_module" => "dmabuf_handler"));
+    DmabufGlobalBuilder::new()
+        .default_feedback(default_feedback)
+        .logger(logger)
+        .build(display, handler)
+}
 
-    let formats = Rc::<[Format]>::from(formats);
-    let handler = Rc::new(RefCell::new(handler));
+fn init_global(
+    display: &mut Display,
+    state: DmabufState,
+    config: GlobalConfig,
+) -> Global<zwp_linux_dmabuf_v1::ZwpLinuxDmabufV1> {
+    let config = Rc::new(config);
+    let version = if state.default_feedback().is_some() { 4 } else { 3 };
+    let log = state.inner.log.clone();
 
     trace!(
         log,
         "Initializing DMABUF handler with {} supported formats",
-        formats.len()
+        state.formats().len()
     );
 
     display.create_global(
-        3,
+        version,
         Filter::new(
             move |(dmabuf, version): (Main<zwp_linux_dmabuf_v1::ZwpLinuxDmabufV1>, u32), _, _| {
-                let dma_formats = formats.clone();
-                let dma_handler = handler.clone();
+                if let (Some(callback), Some(client)) = (config.on_bind.as_ref(), dmabuf.as_ref().client()) {
+                    (callback.borrow_mut())(&client, version);
+                }
+                let dma_config = config.clone();
+                let dma_state = state.clone();
                 let dma_log = log.clone();
-                dmabuf.quick_assign(move |_, req, _| {
-                    if let zwp_linux_dmabuf_v1::Request::CreateParams { params_id } = req {
+                dmabuf.quick_assign(move |_, req, _| match req {
+                    zwp_linux_dmabuf_v1::Request::CreateParams { params_id } => {
                         let mut handler = ParamsHandler {
                             pending_planes: Vec::new(),
-                            max_planes: 4,
                             used: false,
-                            formats: dma_formats.clone(),
-                            handler: dma_handler.clone(),
+                            destroyed: false,
+                            config: dma_config.clone(),
+                            state: dma_state.clone(),
                             log: dma_log.clone(),
                         };
                         params_id.quick_assign(move |params, req, ddata| match req {
@@ -123,7 +1189,7 @@ where
                                 plane_idx,
                                 offset,
                                 stride,
-                                ((modifier_hi as u64) << 32) + (modifier_lo as u64),
+                                combine_modifier(modifier_hi, modifier_lo),
                             ),
                             ParamsRequest::Create {
                                 width,
@@ -140,20 +1206,44 @@ where
                             } => {
                                 handler.create_immed(&*params, buffer_id, width, height, format, flags, ddata)
                             }
-                            _ => {}
+                            ParamsRequest::Destroy => handler.destroy(),
                         });
                     }
-                });
-
-                // send the supported formats
-                for f in &*formats {
-                    dmabuf.format(f.code as u32);
-                    if version >= 3 {
-                        dmabuf.modifier(
-                            f.code as u32,
-                            (Into::<u64>::into(f.modifier) >> 32) as u32,
-                            Into::<u64>::into(f.modifier) as u32,
-                        );
+                    zwp_linux_dmabuf_v1::Request::GetDefaultFeedback { id } => {
+                        id.quick_assign(|_, _, _| {});
+                        // this request is only available to clients of a version 4 global,
+                        // which always has a default feedback
+                        if let Some(default_feedback) = dma_state.default_feedback() {
+                            dma_state.track_feedback(&id, None);
+                            if let Err(err) = default_feedback.send(&id) {
+                                warn!(dma_log,
+                                    "Failed to send dmabuf feedback to client";
+                                    "err" => ?err
+                                );
+                            }
+                        }
+                    }
+                    zwp_linux_dmabuf_v1::Request::GetSurfaceFeedback { id, surface } => {
+                        id.quick_assign(|_, _, _| {});
+                        if let Some(default_feedback) = dma_state.default_feedback() {
+                            dma_state.track_feedback(&id, Some(surface.clone()));
+                            if let Err(err) = feedback::init_surface_feedback(id, surface, &default_feedback)
+                            {
+                                warn!(dma_log,
+                                    "Failed to send dmabuf feedback to client";
+                                    "err" => ?err
+                                );
+                            }
+                        }
+                    }
+                    _ => {}
+                });
+
+                // version 4 clients are expected to use dmabuf feedback instead
+                if version < 4 {
+                    match config.format_advertiser {
+                        Some(ref advertiser) => (advertiser.borrow_mut())(&dmabuf, &state.formats()),
+                        None => send_formats(&dmabuf, &state.formats()),
                     }
                 }
             },
@@ -161,19 +1251,64 @@ where
     )
 }
 
-struct ParamsHandler<H: for<'a> FnMut(&Dmabuf, DispatchData<'a>) -> bool + 'static> {
+/// Send `formats` to a client using `format` and `modifier` events
+///
+/// This is how a dmabuf global advertises its formats to clients binding a version older than 4,
+/// unless a [`format_advertiser`](DmabufGlobalBuilder::format_advertiser) was set.
+/// The `modifier` events are only sent to clients of version 3.
+pub fn send_formats(dmabuf: &zwp_linux_dmabuf_v1::ZwpLinuxDmabufV1, formats: &[Format]) {
+    let version = dmabuf.as_ref().version();
+    for (code, modifiers) in group_by_fourcc(formats) {
+        dmabuf.format(code as u32);
+        if version < 3 {
+            continue;
+        }
+        // the invalid modifier is implied by the format event
+        for modifier in modifiers.into_iter().filter(|m| *m != Modifier::Invalid) {
+            dmabuf.modifier(
+                code as u32,
+                (Into::<u64>::into(modifier) >> 32) as u32,
+                Into::<u64>::into(modifier) as u32,
+            );
+        }
+    }
+}
+
+/// Reassemble a modifier split into its upper and lower 32 bits by the protocol
+fn combine_modifier(modifier_hi: u32, modifier_lo: u32) -> u64 {
+    ((modifier_hi as u64) << 32) | (modifier_lo as u64)
+}
+
+/// Remove duplicated formats, preserving the order of the remaining ones
+fn dedup_formats(formats: Vec<Format>, log: &::slog::Logger) -> Vec<Format> {
+    let count = formats.len();
+    let mut deduped = Vec::with_capacity(count);
+    for format in formats {
+        if !deduped.contains(&format) {
+            deduped.push(format);
+        }
+    }
+    if deduped.len() != count {
+        warn!(
+            log,
+            "Ignoring {} duplicated dmabuf formats",
+            count - deduped.len()
+        );
+    }
+    deduped
+}
+
+struct ParamsHandler {
     pending_planes: Vec<Plane>,
-    max_planes: u32,
     used: bool,
-    formats: Rc<[Format]>,
-    handler: Rc<RefCell<H>>,
+    /// Whether the client sent the destroy request
+    destroyed: bool,
+    config: Rc<GlobalConfig>,
+    state: DmabufState,
     log: ::slog::Logger,
 }
 
-impl<H> ParamsHandler<H>
-where
-    H: for<'a> FnMut(&Dmabuf, DispatchData<'a>) -> bool + 'static,
-{
+impl ParamsHandler {
     fn add(
         &mut self,
         params: &BufferParams,
@@ -193,7 +1328,7 @@ where
             return;
         }
         // plane_idx is not too large
-        if plane_idx >= self.max_planes {
+        if plane_idx >= self.config.max_planes {
             // plane_idx starts at 0
             params.as_ref().post_error(
                 ParamError::PlaneIdx as u32,
@@ -219,93 +1354,176 @@ where
         });
     }
 
-    fn create<'a>(
+    /// Release the planes of a params destroyed without creating a buffer
+    fn destroy(&mut self) {
+        if !self.used && !self.pending_planes.is_empty() {
+            warn!(
+                self.log,
+                "Client destroyed dmabuf params with {} planes without creating a buffer",
+                self.pending_planes.len()
+            );
+        }
+        // dropping the planes closes their fds
+        self.pending_planes.clear();
+        self.destroyed = true;
+    }
+
+    /// Assemble the pending planes into a dmabuf
+    ///
+    /// The pending planes are consumed on every path. Returns `None`, if a protocol error was posted,
+    /// in which case the fds of the planes were already closed. Otherwise the fds are owned by the
+    /// returned dmabuf and closed once it is dropped, e.g. after it was rejected by the handler.
+    fn build(
         &mut self,
         params: &BufferParams,
         width: i32,
         height: i32,
         format: u32,
         flags: BufferFlags,
-        ddata: DispatchData<'a>,
-    ) {
+    ) -> Option<Dmabuf> {
         // Cannot reuse a params:
         if self.used {
             params.as_ref().post_error(
                 ParamError::AlreadyUsed as u32,
                 "This buffer_params has already been used to create a buffer.".into(),
             );
-            return;
+            return None;
         }
         self.used = true;
+        // take ownership of the planes, so their fds are closed on any of the failure paths below
+        let planes = std::mem::take(&mut self.pending_planes);
 
         let format = match Fourcc::try_from(format) {
             Ok(format) => format,
-            Err(_) => {
+            Err(err) => {
                 params.as_ref().post_error(
                     ParamError::InvalidFormat as u32,
-                    format!("Format {:x} is not supported", format),
+                    format!("Format {:?} is not supported", err),
                 );
-                return;
+                return None;
             }
         };
 
         if !buffer_basic_checks(
-            &self.formats,
-            &self.pending_planes,
-            &params,
+            &self.state.formats(),
+            &planes,
+            params,
             format,
             width,
             height,
+            &self.config,
         ) {
             trace!(self.log, "Killing client providing bogus dmabuf buffer params.");
-            return;
+            return None;
         }
 
-        let mut buf = Dmabuf::builder(
-            (width, height),
-            format,
-            DmabufFlags::from_bits_truncate(flags.bits()),
-        );
-        let planes = std::mem::take(&mut self.pending_planes);
-        for (i, plane) in planes.into_iter().enumerate() {
-            let offset = plane.offset;
-            let stride = plane.stride;
-            let modi = plane.modifier;
-            buf.add_plane(plane.into_raw_fd(), i as u32, offset, stride, modi);
-        }
-        let dmabuf = match buf.build() {
-            Some(buf) => buf,
+        match build_dmabuf(planes, width, height, format, dmabuf_flags(flags, &self.log)) {
+            Some(buf) => Some(buf),
             None => {
                 params.as_ref().post_error(
                     ParamError::Incomplete as u32,
                     "Provided buffer is incomplete, it has zero planes".to_string(),
                 );
+                None
+            }
+        }
+    }
+
+    /// Build the dmabuf and pass it to the plane validator and the handler
+    ///
+    /// `buffer` is the `wl_buffer` created by the client for `create_immed`, if any.
+    #[allow(clippy::too_many_arguments)]
+    fn submit(
+        &mut self,
+        params: &BufferParams,
+        buffer: Option<Main<wl_buffer::WlBuffer>>,
+        width: i32,
+        height: i32,
+        format: u32,
+        flags: BufferFlags,
+        ddata: DispatchData<'_>,
+    ) {
+        // the pending planes are consumed by `build`, so determine the reported format up front
+        let reported_format = self.config.metrics.as_ref().and_then(|_| {
+            let modifier = self
+                .pending_planes
+                .first()
+                .map_or(Modifier::Invalid, |plane| plane.modifier);
+            Fourcc::try_from(format)
+                .ok()
+                .map(|code| Format { code, modifier })
+        });
+        let dmabuf = match self.build(params, width, height, format, flags) {
+            Some(dmabuf) => dmabuf,
+            None => {
+                if let Some(format) = reported_format {
+                    self.config.report(format, ImportOutcome::ProtocolError);
+                }
                 return;
             }
         };
+        let mut pending = PendingBuffer {
+            params: params.clone(),
+            buffer,
+            deferred: false,
+            config: self.config.clone(),
+            log: self.log.clone(),
+        };
 
-        let mut handler = self.handler.borrow_mut();
-        if handler(&dmabuf, ddata) {
-            if let Some(buffer) = params
-                .as_ref()
-                .client()
-                .and_then(|c| c.create_resource::<wl_buffer::WlBuffer>(1))
-            {
-                buffer.as_ref().user_data().set_threadsafe(|| dmabuf);
-                buffer.quick_assign(|_, _, _| {});
-
-                trace!(self.log, "Created a new validated dma wl_buffer.");
-                params.created(&buffer);
-            } else {
-                trace!(self.log, "Failed to create a wl_buffer");
-                params.failed();
+        if let Some(ref policy) = self.config.client_policy {
+            let allowed = match params.as_ref().client() {
+                Some(client) => policy(&client),
+                None => false,
+            };
+            if !allowed {
+                debug!(self.log, "Denying dmabuf import of a client by policy");
+                pending.complete(dmabuf, Err(ImportError::Failed));
+                return;
+            }
+        }
+
+        if let Some(ref validator) = self.config.plane_validator {
+            let result = (*validator.borrow_mut())(&dmabuf.0.planes);
+            if let Err(err) = result {
+                pending.complete(dmabuf, Err(err));
+                return;
+            }
+        }
+
+        match *self.state.inner.handler.borrow_mut() {
+            Handler::Sync(ref mut handler) => {
+                let result = handler(&dmabuf, ddata);
+                pending.complete(dmabuf, result);
+            }
+            Handler::Async(ref mut handler) => {
+                if let Some(ref buffer) = pending.buffer {
+                    // the client may already use the buffer before the import completed
+                    buffer.quick_assign(|_, _, _| {});
+                }
+                pending.deferred = true;
+                handler(
+                    PendingImport {
+                        dmabuf,
+                        pending: Some(pending),
+                    },
+                    ddata,
+                );
             }
-        } else {
-            trace!(self.log, "Refusing creation of an invalid dma wl_buffer.");
-            params.failed();
         }
     }
 
+    fn create<'a>(
+        &mut self,
+        params: &BufferParams,
+        width: i32,
+        height: i32,
+        format: u32,
+        flags: BufferFlags,
+        ddata: DispatchData<'a>,
+    ) {
+        self.submit(params, None, width, height, format, flags, ddata)
+    }
+
     #[allow(clippy::too_many_arguments)]
     fn create_immed<'a>(
         &mut self,
@@ -317,80 +1535,254 @@ where
         flags: BufferFlags,
         ddata: DispatchData<'a>,
     ) {
-        // Cannot reuse a params:
-        if self.used {
-            params.as_ref().post_error(
-                ParamError::AlreadyUsed as u32,
-                "This buffer_params has already been used to create a buffer.".into(),
-            );
-            return;
-        }
-        self.used = true;
+        self.submit(params, Some(buffer), width, height, format, flags, ddata)
+    }
+}
 
-        let format = match Fourcc::try_from(format) {
-            Ok(format) => format,
-            Err(_) => {
-                params.as_ref().post_error(
-                    ParamError::InvalidFormat as u32,
-                    format!("Format {:x} is not supported", format),
+/// Buffer creation waiting for the result of the handler
+#[derive(Debug)]
+struct PendingBuffer {
+    params: BufferParams,
+    /// The `wl_buffer` created by the client for `create_immed`
+    buffer: Option<Main<wl_buffer::WlBuffer>>,
+    /// Whether the result is provided by an asynchronous handler
+    deferred: bool,
+    config: Rc<GlobalConfig>,
+    log: ::slog::Logger,
+}
+
+impl PendingBuffer {
+    /// Create the buffer or report the failure to the client
+    fn complete(self, dmabuf: Dmabuf, result: Result<Option<BufferData>, ImportError>) {
+        let outcome = match result {
+            Ok(_) => ImportOutcome::Created,
+            Err(err) => ImportOutcome::Rejected(err),
+        };
+        self.config.report(dmabuf.format(), outcome);
+        match (result, self.buffer.as_ref()) {
+            (Ok(data), Some(buffer)) => self.init_buffer(buffer, dmabuf, data),
+            (Ok(data), None) => {
+                if let Some(buffer) = self
+                    .params
+                    .as_ref()
+                    .client()
+                    .and_then(|c| c.create_resource::<wl_buffer::WlBuffer>(1))
+                {
+                    self.init_buffer(&buffer, dmabuf, data);
+                    self.params.created(&buffer);
+                } else {
+                    trace!(self.log, "Failed to create a wl_buffer");
+                    self.params.failed();
+                }
+            }
+            (Err(err), None) => {
+                debug!(self.log, "Refusing creation of an invalid dma wl_buffer: {}", err;
+                    "buffer" => %DmabufDescription(&dmabuf)
                 );
-                return;
+                self.params.failed();
             }
-        };
+            (Err(err), Some(buffer)) => {
+                debug!(
+                    self.log,
+                    "Refusing creation of an invalid immediate dma wl_buffer, killing client: {}", err;
+                    "buffer" => %DmabufDescription(&dmabuf)
+                );
+                // close the fds of the rejected buffer right away, instead of relying on
+                // the client cleanup after it was killed
+                drop(dmabuf);
+                let error = match err {
+                    // the client may already have used the buffer
+                    _ if self.deferred => ParamError::InvalidWlBuffer,
+                    ImportError::UnsupportedFormat | ImportError::UnsupportedModifier => {
+                        ParamError::InvalidFormat
+                    }
+                    ImportError::OutOfMemory | ImportError::Failed => ParamError::InvalidWlBuffer,
+                };
+                let message = format!("create_immed resulted in an invalid buffer: {}", err);
+                // the params object may be destroyed by the time a deferred import failed
+                if self.params.as_ref().is_alive() {
+                    self.params.as_ref().post_error(error as u32, message);
+                } else {
+                    buffer.as_ref().post_error(error as u32, message);
+                }
+            }
+        }
+    }
 
-        if !buffer_basic_checks(
-            &self.formats,
-            &self.pending_planes,
-            &params,
-            format,
-            width,
-            height,
-        ) {
-            trace!(self.log, "Killing client providing bogus dmabuf buffer params.");
+    /// Attach a validated dmabuf and the data returned by the handler to `buffer`
+    fn init_buffer(&self, buffer: &Main<wl_buffer::WlBuffer>, dmabuf: Dmabuf, data: Option<BufferData>) {
+        // params objects share the version of the global they were created by
+        let version = self.params.as_ref().version();
+        buffer.as_ref().user_data().set_threadsafe(|| DmabufBuffer {
+            dmabuf,
+            data: Mutex::new(data),
+            version,
+            fences: Mutex::new(Fences::default()),
+            damage: Mutex::new(None),
+            user_data: UserDataMap::new(),
+        });
+        buffer.quick_assign(|_, _, _| {});
+        self.assign_destructor(buffer);
+        trace!(self.log, "Created a new validated dma wl_buffer.");
+    }
+
+    /// Notify the `buffer_destroyed` callback once `buffer` is destroyed
+    fn assign_destructor(&self, buffer: &Main<wl_buffer::WlBuffer>) {
+        if self.config.buffer_destroyed.is_none() {
             return;
         }
+        let config = self.config.clone();
+        buffer.assign_destructor(Filter::new(move |buffer: wl_buffer::WlBuffer, _, ddata| {
+            if let (Some(dmabuf), Some(callback)) = (get_dmabuf(&buffer), config.buffer_destroyed.as_ref()) {
+                (*callback.borrow_mut())(dmabuf, ddata);
+            }
+        }));
+    }
+}
 
-        let mut buf = Dmabuf::builder(
-            (width, height),
-            format,
-            DmabufFlags::from_bits_truncate(flags.bits()),
-        );
-        let planes = ::std::mem::take(&mut self.pending_planes);
-        for (i, plane) in planes.into_iter().enumerate() {
-            let offset = plane.offset;
-            let stride = plane.stride;
-            let modi = plane.modifier;
-            buf.add_plane(plane.into_raw_fd(), i as u32, offset, stride, modi);
+/// Dmabuf submitted by a client, waiting to be imported by an asynchronous handler
+///
+/// Passed to the handler of a global created using [`DmabufGlobalBuilder::build_async`].
+/// The import has to be completed by calling either [`accept`](PendingImport::accept) or
+/// [`reject`](PendingImport::reject). Dropping a `PendingImport` rejects it with [`ImportError::Failed`].
+///
+/// The `PendingImport` itself has to stay on the thread running the wayland event loop, but the
+/// [`Dmabuf`] can be cloned and sent to other threads.
+#[derive(Debug)]
+pub struct PendingImport {
+    dmabuf: Dmabuf,
+    pending: Option<PendingBuffer>,
+}
+
+impl PendingImport {
+    /// The dmabuf to be imported
+    pub fn dmabuf(&self) -> &Dmabuf {
+        &self.dmabuf
+    }
+
+    /// Accept the dmabuf and create the buffer
+    pub fn accept(self) {
+        self.complete(Ok(None));
+    }
+
+    /// Accept the dmabuf, attaching `data` to the created buffer
+    ///
+    /// The data can be retrieved from the `wl_buffer` using [`get_buffer_data`].
+    pub fn accept_with_data<T: Send + Sync + 'static>(self, data: T) {
+        self.complete(Ok(Some(Arc::new(data))));
+    }
+
+    /// Reject the dmabuf
+    ///
+    /// Rejected buffers created using `create_immed` raise the `invalid_wl_buffer` protocol error,
+    /// as the client may already have used them.
+    pub fn reject(self, error: ImportError) {
+        self.complete(Err(error));
+    }
+
+    fn complete(mut self, result: Result<Option<BufferData>, ImportError>) {
+        if let Some(pending) = self.pending.take() {
+            pending.complete(self.dmabuf.clone(), result);
         }
-        let dmabuf = match buf.build() {
-            Some(buf) => buf,
-            None => {
-                params.as_ref().post_error(
-                    ParamError::Incomplete as u32,
-                    "Provided buffer is incomplete, it has zero planes".to_string(),
-                );
-                return;
-            }
-        };
+    }
+}
+
+impl Drop for PendingImport {
+    fn drop(&mut self) {
+        if let Some(pending) = self.pending.take() {
+            pending.complete(self.dmabuf.clone(), Err(ImportError::Failed));
+        }
+    }
+}
 
-        let mut handler = self.handler.borrow_mut();
-        if handler(&dmabuf, ddata) {
-            buffer.as_ref().user_data().set_threadsafe(|| dmabuf);
-            buffer.quick_assign(|_, _, _| {});
-            trace!(self.log, "Created a new validated dma wl_buffer.");
-        } else {
-            trace!(
+impl Drop for ParamsHandler {
+    fn drop(&mut self) {
+        // the handler is dropped together with the params object, which is also destroyed
+        // without a destroy request once the client disconnects
+        if !self.used && !self.destroyed {
+            warn!(
                 self.log,
-                "Refusing creation of an invalid immediate dma wl_buffer, killing client."
-            );
-            params.as_ref().post_error(
-                ParamError::InvalidWlBuffer as u32,
-                "create_immed resulted in an invalid buffer.".into(),
+                "Dmabuf params were neither used nor destroyed by the client";
+                "pending_planes" => self.pending_planes.len()
             );
         }
     }
 }
 
+/// Assemble the [`Dmabuf`] out of the planes submitted by a client
+///
+/// Clients may add planes in any order, the planes are identified by their `plane_idx`.
+fn build_dmabuf(
+    planes: Vec<Plane>,
+    width: i32,
+    height: i32,
+    format: Fourcc,
+    flags: DmabufFlags,
+) -> Option<Dmabuf> {
+    let mut buf = Dmabuf::builder((width, height), format, flags);
+    for plane in planes {
+        let plane_idx = plane.plane_idx;
+        let offset = plane.offset;
+        let stride = plane.stride;
+        let modi = plane.modifier;
+        buf.add_plane(plane.into_raw_fd(), plane_idx, offset, stride, modi);
+    }
+    buf.build()
+}
+
+/// Description of the layout of a dmabuf for logging
+///
+/// Includes the size of the memory backing every plane, as far as it can be queried.
+/// The description is only assembled, if the record is actually logged.
+struct DmabufDescription<'a>(&'a Dmabuf);
+
+impl fmt::Display for DmabufDescription<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let format = self.0.format();
+        write!(
+            f,
+            "{}x{} {} ({:?})",
+            self.0.width(),
+            self.0.height(),
+            format.code,
+            format.modifier
+        )?;
+        for plane in self.0.planes() {
+            write!(
+                f,
+                ", plane {}: offset {}, stride {}, fd size ",
+                plane.plane_idx(),
+                plane.offset(),
+                plane.stride()
+            )?;
+            match ::nix::sys::stat::fstat(plane.fd()) {
+                Ok(stat) => write!(f, "{}", stat.st_size)?,
+                Err(_) => f.write_str("unknown")?,
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Convert the flags submitted by a client
+///
+/// Unknown bits are already discarded by the protocol bindings, so only the combination of the
+/// known flags is checked. `BOTTOM_FIRST` only has a meaning for interlaced buffers.
+fn dmabuf_flags(flags: BufferFlags, log: &::slog::Logger) -> DmabufFlags {
+    let interlaced = flags.contains(BufferFlags::Interlaced);
+    let bottom_first = flags.contains(BufferFlags::BottomFirst);
+    if bottom_first && !interlaced {
+        debug!(
+            log,
+            "Ignoring bottom_first flag of a buffer, which is not interlaced"
+        );
+    }
+    DmabufFlags::empty()
+        .y_invert(flags.contains(BufferFlags::YInvert))
+        .interlaced(interlaced)
+        .bottom_first(bottom_first && interlaced)
+}
+
 fn buffer_basic_checks(
     formats: &[Format],
     pending_planes: &[Plane],
@@ -398,6 +1790,7 @@ fn buffer_basic_checks(
     format: Fourcc,
     width: i32,
     height: i32,
+    config: &GlobalConfig,
 ) -> bool {
     // protocol_checks:
     // This must be a known format
@@ -406,7 +1799,7 @@ fn buffer_basic_checks(
         None => {
             params.as_ref().post_error(
                 ParamError::InvalidFormat as u32,
-                format!("Format {:?}/{:x} is not supported.", format, format as u32),
+                format!("Format {}/{:#x} is not supported.", format, format as u32),
             );
             return false;
         }
@@ -419,12 +1812,158 @@ fn buffer_basic_checks(
         );
         return false;
     }
+    // The renderer must be able to handle the dimensions
+    if config.max_width.map(|max| width as u32 > max).unwrap_or(false)
+        || config.max_height.map(|max| height as u32 > max).unwrap_or(false)
+    {
+        params.as_ref().post_error(
+            ParamError::InvalidDimensions as u32,
+            format!(
+                "Dimensions ({},{}) exceed the maximum of ({},{}).",
+                width,
+                height,
+                config.max_width.unwrap_or(u32::MAX),
+                config.max_height.unwrap_or(u32::MAX)
+            ),
+        );
+        return false;
+    }
+    // All planes must use the same modifier
+    if let Some(first) = pending_planes.first() {
+        if pending_planes
+            .iter()
+            .any(|plane| plane.modifier != first.modifier)
+        {
+            params.as_ref().post_error(
+                ParamError::InvalidFormat as u32,
+                "All planes must use the same modifier.".into(),
+            );
+            return false;
+        }
+    }
+    // The implicit modifier may only be used, if it was advertised
+    if let Some(first) = pending_planes.first() {
+        if first.modifier == Modifier::Invalid
+            && config.implicit_modifiers == ImplicitModifierPolicy::RejectUnadvertised
+            && !formats.contains(&Format {
+                code: format,
+                modifier: Modifier::Invalid,
+            })
+        {
+            params.as_ref().post_error(
+                ParamError::InvalidFormat as u32,
+                format!("The implicit modifier is not supported for format {:?}.", format),
+            );
+            return false;
+        }
+    }
+    // The planes must not leave any gaps, their indices are unique and at most as many as planes
+    if let Some(missing) = (0..pending_planes.len() as u32)
+        .find(|idx| !pending_planes.iter().any(|plane| plane.plane_idx == *idx))
+    {
+        params.as_ref().post_error(
+            ParamError::Incomplete as u32,
+            format!("Plane {} of format {:?} is missing.", missing, format),
+        );
+        return false;
+    }
+    // The device importing the buffer knows the exact number of planes
+    let device_planes = pending_planes.first().and_then(|first| {
+        let query = config.plane_count.as_ref()?;
+        query(Format {
+            code: format,
+            modifier: first.modifier,
+        })
+    });
+    if let Some(expected) = device_planes {
+        if pending_planes.len() != expected as usize {
+            params.as_ref().post_error(
+                ParamError::Incomplete as u32,
+                format!(
+                    "Format {:?} with modifier {:?} requires {} planes, got {}.",
+                    format,
+                    pending_planes[0].modifier,
+                    expected,
+                    pending_planes.len()
+                ),
+            );
+            return false;
+        }
+    }
+    // The number of planes must match the format, modifiers may add auxiliary planes
+    if let (Some(expected), Some(first), None) = (format.plane_count(), pending_planes.first(), device_planes)
+    {
+        let explicit_modifier = first.modifier != Modifier::Invalid && first.modifier != Modifier::Linear;
+        if pending_planes.len() < expected || (pending_planes.len() > expected && !explicit_modifier) {
+            params.as_ref().post_error(
+                ParamError::Incomplete as u32,
+                format!(
+                    "Format {:?} requires {} planes, got {}.",
+                    format,
+                    expected,
+                    pending_planes.len()
+                ),
+            );
+            return false;
+        }
+    }
+    // the size of every file is only queried once, as planes may share their file
+    let (file_sizes, plane_files) = if config.check_plane_sizes {
+        plane_files(pending_planes)
+    } else {
+        (Vec::new(), Vec::new())
+    };
+    // the planes must at least be large enough to hold a tightly packed linear buffer
+    if let Some(first) = pending_planes.first().filter(|_| config.check_plane_sizes) {
+        let min_size = Format {
+            code: format,
+            modifier: first.modifier,
+        }
+        .min_buffer_size(width as u32, height as u32);
+        // memory of a file shared by several planes is only available once,
+        // starting at the lowest offset of those planes
+        let available = file_sizes
+            .iter()
+            .enumerate()
+            .map(|(file, size)| {
+                let offset = pending_planes
+                    .iter()
+                    .zip(&plane_files)
+                    .filter(|(_, plane_file)| **plane_file == file)
+                    .map(|(plane, _)| plane.offset as u64)
+                    .min()
+                    .unwrap_or(0);
+                size.map(|size| size.saturating_sub(offset))
+            })
+            .try_fold(0u64, |sum, size| size.map(|size| sum.saturating_add(size)));
+        if let (Some(min_size), Some(available)) = (min_size, available) {
+            if available < min_size as u64 {
+                params.as_ref().post_error(
+                    ParamError::OutOfBounds as u32,
+                    format!(
+                        "A {}x{} buffer of format {:?} requires at least {} bytes, the planes provide {}.",
+                        width, height, format, min_size, available
+                    ),
+                );
+                return false;
+            }
+        }
+    }
     // check the size of each plane buffer
-    for plane in pending_planes {
+    for (idx, plane) in pending_planes.iter().enumerate() {
+        // every row needs some memory, whatever the layout of the modifier
+        if plane.stride == 0 {
+            params.as_ref().post_error(
+                ParamError::OutOfBounds as u32,
+                format!("Stride of plane {} is zero.", plane.plane_idx),
+            );
+            return false;
+        }
+        let rows = plane_height(format, plane.plane_idx, height as u32);
         // check size for overflow
         let end = match plane
             .stride
-            .checked_mul(height as u32)
+            .checked_mul(rows.unwrap_or(height as u32))
             .and_then(|o| o.checked_add(plane.offset))
         {
             None => {
@@ -436,17 +1975,40 @@ fn buffer_basic_checks(
             }
             Some(e) => e,
         };
-        if let Ok(size) = ::nix::unistd::lseek(plane.fd.unwrap(), 0, ::nix::unistd::Whence::SeekEnd) {
-            // reset the seek point
-            let _ = ::nix::unistd::lseek(plane.fd.unwrap(), 0, ::nix::unistd::Whence::SeekSet);
-            if plane.offset as libc::off_t > size {
+        // a row must fit into the stride, the layout of other modifiers is defined by the driver
+        if plane.modifier == Modifier::Linear || plane.modifier == Modifier::Invalid {
+            if let Some(min_stride) = min_stride(format, plane.plane_idx, width as u32) {
+                if (plane.stride as u64) < min_stride {
+                    params.as_ref().post_error(
+                        ParamError::OutOfBounds as u32,
+                        format!(
+                            "Stride {} of plane {} is smaller than a row of {} bytes.",
+                            plane.stride, plane.plane_idx, min_stride
+                        ),
+                    );
+                    return false;
+                }
+            }
+        }
+        if config.strict_fd_check && !is_dmabuf_fd(plane.fd.unwrap()) {
+            params.as_ref().post_error(
+                ParamError::OutOfBounds as u32,
+                format!("The fd of plane {} is not a dma-buf.", plane.plane_idx),
+            );
+            return false;
+        }
+        if !config.check_plane_sizes {
+            continue;
+        }
+        if let Some(size) = file_sizes[plane_files[idx]] {
+            if plane.offset as u64 > size {
                 params.as_ref().post_error(
                     ParamError::OutOfBounds as u32,
                     format!("Invalid offset {} for plane {}.", plane.offset, plane.plane_idx),
                 );
                 return false;
             }
-            if (plane.offset + plane.stride) as libc::off_t > size {
+            if plane.offset as u64 + plane.stride as u64 > size {
                 params.as_ref().post_error(
                     ParamError::OutOfBounds as u32,
                     format!("Invalid stride {} for plane {}.", plane.stride, plane.plane_idx),
@@ -454,18 +2016,1599 @@ fn buffer_basic_checks(
                 return false;
             }
             // Planes > 0 can be subsampled, in which case 'size' will be smaller
-            // than expected.
-            if plane.plane_idx == 0 && end as libc::off_t > size {
-                params.as_ref().post_error(
-                    ParamError::OutOfBounds as u32,
-                    format!(
-                        "Invalid stride ({}) or height ({}) for plane {}.",
-                        plane.stride, height, plane.plane_idx
-                    ),
-                );
-                return false;
+            // than expected. This is only checked, if the layout of the format is known.
+            if let Some(rows) = rows {
+                if end as u64 > size {
+                    params.as_ref().post_error(
+                        ParamError::OutOfBounds as u32,
+                        format!(
+                            "Invalid stride ({}) or height ({}) for plane {}.",
+                            plane.stride, rows, plane.plane_idx
+                        ),
+                    );
+                    return false;
+                }
             }
         }
     }
     true
 }
+
+/// Number of rows of the plane `plane_idx` of a buffer of `format`
+///
+/// Returns `None` if the format is unknown and the plane may be subsampled.
+fn plane_height(format: Fourcc, plane_idx: u32, height: u32) -> Option<u32> {
+    match FormatInfo::for_fourcc(format) {
+        Some(info) => info.plane_height(plane_idx as usize, height),
+        // the first plane is never subsampled
+        None if plane_idx == 0 => Some(height),
+        None => None,
+    }
+}
+
+/// Minimum stride of the plane `plane_idx` of a linear buffer of `format`
+///
+/// Returns `None` if the layout of the format is unknown or it has no such plane.
+fn min_stride(format: Fourcc, plane_idx: u32, width: u32) -> Option<u64> {
+    let info = FormatInfo::for_fourcc(format)?;
+    let plane = plane_idx as usize;
+    Some(info.plane_width(plane, width)? as u64 * info.bytes_per_pixel(plane)? as u64)
+}
+
+/// Sizes of the distinct files backing `planes` and the index of the file of every plane
+///
+/// Like [`DmabufId`](crate::backend::allocator::dmabuf::DmabufId), files are told apart by
+/// their inode, so planes sharing a file through different fds are backed by the same entry.
+fn plane_files(planes: &[Plane]) -> (Vec<Option<u64>>, Vec<usize>) {
+    let mut files = Vec::new();
+    let mut sizes = Vec::new();
+    let plane_files = planes
+        .iter()
+        .map(|plane| {
+            let fd = plane.fd.unwrap();
+            let file = match ::nix::sys::stat::fstat(fd) {
+                Ok(stat) => (stat.st_dev, stat.st_ino),
+                // no real device uses this number
+                Err(_) => (u64::MAX, fd as u64),
+            };
+            match files.iter().position(|other| *other == file) {
+                Some(idx) => idx,
+                None => {
+                    files.push(file);
+                    sizes.push(fd_size(fd));
+                    files.len() - 1
+                }
+            }
+        })
+        .collect();
+    (sizes, plane_files)
+}
+
+/// Size of the file referred to by `fd`, as reported by `lseek`
+fn fd_size(fd: RawFd) -> Option<u64> {
+    let size = ::nix::unistd::lseek(fd, 0, ::nix::unistd::Whence::SeekEnd).ok()?;
+    // reset the seek point
+    let _ = ::nix::unistd::lseek(fd, 0, ::nix::unistd::Whence::SeekSet);
+    Some(size as u64)
+}
+
+/// Check if `fd` refers to a dma-buf
+///
+/// dma-bufs live on their own pseudo filesystem since linux 5.3, older kernels used anonymous
+/// inodes instead. Their size must also be queryable via `lseek`.
+fn is_dmabuf_fd(fd: RawFd) -> bool {
+    const DMA_BUF_MAGIC: u64 = 0x444d_4142;
+    const ANON_INODE_FS_MAGIC: u64 = 0x0904_1934;
+
+    let fs_type = match ::nix::sys::statfs::fstatfs(&fd) {
+        Ok(stat) => stat.filesystem_type().0 as u64,
+        Err(_) => return false,
+    };
+    if fs_type != DMA_BUF_MAGIC && fs_type != ANON_INODE_FS_MAGIC {
+        return false;
+    }
+    match ::nix::unistd::lseek(fd, 0, ::nix::unistd::Whence::SeekEnd) {
+        Ok(_) => {
+            let _ = ::nix::unistd::lseek(fd, 0, ::nix::unistd::Whence::SeekSet);
+            true
+        }
+        Err(_) => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::test_util::TestClient;
+    use super::*;
+    use crate::backend::allocator::Buffer;
+    use std::os::unix::io::AsRawFd;
+    use wayland_client::{protocol::wl_buffer::WlBuffer as ClientBuffer, Main};
+    use wayland_protocols::unstable::linux_dmabuf::v1::client::zwp_linux_buffer_params_v1::Flags as ClientBufferFlags;
+
+    impl TestClient {
+        /// Submit a buffer consisting of the given planes to the dmabuf global
+        ///
+        /// Returns the code of the protocol error posted by the server, if any.
+        fn submit_buffer(
+            &mut self,
+            display: &mut Display,
+            format: Fourcc,
+            planes: &[(u32, Modifier)],
+        ) -> Option<u32> {
+            self.submit(display, format, planes, 256, false)
+        }
+
+        /// Submit a buffer using either `create` or `create_immed`
+        ///
+        /// All planes use the given `stride` and are backed by a file of 64 rows of 256 bytes.
+        fn submit(
+            &mut self,
+            display: &mut Display,
+            format: Fourcc,
+            planes: &[(u32, Modifier)],
+            stride: u32,
+            immed: bool,
+        ) -> Option<u32> {
+            use wayland_protocols::unstable::linux_dmabuf::v1::client::{
+                zwp_linux_buffer_params_v1::Flags, zwp_linux_dmabuf_v1::ZwpLinuxDmabufV1,
+            };
+
+            let dmabuf = self.globals.instantiate_exact::<ZwpLinuxDmabufV1>(3).unwrap();
+            let params = dmabuf.create_params();
+            params.quick_assign(|_, _, _| {});
+            for &(plane_idx, modifier) in planes {
+                let file = tempfile::tempfile().unwrap();
+                file.set_len(64 * 256).unwrap();
+                let modifier: u64 = modifier.into();
+                params.add(
+                    file.as_raw_fd(),
+                    plane_idx,
+                    0,
+                    stride,
+                    (modifier >> 32) as u32,
+                    modifier as u32,
+                );
+            }
+            if immed {
+                params.create_immed(64, 64, format as u32, Flags::empty());
+            } else {
+                params.create(64, 64, format as u32, Flags::empty());
+            }
+            self.roundtrip(display);
+
+            self.display.protocol_error().map(|err| err.code)
+        }
+
+        /// Create an `Argb8888` buffer with a single plane using `create_immed`
+        ///
+        /// The global has to support the format with the linear modifier and accept the buffer.
+        /// Returns the server side of the buffer.
+        fn create_buffer(&mut self, display: &mut Display) -> wl_buffer::WlBuffer {
+            self.create_client_buffer(display, ClientBufferFlags::empty()).1
+        }
+
+        /// Create a buffer like [`create_buffer`](TestClient::create_buffer) with the given `flags`
+        ///
+        /// Returns both the client and the server side of the buffer.
+        fn create_client_buffer(
+            &mut self,
+            display: &mut Display,
+            flags: ClientBufferFlags,
+        ) -> (Main<ClientBuffer>, wl_buffer::WlBuffer) {
+            use wayland_protocols::unstable::linux_dmabuf::v1::client::zwp_linux_dmabuf_v1::ZwpLinuxDmabufV1;
+
+            let dmabuf = self.globals.instantiate_exact::<ZwpLinuxDmabufV1>(3).unwrap();
+            let params = dmabuf.create_params();
+            let file = tempfile::tempfile().unwrap();
+            file.set_len(64 * 256).unwrap();
+            params.add(file.as_raw_fd(), 0, 0, 256, 0, 0);
+            let buffer = params.create_immed(64, 64, Fourcc::Argb8888 as u32, flags);
+            self.roundtrip(display);
+            let server_buffer = self
+                .client
+                .get_resource::<wl_buffer::WlBuffer>(buffer.as_ref().id())
+                .unwrap();
+            (buffer, server_buffer)
+        }
+
+        /// Submit a buffer with a single plane backed by the write end of a pipe
+        ///
+        /// The client closes its write end right away, so the server holds the only one afterwards,
+        /// see [`write_end_open`]. Returns the read end of the pipe, the client side of the `wl_buffer`,
+        /// if it was created, and the code of the protocol error posted by the server, if any.
+        fn submit_pipe(
+            &mut self,
+            display: &mut Display,
+            format: Fourcc,
+            immed: bool,
+        ) -> (RawFd, Option<Main<ClientBuffer>>, Option<u32>) {
+            use nix::fcntl::{fcntl, FcntlArg, OFlag};
+            use wayland_protocols::unstable::linux_dmabuf::v1::client::{
+                zwp_linux_buffer_params_v1::{Event, Flags},
+                zwp_linux_dmabuf_v1::ZwpLinuxDmabufV1,
+            };
+
+            let (read, write) = nix::unistd::pipe().unwrap();
+            fcntl(read, FcntlArg::F_SETFL(OFlag::O_NONBLOCK)).unwrap();
+
+            let created = Rc::new(RefCell::new(None));
+            let created_clone = created.clone();
+            let dmabuf = self.globals.instantiate_exact::<ZwpLinuxDmabufV1>(3).unwrap();
+            let params = dmabuf.create_params();
+            params.quick_assign(move |_, event, _| {
+                if let Event::Created { buffer } = event {
+                    *created_clone.borrow_mut() = Some(buffer);
+                }
+            });
+            params.add(write, 0, 0, 256, 0, 0);
+            nix::unistd::close(write).unwrap();
+            let immed_buffer = if immed {
+                Some(params.create_immed(64, 64, format as u32, Flags::empty()))
+            } else {
+                params.create(64, 64, format as u32, Flags::empty());
+                None
+            };
+            self.roundtrip(display);
+
+            let error = self.display.protocol_error().map(|err| err.code);
+            let buffer = match error {
+                Some(_) => None,
+                None => immed_buffer.or_else(|| created.borrow_mut().take()),
+            };
+            (read, buffer, error)
+        }
+
+        fn has_global(&self, interface: &str) -> bool {
+            self.globals.list().iter().any(|(_, name, _)| name == interface)
+        }
+    }
+
+    #[test]
+    fn destroyed_global_is_not_advertised() {
+        let mut display = Display::new();
+        let (_state, global) = init_dmabuf_global(&mut display, Vec::new(), |_, _| Ok(()), None);
+        assert!(TestClient::new(&mut display)
+            .unwrap()
+            .has_global("zwp_linux_dmabuf_v1"));
+
+        global.destroy();
+        assert!(!TestClient::new(&mut display)
+            .unwrap()
+            .has_global("zwp_linux_dmabuf_v1"));
+    }
+
+    #[test]
+    fn bind_callback_receives_version() {
+        use wayland_protocols::unstable::linux_dmabuf::v1::client::zwp_linux_dmabuf_v1::ZwpLinuxDmabufV1;
+
+        let mut display = Display::new();
+        let binds = Rc::new(RefCell::new(Vec::new()));
+        let binds_clone = binds.clone();
+        let _ = DmabufGlobalBuilder::new()
+            .on_bind(move |client, version| binds_clone.borrow_mut().push((client.clone(), version)))
+            .build(&mut display, |_, _| Ok(()));
+
+        let mut client = TestClient::new(&mut display).unwrap();
+        assert!(binds.borrow().is_empty());
+        let _dmabuf = client.globals.instantiate_exact::<ZwpLinuxDmabufV1>(3).unwrap();
+        client.roundtrip(&mut display);
+
+        let binds = binds.borrow();
+        assert_eq!(binds.len(), 1);
+        assert!(binds[0].0.equals(&client.client));
+        assert_eq!(binds[0].1, 3);
+    }
+
+    #[test]
+    fn per_device_tranches() {
+        use super::protocol::client::{
+            zwp_linux_dmabuf_feedback_v1::Event, zwp_linux_dmabuf_v1::ZwpLinuxDmabufV1,
+        };
+
+        let format = |code, modifier| Format { code, modifier };
+        let render = libc::makedev(226, 128);
+        let display_gpu = libc::makedev(226, 129);
+        let feedback = DmabufFeedbackBuilder::new(
+            render,
+            vec![
+                format(Fourcc::Argb8888, Modifier::Linear),
+                format(Fourcc::Xrgb8888, Modifier::Linear),
+                format(Fourcc::Argb8888, Modifier::I915_y_tiled),
+            ],
+        )
+        .add_preference_tranches(vec![
+            (
+                render,
+                vec![format(Fourcc::Argb8888, Modifier::I915_y_tiled)],
+                TrancheFlags::empty(),
+            ),
+            (
+                display_gpu,
+                vec![
+                    format(Fourcc::Xrgb8888, Modifier::Linear),
+                    format(Fourcc::Argb8888, Modifier::Linear),
+                ],
+                TrancheFlags::Scanout,
+            ),
+        ])
+        // the devices of the test do not exist
+        .build_with_devices(|_| true)
+        .unwrap();
+
+        let mut display = Display::new();
+        let _ = init_dmabuf_global_with_feedback(&mut display, &feedback, |_, _| Ok(()), None);
+        let mut client = TestClient::new(&mut display).unwrap();
+        let dmabuf = client.globals.instantiate_exact::<ZwpLinuxDmabufV1>(4).unwrap();
+        let events = Rc::new(RefCell::new(Vec::new()));
+        let events_clone = events.clone();
+        dmabuf.get_default_feedback().quick_assign(move |_, event, _| {
+            let event = match event {
+                Event::TrancheTargetDevice { device } => format!("device {:?}", device),
+                Event::TrancheFlags { flags } => format!("flags {}", flags.bits()),
+                Event::TrancheFormats { indices } => format!(
+                    "formats {:?}",
+                    indices
+                        .chunks(2)
+                        .map(|idx| u16::from_ne_bytes([idx[0], idx[1]]))
+                        .collect::<Vec<_>>()
+                ),
+                Event::TrancheDone => "tranche_done".into(),
+                Event::Done => "done".into(),
+                _ => return,
+            };
+            events_clone.borrow_mut().push(event);
+        });
+        client.roundtrip(&mut display);
+
+        let device = |dev: libc::dev_t| format!("device {:?}", dev.to_ne_bytes().to_vec());
+        let tranche = |dev, flags: TrancheFlags, formats: &str| {
+            vec![
+                device(dev),
+                format!("flags {}", flags.bits()),
+                format!("formats {}", formats),
+                "tranche_done".to_string(),
+            ]
+        };
+        let mut expected = tranche(render, TrancheFlags::empty(), "[2]");
+        expected.extend(tranche(display_gpu, TrancheFlags::Scanout, "[1, 0]"));
+        expected.extend(tranche(render, TrancheFlags::empty(), "[0, 1, 2]"));
+        expected.push("done".into());
+        assert_eq!(*events.borrow(), expected);
+    }
+
+    #[test]
+    fn updated_feedback_is_resent() {
+        use super::protocol::client::{
+            zwp_linux_dmabuf_feedback_v1::Event, zwp_linux_dmabuf_v1::ZwpLinuxDmabufV1,
+        };
+
+        let mut display = Display::new();
+        let formats = |code| {
+            vec![Format {
+                code,
+                modifier: Modifier::Linear,
+            }]
+        };
+        let (state, _global) = init_dmabuf_global_with_feedback(
+            &mut display,
+            &DmabufFeedback::new(libc::makedev(226, 128), formats(Fourcc::Argb8888)),
+            |_, _| Ok(()),
+            None,
+        );
+
+        let mut client = TestClient::new(&mut display).unwrap();
+        let dmabuf = client.globals.instantiate_exact::<ZwpLinuxDmabufV1>(4).unwrap();
+        // main device received by every feedback object with every `done` event
+        let received = Rc::new(RefCell::new(Vec::new()));
+        let feedbacks = (0..2)
+            .map(|idx| {
+                let feedback = dmabuf.get_default_feedback();
+                let received = received.clone();
+                let mut main_device = Vec::new();
+                feedback.quick_assign(move |_, event, _| match event {
+                    Event::MainDevice { device } => main_device = device,
+                    Event::Done => received.borrow_mut().push((idx, main_device.clone())),
+                    _ => {}
+                });
+                feedback
+            })
+            .collect::<Vec<_>>();
+        client.roundtrip(&mut display);
+
+        let device = |dev: libc::dev_t| dev.to_ne_bytes().to_vec();
+        assert_eq!(
+            *received.borrow(),
+            vec![
+                (0, device(libc::makedev(226, 128))),
+                (1, device(libc::makedev(226, 128)))
+            ]
+        );
+
+        received.borrow_mut().clear();
+        state.update_feedback(DmabufFeedback::new(
+            libc::makedev(226, 129),
+            formats(Fourcc::Xrgb8888),
+        ));
+        display.flush_clients(&mut ());
+        client.roundtrip(&mut display);
+
+        assert_eq!(
+            *received.borrow(),
+            vec![
+                (0, device(libc::makedev(226, 129))),
+                (1, device(libc::makedev(226, 129)))
+            ]
+        );
+        assert_eq!(&*state.formats(), &formats(Fourcc::Xrgb8888)[..]);
+
+        // destroyed feedback objects are no longer tracked
+        assert_eq!(state.inner.feedback_instances.borrow().len(), 2);
+        feedbacks[0].destroy();
+        client.roundtrip(&mut display);
+        assert_eq!(state.inner.feedback_instances.borrow().len(), 1);
+        assert_eq!(
+            state.inner.feedback_instances.borrow()[0].0.as_ref().id(),
+            feedbacks[1].as_ref().id()
+        );
+    }
+
+    #[test]
+    fn supported_formats() {
+        let mut display = Display::new();
+        let format = |code, modifier| Format { code, modifier };
+        let (state, _global) = init_dmabuf_global(
+            &mut display,
+            vec![format(Fourcc::Argb8888, Modifier::Linear)],
+            |_, _| Ok(()),
+            None,
+        );
+
+        assert_eq!(&*state.formats(), &[format(Fourcc::Argb8888, Modifier::Linear)]);
+        assert!(state.is_format_supported(&format(Fourcc::Argb8888, Modifier::Linear)));
+        assert!(!state.is_format_supported(&format(Fourcc::Argb8888, Modifier::Invalid)));
+        assert!(!state.is_format_supported(&format(Fourcc::Xrgb8888, Modifier::Linear)));
+
+        state.set_formats(vec![format(Fourcc::Xrgb8888, Modifier::Linear)]);
+        assert!(!state.is_format_supported(&format(Fourcc::Argb8888, Modifier::Linear)));
+        assert!(state.is_format_supported(&format(Fourcc::Xrgb8888, Modifier::Linear)));
+    }
+
+    #[test]
+    fn advertised_formats() {
+        use wayland_protocols::unstable::linux_dmabuf::v1::client::zwp_linux_dmabuf_v1::{
+            Event, ZwpLinuxDmabufV1,
+        };
+
+        let mut display = Display::new();
+        let format = |code, modifier| Format { code, modifier };
+        let formats = vec![
+            format(Fourcc::Argb8888, Modifier::Linear),
+            format(Fourcc::Argb8888, Modifier::Invalid),
+            format(Fourcc::Xrgb8888, Modifier::Invalid),
+            format(Fourcc::Argb8888, Modifier::Linear),
+        ];
+        let _ = init_dmabuf_global(&mut display, formats, |_, _| Ok(()), None);
+
+        let mut client = TestClient::new(&mut display).unwrap();
+        let events = Rc::new(RefCell::new(Vec::new()));
+        let events_clone = events.clone();
+        client
+            .globals
+            .instantiate_exact::<ZwpLinuxDmabufV1>(3)
+            .unwrap()
+            .quick_assign(move |_, event, _| events_clone.borrow_mut().push(event));
+        client.roundtrip(&mut display);
+
+        let events = events
+            .borrow()
+            .iter()
+            .map(|event| match *event {
+                Event::Format { format } => (format, None),
+                Event::Modifier {
+                    format,
+                    modifier_hi,
+                    modifier_lo,
+                } => (format, Some(((modifier_hi as u64) << 32) | modifier_lo as u64)),
+                _ => unreachable!(),
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            events,
+            vec![
+                (Fourcc::Argb8888 as u32, None),
+                (Fourcc::Argb8888 as u32, Some(0)),
+                (Fourcc::Xrgb8888 as u32, None),
+            ]
+        );
+    }
+
+    #[test]
+    fn custom_format_advertiser() {
+        use wayland_protocols::unstable::linux_dmabuf::v1::client::zwp_linux_dmabuf_v1::{
+            Event, ZwpLinuxDmabufV1,
+        };
+
+        let mut display = Display::new();
+        let format = |code| Format {
+            code,
+            modifier: Modifier::Invalid,
+        };
+        let advertised = Rc::new(RefCell::new(Vec::new()));
+        let advertised_clone = advertised.clone();
+        let _ = DmabufGlobalBuilder::new()
+            .formats(vec![format(Fourcc::Argb8888), format(Fourcc::Xrgb8888)])
+            .format_advertiser(move |dmabuf, formats| {
+                advertised_clone.borrow_mut().push(formats.to_vec());
+                // only advertise the first format
+                send_formats(dmabuf, &formats[..1]);
+            })
+            .build(&mut display, |_, _| Ok(()));
+
+        let mut client = TestClient::new(&mut display).unwrap();
+        let events = Rc::new(RefCell::new(Vec::new()));
+        let events_clone = events.clone();
+        client
+            .globals
+            .instantiate_exact::<ZwpLinuxDmabufV1>(3)
+            .unwrap()
+            .quick_assign(move |_, event, _| events_clone.borrow_mut().push(event));
+        client.roundtrip(&mut display);
+
+        assert_eq!(
+            *advertised.borrow(),
+            vec![vec![format(Fourcc::Argb8888), format(Fourcc::Xrgb8888)]]
+        );
+        assert_eq!(events.borrow().len(), 1);
+        assert!(matches!(
+            events.borrow()[0],
+            Event::Format { format } if format == Fourcc::Argb8888 as u32
+        ));
+    }
+
+    /// Whether any write end of the pipe with the non-blocking `read` end is still open
+    ///
+    /// The read end of a pipe only reports the end of file once every write end was closed.
+    fn write_end_open(read: RawFd) -> bool {
+        nix::unistd::read(read, &mut [0u8]) != Ok(0)
+    }
+
+    #[test]
+    fn rejected_dmabuf_closes_fds() {
+        let mut display = Display::new();
+        let _ = init_dmabuf_global(
+            &mut display,
+            vec![
+                Format {
+                    code: Fourcc::Argb8888,
+                    modifier: Modifier::Linear,
+                },
+                Format {
+                    code: Fourcc::Xrgb8888,
+                    modifier: Modifier::Linear,
+                },
+            ],
+            |dmabuf, _| match dmabuf.format().code {
+                Fourcc::Argb8888 => Ok(()),
+                _ => Err(ImportError::Failed),
+            },
+            None,
+        );
+
+        for _ in 0..32 {
+            // rejected by the handler
+            let (read, _, error) =
+                TestClient::new(&mut display)
+                    .unwrap()
+                    .submit_pipe(&mut display, Fourcc::Xrgb8888, true);
+            assert_eq!(error, Some(ParamError::InvalidWlBuffer as u32));
+            assert!(!write_end_open(read));
+            nix::unistd::close(read).unwrap();
+
+            // rejected by the protocol checks, as the format is not supported
+            let (read, _, error) =
+                TestClient::new(&mut display)
+                    .unwrap()
+                    .submit_pipe(&mut display, Fourcc::Abgr8888, true);
+            assert_eq!(error, Some(ParamError::InvalidFormat as u32));
+            assert!(!write_end_open(read));
+            nix::unistd::close(read).unwrap();
+        }
+    }
+
+    #[test]
+    fn planes_added_out_of_order() {
+        let planes = (0..3)
+            .rev()
+            .map(|idx| Plane {
+                fd: Some(std::fs::File::open("/dev/null").unwrap().into_raw_fd()),
+                plane_idx: idx,
+                offset: idx * 1024,
+                stride: 256 >> idx,
+                modifier: Modifier::Linear,
+            })
+            .collect::<Vec<_>>();
+        let dmabuf = build_dmabuf(planes, 64, 64, Fourcc::Yuv420, DmabufFlags::empty()).unwrap();
+        assert_eq!(dmabuf.offsets().collect::<Vec<_>>(), vec![0, 1024, 2048]);
+        assert_eq!(dmabuf.strides().collect::<Vec<_>>(), vec![256, 128, 64]);
+    }
+
+    #[test]
+    fn regular_fd_is_not_a_dmabuf() {
+        let file = tempfile::tempfile().unwrap();
+        assert!(!is_dmabuf_fd(file.as_raw_fd()));
+    }
+
+    #[test]
+    fn plane_heights() {
+        // chroma planes of NV12 are half height
+        assert_eq!(plane_height(Fourcc::Nv12, 0, 1080), Some(1080));
+        assert_eq!(plane_height(Fourcc::Nv12, 1, 1080), Some(540));
+        // planes of NV24 are not subsampled
+        assert_eq!(plane_height(Fourcc::Nv24, 0, 1080), Some(1080));
+        assert_eq!(plane_height(Fourcc::Nv24, 1, 1080), Some(1080));
+        // unknown layout, only the first plane can be checked
+        assert_eq!(plane_height(Fourcc::Q401, 0, 1080), Some(1080));
+        assert_eq!(plane_height(Fourcc::Q401, 1, 1080), None);
+    }
+
+    #[test]
+    fn mixed_modifiers_are_rejected() {
+        let mut display = Display::new();
+        let formats = [Modifier::Linear, Modifier::Invalid]
+            .iter()
+            .map(|&modifier| Format {
+                code: Fourcc::Nv12,
+                modifier,
+            })
+            .collect();
+        let _ = init_dmabuf_global(&mut display, formats, |_, _| Ok(()), None);
+
+        let mut client = TestClient::new(&mut display).unwrap();
+        let error = client.submit_buffer(
+            &mut display,
+            Fourcc::Nv12,
+            &[(0, Modifier::Linear), (1, Modifier::Invalid)],
+        );
+        assert_eq!(error, Some(ParamError::InvalidFormat as u32));
+    }
+
+    #[test]
+    fn bottom_first_requires_interlaced() {
+        let log = crate::slog_or_fallback(None);
+        assert_eq!(
+            dmabuf_flags(BufferFlags::YInvert | BufferFlags::BottomFirst, &log),
+            DmabufFlags::Y_INVERT
+        );
+        assert_eq!(
+            dmabuf_flags(BufferFlags::Interlaced | BufferFlags::BottomFirst, &log),
+            DmabufFlags::INTERLACED | DmabufFlags::BOTTOM_FIRST
+        );
+    }
+
+    #[test]
+    fn modifier_halves() {
+        // DRM_FORMAT_MOD_INVALID only sets the lower 56 bits
+        assert_eq!(
+            Modifier::from(combine_modifier(0x00ff_ffff, u32::MAX)),
+            Modifier::Invalid
+        );
+        assert_eq!(combine_modifier(u32::MAX, u32::MAX), u64::MAX);
+        assert_eq!(combine_modifier(0, 0), u64::from(Modifier::Linear));
+        assert_eq!(
+            combine_modifier(0x0100_0000, 2),
+            u64::from(Modifier::I915_y_tiled)
+        );
+        assert_eq!(combine_modifier(0x8000_0000, 1), 0x8000_0000_0000_0001);
+    }
+
+    #[test]
+    fn flags_match_the_protocol() {
+        assert_eq!(DmabufFlags::Y_INVERT.bits(), BufferFlags::YInvert.bits());
+        assert_eq!(DmabufFlags::INTERLACED.bits(), BufferFlags::Interlaced.bits());
+        assert_eq!(DmabufFlags::BOTTOM_FIRST.bits(), BufferFlags::BottomFirst.bits());
+
+        let flags = DmabufFlags::empty().y_invert(true).interlaced(false);
+        assert_eq!(flags, DmabufFlags::Y_INVERT);
+        assert!(flags.is_y_inverted() && !flags.is_interlaced() && !flags.is_bottom_first());
+        assert_eq!(flags.y_invert(false), DmabufFlags::empty());
+    }
+
+    #[test]
+    fn wrong_plane_count_is_rejected() {
+        let mut display = Display::new();
+        let formats = vec![Format {
+            code: Fourcc::Nv12,
+            modifier: Modifier::Linear,
+        }];
+        let _ = init_dmabuf_global(&mut display, formats, |_, _| Ok(()), None);
+
+        let error = TestClient::new(&mut display).unwrap().submit_buffer(
+            &mut display,
+            Fourcc::Nv12,
+            &[(0, Modifier::Linear)],
+        );
+        assert_eq!(error, Some(ParamError::Incomplete as u32));
+
+        let error = TestClient::new(&mut display).unwrap().submit_buffer(
+            &mut display,
+            Fourcc::Nv12,
+            &[
+                (0, Modifier::Linear),
+                (1, Modifier::Linear),
+                (2, Modifier::Linear),
+            ],
+        );
+        assert_eq!(error, Some(ParamError::Incomplete as u32));
+    }
+
+    #[test]
+    fn missing_plane_is_rejected() {
+        let mut display = Display::new();
+        let formats = vec![Format {
+            code: Fourcc::Nv12,
+            modifier: Modifier::Linear,
+        }];
+        let _ = init_dmabuf_global(&mut display, formats, |_, _| Ok(()), None);
+
+        // two planes, as required by the format, but the second one uses the wrong index
+        for &immed in &[false, true] {
+            let error = TestClient::new(&mut display).unwrap().submit(
+                &mut display,
+                Fourcc::Nv12,
+                &[(0, Modifier::Linear), (2, Modifier::Linear)],
+                256,
+                immed,
+            );
+            assert_eq!(error, Some(ParamError::Incomplete as u32));
+        }
+
+        // the order the planes are added in does not matter
+        let error = TestClient::new(&mut display).unwrap().submit_buffer(
+            &mut display,
+            Fourcc::Nv12,
+            &[(1, Modifier::Linear), (0, Modifier::Linear)],
+        );
+        assert_eq!(error, None);
+    }
+
+    #[test]
+    fn params_cannot_be_reused() {
+        use wayland_protocols::unstable::linux_dmabuf::v1::client::{
+            zwp_linux_buffer_params_v1::Flags, zwp_linux_dmabuf_v1::ZwpLinuxDmabufV1,
+        };
+
+        let mut display = Display::new();
+        let formats = vec![Format {
+            code: Fourcc::Argb8888,
+            modifier: Modifier::Linear,
+        }];
+        let _ = init_dmabuf_global(&mut display, formats, |_, _| Ok(()), None);
+        let file = tempfile::tempfile().unwrap();
+        file.set_len(64 * 256).unwrap();
+
+        // add -> create -> add
+        let mut client = TestClient::new(&mut display).unwrap();
+        let dmabuf = client.globals.instantiate_exact::<ZwpLinuxDmabufV1>(3).unwrap();
+        let params = dmabuf.create_params();
+        params.quick_assign(|_, _, _| {});
+        params.add(file.as_raw_fd(), 0, 0, 256, 0, 0);
+        params.create(64, 64, Fourcc::Argb8888 as u32, Flags::empty());
+        client.roundtrip(&mut display);
+        assert!(client.display.protocol_error().is_none());
+        params.add(file.as_raw_fd(), 1, 0, 256, 0, 0);
+        client.roundtrip(&mut display);
+        assert_eq!(
+            client.display.protocol_error().map(|err| err.code),
+            Some(ParamError::AlreadyUsed as u32)
+        );
+
+        // create -> create
+        let mut client = TestClient::new(&mut display).unwrap();
+        let dmabuf = client.globals.instantiate_exact::<ZwpLinuxDmabufV1>(3).unwrap();
+        let params = dmabuf.create_params();
+        params.quick_assign(|_, _, _| {});
+        params.add(file.as_raw_fd(), 0, 0, 256, 0, 0);
+        params.create(64, 64, Fourcc::Argb8888 as u32, Flags::empty());
+        client.roundtrip(&mut display);
+        assert!(client.display.protocol_error().is_none());
+        params.create(64, 64, Fourcc::Argb8888 as u32, Flags::empty());
+        client.roundtrip(&mut display);
+        assert_eq!(
+            client.display.protocol_error().map(|err| err.code),
+            Some(ParamError::AlreadyUsed as u32)
+        );
+    }
+
+    #[test]
+    fn zero_stride() {
+        let mut display = Display::new();
+        let format = |modifier| Format {
+            code: Fourcc::Argb8888,
+            modifier,
+        };
+        let _ = init_dmabuf_global(
+            &mut display,
+            vec![format(Modifier::Linear), format(Modifier::I915_x_tiled)],
+            |_, _| Ok(()),
+            None,
+        );
+
+        // the minimum stride of tiled buffers is not known
+        for &modifier in &[Modifier::Linear, Modifier::I915_x_tiled] {
+            for &immed in &[false, true] {
+                let mut client = TestClient::new(&mut display).unwrap();
+                assert_eq!(
+                    client.submit(&mut display, Fourcc::Argb8888, &[(0, modifier)], 0, immed),
+                    Some(ParamError::OutOfBounds as u32)
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn stride_must_fit_a_row() {
+        let mut display = Display::new();
+        let format = |code| Format {
+            code,
+            modifier: Modifier::Linear,
+        };
+        let _ = init_dmabuf_global(
+            &mut display,
+            vec![format(Fourcc::Argb8888), format(Fourcc::Nv12)],
+            |_, _| Ok(()),
+            None,
+        );
+        let argb = [(0, Modifier::Linear)];
+        let nv12 = [(0, Modifier::Linear), (1, Modifier::Linear)];
+
+        let mut client = TestClient::new(&mut display).unwrap();
+        assert_eq!(
+            client.submit(&mut display, Fourcc::Argb8888, &argb, 255, false),
+            Some(ParamError::OutOfBounds as u32)
+        );
+        let mut client = TestClient::new(&mut display).unwrap();
+        assert_eq!(
+            client.submit(&mut display, Fourcc::Argb8888, &argb, 256, false),
+            None
+        );
+        // the luma plane of 64 bytes per row is the widest plane of nv12
+        let mut client = TestClient::new(&mut display).unwrap();
+        assert_eq!(
+            client.submit(&mut display, Fourcc::Nv12, &nv12, 63, false),
+            Some(ParamError::OutOfBounds as u32)
+        );
+        let mut client = TestClient::new(&mut display).unwrap();
+        assert_eq!(client.submit(&mut display, Fourcc::Nv12, &nv12, 64, false), None);
+    }
+
+    #[test]
+    fn handler_receives_dmabufs() {
+        let mut display = Display::new();
+        let formats = vec![Format {
+            code: Fourcc::Argb8888,
+            modifier: Modifier::Linear,
+        }];
+        let handler = TestDmabufHandler::new();
+        let _ = init_dmabuf_global(&mut display, formats, handler.handler(), None);
+
+        let mut client = TestClient::new(&mut display).unwrap();
+        let error = client.submit_buffer(&mut display, Fourcc::Argb8888, &[(0, Modifier::Linear)]);
+        assert_eq!(error, None);
+        handler.set_result(Err(ImportError::Failed));
+        // a failed `create` is reported to the client without killing it
+        let error = client.submit_buffer(&mut display, Fourcc::Argb8888, &[(0, Modifier::Linear)]);
+        assert_eq!(error, None);
+
+        let received = handler.received();
+        assert_eq!(received.len(), 2);
+        assert_eq!(Buffer::size(&received[0]), (64, 64).into());
+        assert_eq!(received[0].strides().collect::<Vec<_>>(), vec![256]);
+    }
+
+    #[test]
+    fn params_are_released_after_destroy() {
+        use wayland_protocols::unstable::linux_dmabuf::v1::client::{
+            zwp_linux_buffer_params_v1::Flags, zwp_linux_dmabuf_v1::ZwpLinuxDmabufV1,
+        };
+
+        let mut display = Display::new();
+        let formats = vec![Format {
+            code: Fourcc::Argb8888,
+            modifier: Modifier::Linear,
+        }];
+        let (state, _global) = init_dmabuf_global(&mut display, formats, |_, _| Ok(()), None);
+        let mut client = TestClient::new(&mut display).unwrap();
+        let dmabuf = client.globals.instantiate_exact::<ZwpLinuxDmabufV1>(3).unwrap();
+        client.roundtrip(&mut display);
+        // the handler of every params object holds a reference to the state
+        let references = Rc::strong_count(&state.inner);
+
+        let params = dmabuf.create_params();
+        params.quick_assign(|_, _, _| {});
+        let file = tempfile::tempfile().unwrap();
+        file.set_len(64 * 256).unwrap();
+        params.add(file.as_raw_fd(), 0, 0, 256, 0, 0);
+        params.create(64, 64, Fourcc::Argb8888 as u32, Flags::empty());
+        client.roundtrip(&mut display);
+        assert_eq!(Rc::strong_count(&state.inner), references + 1);
+
+        params.destroy();
+        client.roundtrip(&mut display);
+        assert_eq!(Rc::strong_count(&state.inner), references);
+        assert!(client.display.protocol_error().is_none());
+    }
+
+    #[test]
+    fn planes_sharing_a_file() {
+        use wayland_protocols::unstable::linux_dmabuf::v1::client::{
+            zwp_linux_buffer_params_v1::Flags, zwp_linux_dmabuf_v1::ZwpLinuxDmabufV1,
+        };
+
+        let mut display = Display::new();
+        let formats = vec![Format {
+            code: Fourcc::Nv12,
+            modifier: Modifier::Linear,
+        }];
+        let _ = init_dmabuf_global(&mut display, formats, |_, _| Ok(()), None);
+
+        // both planes of a 64x64 buffer in a single file, which needs 1.5 * 64 * 64 bytes
+        let mut submit = |file_size: u64, chroma_offset: u32| {
+            let mut client = TestClient::new(&mut display).unwrap();
+            let dmabuf = client.globals.instantiate_exact::<ZwpLinuxDmabufV1>(3).unwrap();
+            let params = dmabuf.create_params();
+            params.quick_assign(|_, _, _| {});
+            let file = tempfile::tempfile().unwrap();
+            file.set_len(file_size).unwrap();
+            params.add(file.as_raw_fd(), 0, 0, 64, 0, 0);
+            params.add(file.as_raw_fd(), 1, chroma_offset, 64, 0, 0);
+            params.create_immed(64, 64, Fourcc::Nv12 as u32, Flags::empty());
+            client.roundtrip(&mut display);
+            client.display.protocol_error().map(|err| err.code)
+        };
+
+        assert_eq!(submit(6144, 4096), None);
+        // overlapping planes in a file of about 1.3 * 64 * 64 bytes fit the file one by one,
+        // but the file is counted only once for the size of the whole buffer
+        assert_eq!(submit(5324, 3276), Some(ParamError::OutOfBounds as u32));
+    }
+
+    #[test]
+    fn create_and_create_immed_build_identical_dmabufs() {
+        let mut display = Display::new();
+        let formats = vec![Format {
+            code: Fourcc::Nv12,
+            modifier: Modifier::Linear,
+        }];
+        let handler = TestDmabufHandler::new();
+        let _ = init_dmabuf_global(&mut display, formats, handler.handler(), None);
+
+        // planes are added out of order
+        let planes = [(1, Modifier::Linear), (0, Modifier::Linear)];
+        for &immed in &[false, true] {
+            let error = TestClient::new(&mut display).unwrap().submit(
+                &mut display,
+                Fourcc::Nv12,
+                &planes,
+                256,
+                immed,
+            );
+            assert_eq!(error, None);
+        }
+
+        let received = handler.received();
+        assert_eq!(received.len(), 2);
+        let layout = |dmabuf: &Dmabuf| {
+            (
+                Buffer::size(dmabuf),
+                dmabuf.format(),
+                dmabuf.y_inverted(),
+                dmabuf
+                    .planes()
+                    .map(|plane| (plane.plane_idx(), plane.offset(), plane.stride()))
+                    .collect::<Vec<_>>(),
+            )
+        };
+        assert_eq!(layout(&received[0]), layout(&received[1]));
+        assert_eq!(layout(&received[0]).3, vec![(0, 0, 256), (1, 0, 256)]);
+    }
+
+    #[test]
+    fn y_inverted_buffer_flags() {
+        let mut display = Display::new();
+        let formats = vec![Format {
+            code: Fourcc::Argb8888,
+            modifier: Modifier::Linear,
+        }];
+        let _ = init_dmabuf_global(&mut display, formats, |_, _| Ok(()), None);
+
+        let mut client = TestClient::new(&mut display).unwrap();
+        let (_, buffer) = client.create_client_buffer(&mut display, ClientBufferFlags::YInvert);
+        let flags = get_dmabuf(&buffer).unwrap().flags();
+        assert_eq!(flags, DmabufFlags::Y_INVERT);
+        assert!(flags.is_y_inverted());
+    }
+
+    #[test]
+    fn destroyed_params_close_pending_planes() {
+        use nix::fcntl::{fcntl, FcntlArg, OFlag};
+        use wayland_protocols::unstable::linux_dmabuf::v1::client::zwp_linux_dmabuf_v1::ZwpLinuxDmabufV1;
+
+        let mut display = Display::new();
+        let _ = init_dmabuf_global(&mut display, Vec::new(), |_, _| Ok(()), None);
+        let mut client = TestClient::new(&mut display).unwrap();
+
+        // the read end of a pipe only reports the end of file once every write end was closed
+        let (read, write) = nix::unistd::pipe().unwrap();
+        fcntl(read, FcntlArg::F_SETFL(OFlag::O_NONBLOCK)).unwrap();
+        let write_end_open = || nix::unistd::read(read, &mut [0u8]) != Ok(0);
+
+        let dmabuf = client.globals.instantiate_exact::<ZwpLinuxDmabufV1>(3).unwrap();
+        let params = dmabuf.create_params();
+        params.quick_assign(|_, _, _| {});
+        params.add(write, 0, 0, 256, 0, 0);
+        nix::unistd::close(write).unwrap();
+        client.roundtrip(&mut display);
+        assert!(write_end_open());
+
+        params.destroy();
+        client.roundtrip(&mut display);
+        assert!(!write_end_open());
+        assert!(client.display.protocol_error().is_none());
+        nix::unistd::close(read).unwrap();
+    }
+
+    #[test]
+    fn plane_size_check_can_be_disabled() {
+        let planes = [(0, Modifier::Linear)];
+        for &check in &[true, false] {
+            let mut display = Display::new();
+            let _ = DmabufGlobalBuilder::new()
+                .formats(vec![Format {
+                    code: Fourcc::Argb8888,
+                    modifier: Modifier::Linear,
+                }])
+                .check_plane_sizes(check)
+                .build(&mut display, |_, _| Ok(()));
+
+            // 64 rows of 512 bytes exceed the size of the submitted file
+            let error = TestClient::new(&mut display).unwrap().submit(
+                &mut display,
+                Fourcc::Argb8888,
+                &planes,
+                512,
+                false,
+            );
+            let expected = if check {
+                Some(ParamError::OutOfBounds as u32)
+            } else {
+                None
+            };
+            assert_eq!(error, expected);
+        }
+    }
+
+    #[test]
+    fn dimensions_are_limited() {
+        let planes = [(0, Modifier::Linear)];
+        // the submitted buffers are 64x64
+        for &(max_width, max_height, expected) in &[
+            (64, 64, None),
+            (63, 64, Some(ParamError::InvalidDimensions as u32)),
+            (64, 63, Some(ParamError::InvalidDimensions as u32)),
+        ] {
+            let mut display = Display::new();
+            let _ = DmabufGlobalBuilder::new()
+                .formats(vec![Format {
+                    code: Fourcc::Argb8888,
+                    modifier: Modifier::Linear,
+                }])
+                .max_width(max_width)
+                .max_height(max_height)
+                .build(&mut display, |_, _| Ok(()));
+
+            let error =
+                TestClient::new(&mut display)
+                    .unwrap()
+                    .submit_buffer(&mut display, Fourcc::Argb8888, &planes);
+            assert_eq!(error, expected);
+        }
+    }
+
+    #[test]
+    fn async_import() {
+        use wayland_protocols::unstable::linux_dmabuf::v1::client::{
+            zwp_linux_buffer_params_v1::{Event, Flags},
+            zwp_linux_dmabuf_v1::ZwpLinuxDmabufV1,
+        };
+
+        let mut display = Display::new();
+        let imports = Rc::new(RefCell::new(Vec::new()));
+        let imports_clone = imports.clone();
+        let _ = DmabufGlobalBuilder::new()
+            .formats(vec![Format {
+                code: Fourcc::Argb8888,
+                modifier: Modifier::Linear,
+            }])
+            .build_async(&mut display, move |import, _| {
+                imports_clone.borrow_mut().push(import)
+            });
+
+        let mut client = TestClient::new(&mut display).unwrap();
+        let dmabuf = client.globals.instantiate_exact::<ZwpLinuxDmabufV1>(3).unwrap();
+        let submit = |immed| {
+            let params = dmabuf.create_params();
+            let file = tempfile::tempfile().unwrap();
+            file.set_len(64 * 256).unwrap();
+            params.add(file.as_raw_fd(), 0, 0, 256, 0, 0);
+            if immed {
+                params.create_immed(64, 64, Fourcc::Argb8888 as u32, Flags::empty());
+            } else {
+                params.create(64, 64, Fourcc::Argb8888 as u32, Flags::empty());
+            }
+            params
+        };
+        let events = Rc::new(RefCell::new(Vec::new()));
+        let events_clone = events.clone();
+        submit(false).quick_assign(move |_, event, _| {
+            events_clone
+                .borrow_mut()
+                .push(matches!(event, Event::Created { .. }))
+        });
+        client.roundtrip(&mut display);
+
+        // the buffer is only created once the import completed
+        assert!(events.borrow().is_empty());
+        let import = imports.borrow_mut().remove(0);
+        assert_eq!(import.dmabuf().num_planes(), 1);
+        import.accept();
+        client.roundtrip(&mut display);
+        assert_eq!(*events.borrow(), vec![true]);
+
+        submit(true).quick_assign(|_, _, _| {});
+        client.roundtrip(&mut display);
+        assert!(client.display.protocol_error().is_none());
+        imports
+            .borrow_mut()
+            .remove(0)
+            .reject(ImportError::UnsupportedModifier);
+        client.roundtrip(&mut display);
+        assert_eq!(
+            client.display.protocol_error().map(|err| err.code),
+            Some(ParamError::InvalidWlBuffer as u32)
+        );
+    }
+
+    #[test]
+    fn deferred_immed_failure() {
+        use wayland_protocols::unstable::linux_dmabuf::v1::client::{
+            zwp_linux_buffer_params_v1::Flags, zwp_linux_dmabuf_v1::ZwpLinuxDmabufV1,
+        };
+
+        let mut display = Display::new();
+        let imports = Rc::new(RefCell::new(Vec::new()));
+        let imports_clone = imports.clone();
+        let _ = DmabufGlobalBuilder::new()
+            .formats(vec![Format {
+                code: Fourcc::Argb8888,
+                modifier: Modifier::Linear,
+            }])
+            .build_async(&mut display, move |import, _| {
+                imports_clone.borrow_mut().push(import)
+            });
+
+        let mut client = TestClient::new(&mut display).unwrap();
+        let dmabuf = client.globals.instantiate_exact::<ZwpLinuxDmabufV1>(3).unwrap();
+        let params = dmabuf.create_params();
+        let file = tempfile::tempfile().unwrap();
+        file.set_len(64 * 256).unwrap();
+        params.add(file.as_raw_fd(), 0, 0, 256, 0, 0);
+        let buffer = params.create_immed(64, 64, Fourcc::Argb8888 as u32, Flags::empty());
+        params.destroy();
+        client.roundtrip(&mut display);
+
+        // the buffer exists, but is not backed by a dmabuf until the import completed
+        let server_buffer = client
+            .client
+            .get_resource::<wl_buffer::WlBuffer>(buffer.as_ref().id())
+            .unwrap();
+        assert!(server_buffer.as_ref().is_alive());
+        assert!(get_dmabuf(&server_buffer).is_none());
+        assert!(client.display.protocol_error().is_none());
+
+        // dropping the pending import rejects it
+        imports.borrow_mut().clear();
+        client.roundtrip(&mut display);
+        assert_eq!(
+            client.display.protocol_error().map(|err| err.code),
+            Some(ParamError::InvalidWlBuffer as u32)
+        );
+    }
+
+    #[test]
+    fn device_plane_count() {
+        let formats = vec![
+            Format {
+                code: Fourcc::Argb8888,
+                modifier: Modifier::I915_x_tiled,
+            },
+            Format {
+                code: Fourcc::Argb8888,
+                modifier: Modifier::I915_y_tiled_ccs,
+            },
+            Format {
+                code: Fourcc::Argb8888,
+                modifier: Modifier::Linear,
+            },
+        ];
+
+        let mut display = Display::new();
+        let _ = DmabufGlobalBuilder::new()
+            .formats(formats)
+            .plane_count(|format| match format.modifier {
+                Modifier::I915_x_tiled => Some(1),
+                Modifier::I915_y_tiled_ccs => Some(2),
+                _ => None,
+            })
+            .build(&mut display, |_, _| Ok(()));
+
+        let cases = [
+            (vec![(0, Modifier::I915_x_tiled)], None),
+            // the generic check would allow auxiliary planes
+            (
+                vec![(0, Modifier::I915_x_tiled), (1, Modifier::I915_x_tiled)],
+                Some(ParamError::Incomplete as u32),
+            ),
+            (
+                vec![(0, Modifier::I915_y_tiled_ccs)],
+                Some(ParamError::Incomplete as u32),
+            ),
+            (
+                vec![(0, Modifier::I915_y_tiled_ccs), (1, Modifier::I915_y_tiled_ccs)],
+                None,
+            ),
+            // unknown to the device, falls back to the generic check
+            (vec![(0, Modifier::Linear)], None),
+            (
+                vec![(0, Modifier::Linear), (1, Modifier::Linear)],
+                Some(ParamError::Incomplete as u32),
+            ),
+        ];
+        for (planes, expected) in cases.iter() {
+            let error =
+                TestClient::new(&mut display)
+                    .unwrap()
+                    .submit_buffer(&mut display, Fourcc::Argb8888, planes);
+            assert_eq!(error, *expected, "{:?}", planes);
+        }
+    }
+
+    #[test]
+    fn memfd_round_trip() {
+        use crate::backend::allocator::{dmabuf::AsDmabuf, memfd::MemfdAllocator, Allocator};
+
+        let buffer = MemfdAllocator::new()
+            .create_buffer(64, 64, Fourcc::Nv12, &[Modifier::Linear])
+            .unwrap();
+        let dmabuf = buffer.export().unwrap();
+        let received = submit_dmabuf(&dmabuf).unwrap();
+        assert_eq!(received.describe(), dmabuf.describe());
+        assert_eq!(received.num_planes(), 2);
+    }
+
+    #[test]
+    fn unadvertised_implicit_modifier() {
+        let formats = vec![Format {
+            code: Fourcc::Argb8888,
+            modifier: Modifier::Linear,
+        }];
+        let planes = [(0, Modifier::Invalid)];
+
+        let mut display = Display::new();
+        let _ = DmabufGlobalBuilder::new()
+            .formats(formats.clone())
+            .build(&mut display, |_, _| Ok(()));
+        let error =
+            TestClient::new(&mut display)
+                .unwrap()
+                .submit_buffer(&mut display, Fourcc::Argb8888, &planes);
+        assert_eq!(error, None);
+
+        let mut display = Display::new();
+        let _ = DmabufGlobalBuilder::new()
+            .formats(formats)
+            .implicit_modifier_policy(ImplicitModifierPolicy::RejectUnadvertised)
+            .build(&mut display, |_, _| Ok(()));
+        let error =
+            TestClient::new(&mut display)
+                .unwrap()
+                .submit_buffer(&mut display, Fourcc::Argb8888, &planes);
+        assert_eq!(error, Some(ParamError::InvalidFormat as u32));
+    }
+
+    #[test]
+    fn destroyed_buffer_is_reported() {
+        let mut display = Display::new();
+        let destroyed = Rc::new(RefCell::new(Vec::new()));
+        let destroyed_clone = destroyed.clone();
+        let _ = DmabufGlobalBuilder::new()
+            .formats(vec![Format {
+                code: Fourcc::Argb8888,
+                modifier: Modifier::Linear,
+            }])
+            .buffer_destroyed(move |dmabuf, _| destroyed_clone.borrow_mut().push(dmabuf.clone()))
+            .build(&mut display, |_, _| Ok(()));
+
+        let mut client = TestClient::new(&mut display).unwrap();
+        let (buffer, _) = client.create_client_buffer(&mut display, ClientBufferFlags::empty());
+        assert!(destroyed.borrow().is_empty());
+
+        buffer.destroy();
+        client.roundtrip(&mut display);
+        assert_eq!(destroyed.borrow().len(), 1);
+    }
+
+    #[test]
+    fn metrics() {
+        let mut display = Display::new();
+        let outcomes = Rc::new(RefCell::new(Vec::new()));
+        let outcomes_clone = outcomes.clone();
+        let _ = DmabufGlobalBuilder::new()
+            .formats(vec![
+                Format {
+                    code: Fourcc::Argb8888,
+                    modifier: Modifier::Linear,
+                },
+                Format {
+                    code: Fourcc::Xrgb8888,
+                    modifier: Modifier::Linear,
+                },
+            ])
+            .metrics(move |format, outcome| outcomes_clone.borrow_mut().push((format, outcome)))
+            .build(&mut display, |dmabuf, _| match dmabuf.format().code {
+                Fourcc::Argb8888 => Ok(()),
+                _ => Err(ImportError::Failed),
+            });
+
+        let format = |code| Format {
+            code,
+            modifier: Modifier::Linear,
+        };
+        let mut client = TestClient::new(&mut display).unwrap();
+        for &(code, stride) in &[
+            (Fourcc::Argb8888, 256),
+            (Fourcc::Xrgb8888, 256),
+            (Fourcc::Argb8888, 0),
+        ] {
+            client.submit(&mut display, code, &[(0, Modifier::Linear)], stride, false);
+        }
+
+        assert_eq!(
+            *outcomes.borrow(),
+            vec![
+                (format(Fourcc::Argb8888), ImportOutcome::Created),
+                (
+                    format(Fourcc::Xrgb8888),
+                    ImportOutcome::Rejected(ImportError::Failed)
+                ),
+                (format(Fourcc::Argb8888), ImportOutcome::ProtocolError),
+            ]
+        );
+    }
+
+    #[test]
+    fn client_policy() {
+        let mut display = Display::new();
+        let trusted = Rc::new(RefCell::new(None::<Client>));
+        let trusted_clone = trusted.clone();
+        let imports = Rc::new(std::cell::Cell::new(0));
+        let imports_clone = imports.clone();
+        let _ = DmabufGlobalBuilder::new()
+            .formats(vec![Format {
+                code: Fourcc::Argb8888,
+                modifier: Modifier::Linear,
+            }])
+            .allow_client(move |client| trusted_clone.borrow().as_ref() == Some(client))
+            .build(&mut display, move |_, _| {
+                imports_clone.set(imports_clone.get() + 1);
+                Ok(())
+            });
+
+        let mut allowed = TestClient::new(&mut display).unwrap();
+        *trusted.borrow_mut() = Some(allowed.client.clone());
+        let mut denied = TestClient::new(&mut display).unwrap();
+
+        assert_eq!(
+            allowed.submit(
+                &mut display,
+                Fourcc::Argb8888,
+                &[(0, Modifier::Linear)],
+                256,
+                true
+            ),
+            None
+        );
+        assert_eq!(imports.get(), 1);
+        assert_eq!(
+            denied.submit(
+                &mut display,
+                Fourcc::Argb8888,
+                &[(0, Modifier::Linear)],
+                256,
+                true
+            ),
+            Some(ParamError::InvalidWlBuffer as u32)
+        );
+        assert_eq!(imports.get(), 1);
+    }
+
+    #[test]
+    fn user_data() {
+        #[derive(Debug, PartialEq)]
+        struct Texture(u32);
+
+        let mut display = Display::new();
+        let _ = DmabufGlobalBuilder::new()
+            .formats(vec![Format {
+                code: Fourcc::Argb8888,
+                modifier: Modifier::Linear,
+            }])
+            .build_with_data(&mut display, |_, _| Ok(1u32));
+        let mut client = TestClient::new(&mut display).unwrap();
+        let buffer = client.create_buffer(&mut display);
+
+        let user_data = buffer_user_data(&buffer).unwrap();
+        assert!(user_data.insert_if_missing(|| Texture(42)));
+        assert!(user_data.insert_if_missing_threadsafe(|| String::from("client buffer")));
+        assert!(!user_data.insert_if_missing(|| Texture(0)));
+
+        // all of the data attached to the buffer coexists
+        assert_eq!(get_dmabuf(&buffer).unwrap().format().code, Fourcc::Argb8888);
+        assert_eq!(get_buffer_data::<u32>(&buffer).as_deref(), Some(&1));
+        let user_data = buffer_user_data(&buffer).unwrap();
+        assert_eq!(user_data.get::<Texture>(), Some(&Texture(42)));
+        assert_eq!(user_data.get::<String>().unwrap(), "client buffer");
+    }
+
+    #[test]
+    fn fences() {
+        use std::os::unix::io::FromRawFd;
+
+        let mut display = Display::new();
+        let formats = vec![Format {
+            code: Fourcc::Argb8888,
+            modifier: Modifier::Linear,
+        }];
+        let _ = init_dmabuf_global(&mut display, formats, |_, _| Ok(()), None);
+        let mut client = TestClient::new(&mut display).unwrap();
+        let buffer = client.create_buffer(&mut display);
+
+        // any fd can stand in for a fence
+        let fence = || unsafe { OwnedFd::from_raw_fd(tempfile::tempfile().unwrap().into_raw_fd()) };
+        assert!(take_acquire_fence(&buffer).is_none());
+        let acquire = fence();
+        let acquire_fd = acquire.as_raw_fd();
+        assert!(set_acquire_fence(&buffer, acquire));
+        assert!(set_release_fence(&buffer, fence()));
+        assert_eq!(
+            take_acquire_fence(&buffer).map(|fd| fd.as_raw_fd()),
+            Some(acquire_fd)
+        );
+        assert!(take_acquire_fence(&buffer).is_none());
+        assert!(take_release_fence(&buffer).is_some());
+        assert!(take_release_fence(&buffer).is_none());
+    }
+
+    #[test]
+    fn sync_points() {
+        use std::os::unix::io::FromRawFd;
+
+        let mut display = Display::new();
+        let formats = vec![Format {
+            code: Fourcc::Argb8888,
+            modifier: Modifier::Linear,
+        }];
+        let _ = init_dmabuf_global(&mut display, formats, |_, _| Ok(()), None);
+        let mut client = TestClient::new(&mut display).unwrap();
+        let buffer = client.create_buffer(&mut display);
+
+        // any fd can stand in for a syncobj
+        let timeline = Arc::new(unsafe { OwnedFd::from_raw_fd(tempfile::tempfile().unwrap().into_raw_fd()) });
+        assert!(take_acquire_point(&buffer).is_none());
+        assert!(set_acquire_point(&buffer, SyncPoint::new(timeline.clone(), 1)));
+        assert!(set_acquire_point(&buffer, SyncPoint::new(timeline.clone(), 2)));
+        assert!(set_release_point(&buffer, SyncPoint::new(timeline.clone(), 3)));
+
+        let acquire = take_acquire_point(&buffer).unwrap();
+        assert!(Arc::ptr_eq(acquire.timeline(), &timeline));
+        assert_eq!(acquire.point(), 2);
+        assert!(take_acquire_point(&buffer).is_none());
+        assert_eq!(take_release_point(&buffer).map(|point| point.point()), Some(3));
+        assert!(take_release_point(&buffer).is_none());
+        // the points do not touch the fences
+        assert!(take_acquire_fence(&buffer).is_none());
+    }
+
+    #[test]
+    fn damage() {
+        let mut display = Display::new();
+        let formats = vec![Format {
+            code: Fourcc::Argb8888,
+            modifier: Modifier::Linear,
+        }];
+        let _ = init_dmabuf_global(&mut display, formats, |_, _| Ok(()), None);
+        let mut client = TestClient::new(&mut display).unwrap();
+        let buffer = client.create_buffer(&mut display);
+
+        let first = Rectangle::from_loc_and_size((0, 0), (16, 16));
+        let second = Rectangle::from_loc_and_size((32, 8), (8, 48));
+        assert!(take_damage(&buffer).is_none());
+        assert!(set_damage(&buffer, &[first]));
+        assert!(set_damage(&buffer, &[second]));
+        assert_eq!(take_damage(&buffer), Some(vec![first, second]));
+        assert!(take_damage(&buffer).is_none());
+    }
+
+    #[test]
+    fn buffer_data() {
+        let mut display = Display::new();
+        // counts the imports, failing from the third one on
+        let mut imports = 0usize;
+        let (state, _) = DmabufGlobalBuilder::new()
+            .formats(vec![Format {
+                code: Fourcc::Argb8888,
+                modifier: Modifier::Linear,
+            }])
+            .build_with_data(&mut display, move |_, _| {
+                imports += 1;
+                if imports < 3 {
+                    Ok(imports)
+                } else {
+                    Err(ImportError::Failed)
+                }
+            });
+
+        let mut client = TestClient::new(&mut display).unwrap();
+        let buffer = client.create_buffer(&mut display);
+        assert_eq!(get_buffer_data::<usize>(&buffer).as_deref(), Some(&1));
+        assert!(get_buffer_data::<u32>(&buffer).is_none());
+        assert_eq!(get_dmabuf(&buffer).map(Dmabuf::num_planes), Some(1));
+        assert_eq!(dmabuf_version(&buffer), Some(3));
+
+        assert!(state.revalidate(&buffer, DispatchData::wrap(&mut ())));
+        assert_eq!(get_buffer_data::<usize>(&buffer).as_deref(), Some(&2));
+        assert!(!state.revalidate(&buffer, DispatchData::wrap(&mut ())));
+        assert!(get_buffer_data::<usize>(&buffer).is_none());
+        assert!(get_dmabuf(&buffer).is_some());
+    }
+
+    #[test]
+    fn created_buffers_close_fds() {
+        let formats = vec![Format {
+            code: Fourcc::Argb8888,
+            modifier: Modifier::Linear,
+        }];
+        for &immed in &[false, true] {
+            for &result in &[Ok(()), Err(ImportError::Failed)] {
+                let mut display = Display::new();
+                let _ = init_dmabuf_global(&mut display, formats.clone(), move |_, _| result, None);
+                let mut client = TestClient::new(&mut display).unwrap();
+                let (read, buffer, error) = client.submit_pipe(&mut display, Fourcc::Argb8888, immed);
+                match buffer {
+                    // the fds are owned by the buffer until it is destroyed
+                    Some(buffer) => {
+                        assert!(result.is_ok());
+                        assert!(write_end_open(read));
+                        buffer.destroy();
+                        client.roundtrip(&mut display);
+                    }
+                    None => {
+                        assert!(result.is_err());
+                        assert_eq!(error.is_some(), immed);
+                    }
+                }
+                assert!(!write_end_open(read));
+                nix::unistd::close(read).unwrap();
+            }
+        }
+    }
+}
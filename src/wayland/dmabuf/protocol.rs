@@ -0,0 +1,39 @@
+//! Bindings of the linux-dmabuf protocol
+//!
+//! The version of `wayland-protocols` smithay depends on only provides version 3 of
+//! `zwp_linux_dmabuf_v1`. These bindings are generated from version 4 of the protocol,
+//! which adds the `zwp_linux_dmabuf_feedback_v1` interface.
+
+#![allow(dead_code, non_camel_case_types, unused_unsafe, unused_variables)]
+#![allow(non_upper_case_globals, non_snake_case, unused_imports)]
+#![allow(missing_docs, missing_debug_implementations, rust_2018_idioms, clippy::all)]
+#![allow(unknown_lints, static_mut_refs)]
+
+pub(crate) use wayland_commons::map::{Object, ObjectMetadata};
+pub(crate) use wayland_commons::smallvec;
+pub(crate) use wayland_commons::wire::{Argument, ArgumentType, Message, MessageDesc};
+pub(crate) use wayland_commons::{Interface, MessageGroup};
+pub(crate) use wayland_server::protocol::{wl_buffer, wl_surface};
+pub(crate) use wayland_server::sys;
+pub(crate) use wayland_server::{AnonymousObject, Main, Resource, ResourceMap};
+
+// the generated code expects `bitflags!` to be in textual scope
+macro_rules! bitflags {
+    ($($t:tt)*) => { bitflags::bitflags! { $($t)* } };
+}
+
+include!(concat!(env!("OUT_DIR"), "/linux-dmabuf-v1_server_api.rs"));
+
+/// Client-side bindings, used to test the global with version 4 clients
+#[cfg(test)]
+pub(crate) mod client {
+    pub(crate) use wayland_client::protocol::{wl_buffer, wl_surface};
+    pub(crate) use wayland_client::sys;
+    pub(crate) use wayland_client::{AnonymousObject, Attached, Main, Proxy, ProxyMap};
+    pub(crate) use wayland_commons::map::{Object, ObjectMetadata};
+    pub(crate) use wayland_commons::smallvec;
+    pub(crate) use wayland_commons::wire::{Argument, ArgumentType, Message, MessageDesc};
+    pub(crate) use wayland_commons::{Interface, MessageGroup};
+
+    include!(concat!(env!("OUT_DIR"), "/linux-dmabuf-v1_client_api.rs"));
+}
@@ -0,0 +1,245 @@
+use std::{
+    cell::{Cell, RefCell},
+    error::Error,
+    os::unix::{
+        io::{IntoRawFd, RawFd},
+        net::UnixStream,
+    },
+    rc::Rc,
+    time::Duration,
+};
+
+use wayland_protocols::unstable::linux_dmabuf::v1::client::{
+    zwp_linux_buffer_params_v1::{Event as ParamsEvent, Flags},
+    zwp_linux_dmabuf_v1::ZwpLinuxDmabufV1,
+};
+use wayland_server::{Client, DispatchData, Display};
+
+use super::{DmabufGlobalBuilder, ImportError};
+#[cfg(feature = "backend_gbm")]
+use crate::backend::allocator::gbm::{GbmBuffer, GbmBufferFlags, GbmDevice, GbmImportError};
+use crate::backend::allocator::{
+    dmabuf::{AsDmabuf, Dmabuf},
+    Allocator, Buffer, Format,
+};
+
+/// Dmabuf handler for tests
+///
+/// Records all dmabufs passed to the handler returned by [`handler`](TestDmabufHandler::handler)
+/// and answers them with a configurable result, accepting all of them by default.
+///
+/// `TestDmabufHandler` is a cheap handle and can be freely cloned.
+#[derive(Debug, Clone)]
+pub struct TestDmabufHandler(Rc<RefCell<TestDmabufHandlerInner>>);
+
+#[derive(Debug)]
+struct TestDmabufHandlerInner {
+    received: Vec<Dmabuf>,
+    result: Result<(), ImportError>,
+}
+
+impl Default for TestDmabufHandler {
+    fn default() -> Self {
+        TestDmabufHandler::new()
+    }
+}
+
+impl TestDmabufHandler {
+    /// Create a new handler accepting all dmabufs
+    pub fn new() -> TestDmabufHandler {
+        TestDmabufHandler(Rc::new(RefCell::new(TestDmabufHandlerInner {
+            received: Vec::new(),
+            result: Ok(()),
+        })))
+    }
+
+    /// Closure to be passed to [`init_dmabuf_global`](super::init_dmabuf_global)
+    pub fn handler(
+        &self,
+    ) -> impl for<'a> FnMut(&Dmabuf, DispatchData<'a>) -> Result<(), ImportError> + 'static {
+        let inner = self.0.clone();
+        move |dmabuf, _| {
+            let mut inner = inner.borrow_mut();
+            inner.received.push(dmabuf.clone());
+            inner.result
+        }
+    }
+
+    /// Set the result returned for all following dmabufs
+    pub fn set_result(&self, result: Result<(), ImportError>) {
+        self.0.borrow_mut().result = result;
+    }
+
+    /// All dmabufs received by the handler so far
+    pub fn received(&self) -> Vec<Dmabuf> {
+        self.0.borrow().received.clone()
+    }
+}
+
+/// A wayland client connected to a server running in the same thread
+pub(crate) struct TestClient {
+    pub(crate) display: wayland_client::Display,
+    pub(crate) queue: wayland_client::EventQueue,
+    pub(crate) globals: wayland_client::GlobalManager,
+    /// Server side of the client, only read by the tests of the dmabuf global
+    #[cfg_attr(not(test), allow(dead_code))]
+    pub(crate) client: Client,
+}
+
+impl TestClient {
+    /// Connect a new client to `display` and receive its globals
+    pub(crate) fn new(display: &mut Display) -> std::io::Result<TestClient> {
+        let (server_socket, client_socket) = UnixStream::pair()?;
+        let client = unsafe { display.create_client(server_socket.into_raw_fd(), &mut ()) };
+        let client_display = unsafe { wayland_client::Display::from_fd(client_socket.into_raw_fd()) }
+            .map_err(|err| std::io::Error::new(std::io::ErrorKind::ConnectionRefused, err))?;
+        let queue = client_display.create_event_queue();
+        let globals = wayland_client::GlobalManager::new(&client_display.attach(queue.token()));
+        let mut test_client = TestClient {
+            display: client_display,
+            queue,
+            globals,
+            client,
+        };
+        test_client.roundtrip(display);
+        Ok(test_client)
+    }
+
+    /// Let the server process all requests and the client all resulting events
+    pub(crate) fn roundtrip(&mut self, display: &mut Display) {
+        let done = Rc::new(Cell::new(false));
+        let done_clone = done.clone();
+        self.display
+            .attach(self.queue.token())
+            .sync()
+            .quick_assign(move |_, _, _| done_clone.set(true));
+
+        let _ = self.display.flush();
+        let _ = display.dispatch(Duration::from_millis(0), &mut ());
+        display.flush_clients(&mut ());
+        while !done.get() {
+            if self.queue.dispatch(&mut (), |_, _, _| {}).is_err() {
+                // the client was killed by a protocol error
+                break;
+            }
+        }
+    }
+}
+
+/// Errors of [`submit_dmabuf`] and [`round_trip`]
+#[derive(Debug, thiserror::Error)]
+pub enum RoundTripError {
+    /// The allocator failed to create the buffer
+    #[error("Failed to allocate the buffer: {0}")]
+    Allocation(#[source] Box<dyn Error>),
+    /// The buffer could not be exported as a dmabuf
+    #[error("Failed to export the buffer: {0}")]
+    Export(#[source] Box<dyn Error>),
+    /// The dmabuf global posted a protocol error
+    #[error("The dmabuf global posted protocol error {0}")]
+    Protocol(u32),
+    /// The dmabuf global failed the buffer, or never answered
+    #[error("The dmabuf global did not create the buffer")]
+    Failed,
+    /// The dmabuf created by the global differs from the submitted one
+    #[error("The created dmabuf differs from the submitted one")]
+    Mismatch,
+    /// Importing the created dmabuf failed
+    #[cfg(feature = "backend_gbm")]
+    #[error(transparent)]
+    Import(#[from] GbmImportError),
+}
+
+/// Submit a dmabuf to a dmabuf global like a client would
+///
+/// Creates a new [`Display`] with a dmabuf global supporting only the format of `dmabuf`
+/// and connects a client to it, which adds all planes of the dmabuf to a params object
+/// and requests a buffer out of them using `create`. The planes are added in reverse order,
+/// as clients may add them in any order.
+///
+/// Returns the dmabuf passed to the handler of the global, after checking that it describes
+/// the same buffer as `dmabuf`.
+pub fn submit_dmabuf(dmabuf: &Dmabuf) -> Result<Dmabuf, RoundTripError> {
+    let mut display = Display::new();
+    let handler = TestDmabufHandler::new();
+    let _global = DmabufGlobalBuilder::new()
+        .formats(vec![dmabuf.format()])
+        .build(&mut display, handler.handler());
+
+    let mut client = TestClient::new(&mut display).map_err(|_| RoundTripError::Failed)?;
+    let global = client
+        .globals
+        .instantiate_exact::<ZwpLinuxDmabufV1>(3)
+        .map_err(|_| RoundTripError::Failed)?;
+    let params = global.create_params();
+    let created = Rc::new(Cell::new(false));
+    let created_clone = created.clone();
+    params.quick_assign(move |_, event, _| {
+        if let ParamsEvent::Created { .. } = event {
+            created_clone.set(true);
+        }
+    });
+    for plane in dmabuf.planes().collect::<Vec<_>>().into_iter().rev() {
+        let modifier: u64 = plane.modifier().into();
+        params.add(
+            plane.fd() as RawFd,
+            plane.plane_idx(),
+            plane.offset(),
+            plane.stride(),
+            (modifier >> 32) as u32,
+            modifier as u32,
+        );
+    }
+    let flags = Flags::from_bits_truncate(dmabuf.flags().bits());
+    params.create(
+        dmabuf.width() as i32,
+        dmabuf.height() as i32,
+        dmabuf.format().code as u32,
+        flags,
+    );
+    client.roundtrip(&mut display);
+
+    if let Some(error) = client.display.protocol_error() {
+        return Err(RoundTripError::Protocol(error.code));
+    }
+    if !created.get() {
+        return Err(RoundTripError::Failed);
+    }
+    let received = handler.received().pop().ok_or(RoundTripError::Failed)?;
+    if received.describe() != dmabuf.describe() {
+        return Err(RoundTripError::Mismatch);
+    }
+    Ok(received)
+}
+
+/// Run a buffer through the whole dmabuf pipeline
+///
+/// Allocates a buffer of the given size and `format` using `allocator`, exports it as a dmabuf,
+/// submits it to a dmabuf global using [`submit_dmabuf`] and imports the dmabuf created by the
+/// global into `gbm` using `usage`. This allows testing allocators against the protocol
+/// implementation and a real device.
+#[cfg(feature = "backend_gbm")]
+pub fn round_trip<A, B, G>(
+    allocator: &mut A,
+    gbm: &GbmDevice<G>,
+    width: u32,
+    height: u32,
+    format: Format,
+    usage: GbmBufferFlags,
+) -> Result<GbmBuffer<()>, RoundTripError>
+where
+    A: Allocator<B>,
+    A::Error: 'static,
+    B: Buffer + AsDmabuf,
+    B::Error: Error + 'static,
+    G: std::os::unix::io::AsRawFd + 'static,
+{
+    let buffer = allocator
+        .create_buffer(width, height, format.code, &[format.modifier])
+        .map_err(|err| RoundTripError::Allocation(Box::new(err)))?;
+    let dmabuf = buffer
+        .export()
+        .map_err(|err| RoundTripError::Export(Box::new(err)))?;
+    let received = submit_dmabuf(&dmabuf)?;
+    Ok(received.import_to(gbm, usage)?)
+}